@@ -0,0 +1,138 @@
+//! Command line interface to the compiler
+//!
+//! - `benchpress compile [--escape MODE] [--raw-allowlist FILE] [--policy FILE] TEMPLATE`
+//!   prints the compiled template, where the policy file is a JSON object
+//! - `benchpress audit [--json] [--raw-allowlist FILE] TEMPLATE...`
+//!   lists every raw interpolation, and fails if any are not in the allowlist
+
+use std::{
+    fs,
+    process,
+};
+
+const USAGE: &str = "usage:
+    benchpress compile [--escape MODE] [--raw-allowlist FILE] [--policy FILE] TEMPLATE
+    benchpress audit [--json] [--raw-allowlist FILE] TEMPLATE...";
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(2)
+}
+
+fn read(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| fail(&format!("{}: {}", path, e)))
+}
+
+struct Args {
+    options: json::JsonValue,
+    json: bool,
+    templates: Vec<String>,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Args {
+    let mut parsed = Args {
+        options: json::JsonValue::new_object(),
+        json: false,
+        templates: Vec::new(),
+    };
+
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| fail(USAGE));
+        match arg.as_str() {
+            "--escape" => parsed.options["escape"] = value().into(),
+            "--raw-allowlist" => parsed.options["rawAllowlist"] = read(&value()).into(),
+            "--policy" => {
+                let path = value();
+                parsed.options["policy"] = json::parse(&read(&path))
+                    .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)))
+            }
+            "--json" => parsed.json = true,
+            _ if arg.starts_with("--") => fail(USAGE),
+            _ => parsed.templates.push(arg),
+        }
+    }
+
+    parsed
+}
+
+fn compile(args: Args) {
+    let template = match args.templates.as_slice() {
+        [template] => template,
+        _ => fail(USAGE),
+    };
+
+    match compiler::compile_with_options(&read(template), template, &args.options.dump()) {
+        Ok(code) => println!("{}", code),
+        Err(errors) => {
+            eprintln!("{}", errors);
+            process::exit(1);
+        }
+    }
+}
+
+fn audit(args: Args) {
+    if args.templates.is_empty() {
+        fail(USAGE);
+    }
+
+    let mut sites = Vec::new();
+    for template in &args.templates {
+        let report = compiler::audit(&read(template), template, &args.options.dump())
+            .unwrap_or_else(|errors| {
+                eprintln!("{}", errors);
+                process::exit(1)
+            });
+        if let Ok(json::JsonValue::Array(mut report)) = json::parse(&report) {
+            sites.append(&mut report);
+        }
+    }
+    let not_allowed = sites.iter().filter(|site| site["allowed"] == false).count();
+
+    if args.json {
+        println!("{}", json::stringify_pretty(sites, 2));
+    } else {
+        for site in &sites {
+            let sources: Vec<String> = site["paths"]
+                .members()
+                .map(|path| path.to_string())
+                .chain(site["helpers"].members().map(|helper| format!("{}()", helper)))
+                .collect();
+            println!(
+                "{}:{}:{}: {{{{{}}}}} outputs {}{}",
+                site["filename"],
+                site["line"],
+                site["column"],
+                site["source"],
+                if sources.is_empty() {
+                    "only literals".to_string()
+                } else {
+                    sources.join(", ")
+                },
+                if site["allowed"] == false {
+                    " (not allowed)"
+                } else {
+                    ""
+                }
+            );
+        }
+        println!(
+            "{} raw interpolations, {} not allowed",
+            sites.len(),
+            not_allowed
+        );
+    }
+
+    if not_allowed > 0 {
+        process::exit(1);
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("compile") => compile(parse_args(args)),
+        Some("audit") => audit(parse_args(args)),
+        _ => fail(USAGE),
+    }
+}
@@ -0,0 +1,332 @@
+use crate::{
+    options::EscapeMode,
+    parse::{
+        expression::Expression,
+        path::{
+            Path,
+            PathPart,
+        },
+        tree::Instruction,
+        Span,
+        SpanExt,
+    },
+};
+/// Paths and helpers which are allowed to be output raw
+///
+/// Each entry is a resolved path like `posts.*.content`, where `*` matches any one key
+/// and `**` matches any number of them, or a helper name followed by `()` like `buildAvatar()`
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Allowlist {
+    paths: Vec<String>,
+    helpers: Vec<String>,
+}
+
+impl Allowlist {
+    /// Parse an allowlist file, with one entry per line and `#` comments
+    pub fn parse(source: &str) -> Self {
+        Self::from_entries(source.lines())
+    }
+
+    pub fn from_entries<'a>(entries: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut allowlist = Allowlist::default();
+        for entry in entries {
+            let entry = entry.split('#').next().unwrap_or_default().trim();
+            if entry.is_empty() {
+                continue;
+            }
+
+            match entry.strip_suffix("()") {
+                Some(helper) => allowlist.helpers.push(helper.to_string()),
+                None => allowlist.paths.push(entry.to_string()),
+            }
+        }
+        allowlist
+    }
+
+    /// Whether everything a raw interpolation can output is allowed
+    pub fn allows(&self, site: &RawSite) -> bool {
+        site.paths
+            .iter()
+            .all(|path| self.paths.iter().any(|pattern| path_matches(pattern, path)))
+            && site
+                .helpers
+                .iter()
+                .all(|helper| self.helpers.contains(helper))
+    }
+}
+
+fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern.split('.');
+    let mut path = path.split('.');
+    loop {
+        match (pattern.next(), path.next()) {
+            (Some("**"), _) => return true,
+            (Some(expected), Some(key)) if expected == "*" || expected == key => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// A raw interpolation or block helper call, which is output without escaping
+#[derive(Debug, PartialEq, Eq)]
+pub struct RawSite<'a> {
+    pub span: Span<'a>,
+    // resolved paths which can be output
+    pub paths: Vec<String>,
+    // helpers whose return value can be output
+    pub helpers: Vec<String>,
+}
+
+/// Find every raw interpolation and block helper call in a template, including in macros
+/// When the escape mode doesn't escape markup, escaped interpolations are raw as well
+pub fn raw_sites<'a>(tree: &[Instruction<Span<'a>>], escape: EscapeMode) -> Vec<RawSite<'a>> {
+    let mut sites = Vec::new();
+    collect_sites(tree, !escape.escapes_markup(), &mut sites);
+    sites
+}
+
+fn collect_sites<'a>(
+    tree: &[Instruction<Span<'a>>],
+    escaped_raw: bool,
+    sites: &mut Vec<RawSite<'a>>,
+) {
+    for instruction in tree {
+        match instruction {
+            Instruction::InterpEscaped(_) if !escaped_raw => {}
+            Instruction::InterpRaw(expr) | Instruction::InterpEscaped(expr) => {
+                let mut site = RawSite {
+                    span: expr.span(),
+                    paths: Vec::new(),
+                    helpers: Vec::new(),
+                };
+                add_sources(expr, &mut site);
+                sites.push(site);
+            }
+            Instruction::If { body, alt, .. } | Instruction::Iter { body, alt, .. } => {
+                collect_sites(body, escaped_raw, sites);
+                collect_sites(alt, escaped_raw, sites);
+            }
+            // the return value of a block helper is output without escaping
+            Instruction::BlockHelper {
+                name, body, alt, ..
+            } => {
+                sites.push(RawSite {
+                    span: *name,
+                    paths: Vec::new(),
+                    helpers: vec![name.fragment().to_string()],
+                });
+                collect_sites(body, escaped_raw, sites);
+                collect_sites(alt, escaped_raw, sites);
+            }
+            Instruction::Switch { cases, default, .. } => {
+                for (_, body) in cases {
+                    collect_sites(body, escaped_raw, sites);
+                }
+                collect_sites(default, escaped_raw, sites);
+            }
+            Instruction::Macro { body, .. }
+            | Instruction::Let { body, .. }
+            | Instruction::Ambiguous { body, .. } => {
+                collect_sites(body, escaped_raw, sites);
+            }
+            Instruction::Text(_) => {}
+        }
+    }
+}
+
+/// Add the paths and helpers an expression can evaluate to
+fn add_sources(expr: &Expression<Span>, site: &mut RawSite) {
+    match expr {
+        Expression::Path { path, .. } => site.paths.push(path_name(path)),
+        Expression::Helper { name, .. } | Expression::LegacyHelper { name, .. } => {
+            site.helpers.push(name.fragment().to_string())
+        }
+        Expression::Coalesce { left, right, .. } => {
+            add_sources(left, site);
+            add_sources(right, site);
+        }
+        Expression::Ternary {
+            then, otherwise, ..
+        } => {
+            add_sources(then, site);
+            add_sources(otherwise, site);
+        }
+        // literals, booleans, and escaped macro output are safe
+        _ => {}
+    }
+}
+
+/// A resolved path as text, with `*` for the element of a loop or a dynamic key
+/// like `posts.*.content` for `{{{ each posts }}}{{posts.content}}`
+pub fn path_name(path: Path<Span>) -> String {
+    path_keys(path).join(".")
+}
+
+/// The keys of a resolved path, with `*` for the element of a loop or a dynamic key
+pub fn path_keys(path: Path<Span>) -> Vec<String> {
+    let mut keys = Vec::new();
+    for part in path {
+        keys.push(
+            part.key()
+                .map_or_else(|| "*".to_string(), |key| key.into_owned()),
+        );
+
+        if let PathPart::PartDepth(..) | PathPart::ComputedDepth(..) = part {
+            // loop keywords like `@value` are already the element
+            if !part.inner().starts_with('@') {
+                keys.push("*".to_string());
+            }
+        }
+    }
+    keys
+}
+
+/// A description of everything a raw interpolation can output
+fn describe(site: &RawSite) -> String {
+    site.paths
+        .iter()
+        .cloned()
+        .chain(site.helpers.iter().map(|helper| format!("{}()", helper)))
+        .collect::<Vec<_>>()
+        .join("`, `")
+}
+
+/// An error for a raw interpolation which isn't in the allowlist
+pub fn not_allowed_error(site: &RawSite) -> String {
+    site.span.error(
+        &format!("raw interpolation of `{}` is not allowed", describe(site)),
+        "use `{ }` to escape it, or add it to the raw allowlist",
+    )
+}
+
+/// A JSON report of raw interpolations, with whether each is allowed if there is an allowlist
+pub fn report(sites: &[RawSite], allowlist: Option<&Allowlist>) -> String {
+    let report: Vec<json::JsonValue> = sites
+        .iter()
+        .map(|site| {
+            let (_, column, _) = site.span.get_line_column_padding();
+
+            let mut entry = json::JsonValue::new_object();
+            entry["filename"] = site.span.extra.filename.into();
+            entry["line"] = site.span.location_line().into();
+            entry["column"] = column.into();
+            entry["source"] = (*site.span.fragment()).into();
+            entry["paths"] = site.paths.clone().into();
+            entry["helpers"] = site.helpers.clone().into();
+            if let Some(allowlist) = allowlist {
+                entry["allowed"] = allowlist.allows(site).into();
+            }
+            entry
+        })
+        .collect();
+
+    json::stringify(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{
+        test::sp,
+        tokens::tokens,
+        tree::{
+            fix_extra_tokens,
+            tree,
+        },
+    };
+    use pretty_assertions::assert_eq;
+
+    fn sites_of(source: &str, escape: EscapeMode) -> Vec<(String, Vec<String>, Vec<String>)> {
+        let (_, toks) = tokens(sp(source)).unwrap();
+        let mut instructions = vec![];
+        tree(
+            0,
+            &[],
+            &[],
+            &mut fix_extra_tokens(toks).into_iter(),
+            &mut instructions,
+        )
+        .unwrap();
+
+        raw_sites(&instructions, escape)
+            .into_iter()
+            .map(|site| (site.span.fragment().to_string(), site.paths, site.helpers))
+            .collect()
+    }
+
+    #[test]
+    fn sites() {
+        assert_eq!(
+            sites_of(
+                "{html} {{html}} {{{ each posts }}}{{posts.content}}{{{ end }}}\
+                 {{{ if x }}}{{a ?? buildAvatar(user)}}{{{ end }}}{{\"<br>\"}}",
+                EscapeMode::Html
+            ),
+            vec![
+                ("html".to_string(), vec!["html".to_string()], vec![]),
+                (
+                    "posts.content".to_string(),
+                    vec!["posts.*.content".to_string()],
+                    vec![]
+                ),
+                (
+                    "a ?? buildAvatar(user)".to_string(),
+                    vec!["a".to_string()],
+                    vec!["buildAvatar".to_string()]
+                ),
+                ("\"<br>\"".to_string(), vec![], vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn block_helper_calls() {
+        assert_eq!(
+            sites_of(
+                "{{{ block wrap(x) }}}{{inner}}{{{ else }}}{y}{{{ end }}}",
+                EscapeMode::Html
+            ),
+            vec![
+                ("wrap".to_string(), vec![], vec!["wrap".to_string()]),
+                ("inner".to_string(), vec!["inner".to_string()], vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unescaped_modes() {
+        let source = "<p>{name}</p>{{bio}}";
+        assert_eq!(sites_of(source, EscapeMode::Xml).len(), 1);
+        assert_eq!(
+            sites_of(source, EscapeMode::Plain),
+            vec![
+                ("name".to_string(), vec!["name".to_string()], vec![]),
+                ("bio".to_string(), vec!["bio".to_string()], vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn allowlist() {
+        let allowlist = Allowlist::parse(
+            "# trusted markup
+            posts.*.content
+            widgets.**
+            buildAvatar() # escapes its own output
+            ",
+        );
+        let site = |paths: &[&str], helpers: &[&str]| RawSite {
+            span: sp("x"),
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            helpers: helpers.iter().map(|h| h.to_string()).collect(),
+        };
+
+        assert!(allowlist.allows(&site(&["posts.*.content"], &["buildAvatar"])));
+        assert!(allowlist.allows(&site(&["widgets.footer.html"], &[])));
+        assert!(allowlist.allows(&site(&[], &[])));
+        assert!(!allowlist.allows(&site(&["posts.*.title"], &[])));
+        assert!(!allowlist.allows(&site(&["posts.*.content.extra"], &[])));
+        assert!(!allowlist.allows(&site(&["posts.*.content"], &["other"])));
+    }
+}
@@ -1,2 +1,5 @@
 pub mod generator;
+mod hoist;
+pub mod html;
+mod share;
 mod templates;
@@ -1,5 +1,17 @@
 use crate::{
-    generate::templates,
+    generate::{
+        hoist::hoist,
+        html::Context,
+        share::share,
+        templates::{
+            self,
+            Access,
+        },
+    },
+    options::{
+        EscapeMode,
+        Options,
+    },
     parse::{
         expression::Expression,
         tree::Instruction,
@@ -7,30 +19,72 @@ use crate::{
     },
 };
 
-use std::collections::HashSet;
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+};
 
 /// generate code for a body
 /// recursively applied to If and Iter children
+/// macro functions are added to `macros`
+/// `html` is the HTML context at the start of the body, and is advanced to the end of it
+/// `options.escape` is how escaped interpolations are escaped, where HTML contexts are only
+/// tracked in `EscapeMode::Html`
+/// `bound` are the names bound by `set` and `let` around the body with the code for their
+/// values, outermost first, which are bound again within block methods
 fn gen_body<'a, 'b>(
     entry: Vec<Instruction<Span<'a>>>,
     top: bool,
     mut block_names: &'b mut HashSet<&'a str>,
+    bound: &[(&'a str, String)],
+    macros: &mut Vec<String>,
+    html: &mut Context,
+    options: &Options,
 ) -> (String, Vec<String>) {
     if entry.is_empty() {
         return ("\"\"".to_string(), Vec::new());
     }
 
+    let mode = options.escape;
+    let access = access(options);
+    let expression =
+        |expr: Expression<Span<'a>>| -> Cow<'a, str> { templates::expression(expr, access) };
+
     let mut blocks: Vec<String> = Vec::new();
 
     let output = entry
         .into_iter()
         .map(|elem| match elem {
             // output a string literal in JS
-            Instruction::Text(value) => json::stringify(json::from(*value.fragment())),
+            Instruction::Text(pieces) => {
+                let value: String = pieces.iter().map(|piece| *piece.fragment()).collect();
+                if mode == EscapeMode::Html {
+                    html.text(&value);
+                }
+                json::stringify(json::from(value))
+            }
             // output a ternary in JS
             Instruction::If { subject, body, alt } => {
-                let (b, mut b_blocks) = gen_body(body, top, &mut block_names);
-                let (a, mut a_blocks) = gen_body(alt, top, &mut block_names);
+                let (mut body_html, mut alt_html) = (*html, *html);
+                let (b, mut b_blocks) = gen_body(
+                    body,
+                    top,
+                    &mut block_names,
+                    bound,
+                    macros,
+                    &mut body_html,
+                    options,
+                );
+                let (a, mut a_blocks) = gen_body(
+                    alt,
+                    top,
+                    &mut block_names,
+                    bound,
+                    macros,
+                    &mut alt_html,
+                    options,
+                );
+                *html = body_html.join_branches(alt_html, subject.span());
 
                 blocks.append(&mut b_blocks);
                 blocks.append(&mut a_blocks);
@@ -42,27 +96,73 @@ fn gen_body<'a, 'b>(
                     (subject, false)
                 };
 
-                templates::if_else(neg, &templates::expression(expr), &b, &a)
+                templates::if_else(neg, &expression(expr), &b, &a)
+            }
+            // output a `for` loop over a range in JS,
+            // or iterate over an array of its numbers to apply clauses
+            Instruction::Iter {
+                depth,
+                subject: Expression::Range { span, start, end },
+                clauses,
+                body,
+                alt,
+            } if clauses.is_empty() => {
+                let (locals, b, a) = gen_loop_bodies(body, alt, macros, html, options, span);
+
+                templates::range(
+                    depth,
+                    &expression(*start),
+                    &expression(*end),
+                    &locals,
+                    &b,
+                    &a,
+                )
             }
             // output a call to `iter` in JS
             Instruction::Iter {
                 depth,
                 subject,
+                clauses,
                 body,
                 alt,
             } => {
                 let subject_raw = *subject.span().fragment();
+                let (locals, b, a) =
+                    gen_loop_bodies(body, alt, macros, html, options, subject.span());
+
+                let iter_options = if clauses.is_empty() {
+                    None
+                } else {
+                    let expr =
+                        |e: Option<Expression<Span<'a>>>| e.map(|e| expression(e).into_owned());
+                    Some(templates::iter_options(
+                        depth,
+                        expr(clauses.filter).as_deref(),
+                        expr(clauses.limit).as_deref(),
+                        expr(clauses.offset).as_deref(),
+                        clauses.reverse,
+                        clauses
+                            .sep
+                            .map(|sep| templates::escape(mode.escaper(), &expression(sep)))
+                            .as_deref(),
+                    ))
+                };
 
                 let block = templates::iter(
                     depth,
-                    &templates::expression(subject),
-                    &gen_body(body, false, &mut HashSet::new()).0,
-                    &gen_body(alt, false, &mut HashSet::new()).0,
+                    &expression(subject),
+                    &locals,
+                    &b,
+                    &a,
+                    iter_options.as_deref(),
                 );
 
                 // if top level, pull out into a block method
                 if top && !block_names.contains(subject_raw) {
                     let out = templates::block_call(subject_raw);
+                    let block = bound.iter().rev().fold(block, |block, (name, value)| {
+                        templates::let_in(name, value, &block)
+                    });
                     blocks.push(templates::block(subject_raw, &block));
                     block_names.insert(subject_raw);
 
@@ -71,22 +171,237 @@ fn gen_body<'a, 'b>(
                     block
                 }
             }
-            // generate an escape call and guard expression
+            // output a helper call in JS, passed functions to render the body and alt
+            Instruction::BlockHelper {
+                name,
+                args,
+                kwargs,
+                body,
+                alt,
+            } => {
+                // the body and alt can be output any number of times
+                let (mut body_html, mut alt_html) = (*html, *html);
+                let (b, mut b_blocks) = gen_body(
+                    body,
+                    top,
+                    &mut block_names,
+                    bound,
+                    macros,
+                    &mut body_html,
+                    options,
+                );
+                let (a, mut a_blocks) = gen_body(
+                    alt,
+                    top,
+                    &mut block_names,
+                    bound,
+                    macros,
+                    &mut alt_html,
+                    options,
+                );
+                *html = html
+                    .join_branches(body_html, name)
+                    .join_branches(alt_html, name);
+
+                blocks.append(&mut b_blocks);
+                blocks.append(&mut a_blocks);
+
+                let mut args: Vec<Cow<str>> = args.into_iter().map(expression).collect();
+                if !kwargs.is_empty() {
+                    args.push(templates::keyword_arguments(kwargs, access).into());
+                }
+
+                templates::block_helper(name.fragment(), &args, &b, &a)
+            }
+            // output a switch within a function in JS
+            Instruction::Switch {
+                subject,
+                cases,
+                default,
+            } => {
+                let mut case_bodies = Vec::new();
+                let mut end_html = *html;
+                for (value, body) in cases {
+                    let mut case_html = *html;
+                    let (b, mut b_blocks) = gen_body(
+                        body,
+                        top,
+                        &mut block_names,
+                        bound,
+                        macros,
+                        &mut case_html,
+                        options,
+                    );
+                    blocks.append(&mut b_blocks);
+                    end_html = end_html.join_branches(case_html, value.span());
+
+                    case_bodies.push((expression(value).into_owned(), b));
+                }
+                let (d, mut d_blocks) =
+                    gen_body(default, top, &mut block_names, bound, macros, html, options);
+                blocks.append(&mut d_blocks);
+                *html = html.join_branches(end_html, subject.span());
+
+                templates::switch(&expression(subject), &case_bodies, &d)
+            }
+            // output a function definition alongside the compiled template
+            Instruction::Macro {
+                name,
+                params,
+                mut body,
+            } => {
+                let locals = locals(&mut body, false, options);
+                // macros are assumed to be called within element content
+                let (b, _) = gen_body(
+                    body,
+                    false,
+                    &mut HashSet::new(),
+                    &[],
+                    macros,
+                    &mut Context::Text,
+                    options,
+                );
+                let params: Vec<&str> = params.iter().map(|p| *p.fragment()).collect();
+                macros.push(templates::macro_fn(name.fragment(), &params, &locals, &b));
+
+                String::new()
+            }
+            // bind the value to a local within a function around the body
+            Instruction::Let { name, value, body } => {
+                let value = expression(value).into_owned();
+                let mut bound = bound.to_vec();
+                bound.push((name.fragment(), value.clone()));
+
+                let (b, mut b_blocks) =
+                    gen_body(body, top, &mut block_names, &bound, macros, html, options);
+                blocks.append(&mut b_blocks);
+
+                templates::let_in(name.fragment(), &value, &b)
+            }
+            // bind whether an ambiguous `BEGIN` is relative within a function around the loop
+            Instruction::Ambiguous {
+                depth,
+                relative,
+                body,
+            } => {
+                let (b, mut b_blocks) =
+                    gen_body(body, top, &mut block_names, bound, macros, html, options);
+                blocks.append(&mut b_blocks);
+
+                templates::relative_in(depth, &expression(relative), &b)
+            }
+            // macros output HTML, so don't escape it again within element text
+            Instruction::InterpEscaped(subject @ Expression::MacroCall { .. })
+                if mode != EscapeMode::Html || *html == Context::Text =>
+            {
+                html.output();
+                expression(subject).into()
+            }
+            // generate an escape call for the HTML context or escape mode and guard expression
             Instruction::InterpEscaped(subject) => {
-                format!("{}({})", templates::ESCAPE, templates::expression(subject))
+                let escaper = match mode {
+                    EscapeMode::Html => html.escaper(subject.span()),
+                    mode => mode.escaper(),
+                };
+                html.output();
+                templates::escape(escaper, &expression(subject))
             }
             // generate a guard expression
-            Instruction::InterpRaw(subject) => templates::expression(subject).into(),
+            Instruction::InterpRaw(subject) => {
+                html.output();
+                expression(subject).into()
+            }
         })
         .filter(|x| !x.is_empty())
         .collect::<Vec<String>>();
 
+    // a body of only macro definitions outputs nothing
+    if output.is_empty() {
+        return ("\"\"".to_string(), blocks);
+    }
+
     (templates::concat(&output), blocks)
 }
 
+/// how guard chains access properties
+fn access(options: &Options) -> Access {
+    if options.own_properties {
+        Access::Own
+    } else {
+        Access::Any
+    }
+}
+
+/// share helper calls and hoist guard chain prefixes which are evaluated more than once
+/// within the function a body is generated in into locals of it,
+/// returning their declarations
+fn locals(body: &mut [Instruction<Span>], top: bool, options: &Options) -> String {
+    if !options.optimize {
+        return String::new();
+    }
+
+    let calls = templates::shared_calls(share(body, top, &options.impure_helpers));
+    let paths = hoist(body, top, access(options));
+
+    [calls, paths]
+        .iter()
+        .filter(|locals| !locals.is_empty())
+        .cloned()
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// generate code for the body and alt of a loop
+/// the body can be output any number of times, and the alt instead of it
+/// returns declarations of locals for the loop function along with them
+fn gen_loop_bodies<'a>(
+    mut body: Vec<Instruction<Span<'a>>>,
+    alt: Vec<Instruction<Span<'a>>>,
+    macros: &mut Vec<String>,
+    html: &mut Context,
+    options: &Options,
+    span: Span<'a>,
+) -> (String, String, String) {
+    let locals = locals(&mut body, false, options);
+    let (mut body_html, mut alt_html) = (*html, *html);
+    let (b, _) = gen_body(
+        body,
+        false,
+        &mut HashSet::new(),
+        &[],
+        macros,
+        &mut body_html,
+        options,
+    );
+    let (a, _) = gen_body(
+        alt,
+        false,
+        &mut HashSet::new(),
+        &[],
+        macros,
+        &mut alt_html,
+        options,
+    );
+    *html = html
+        .join_branches(body_html, span)
+        .join_branches(alt_html, span);
+
+    (locals, b, a)
+}
+
 /// generate code from parser output
-pub fn generate(input: Vec<Instruction<Span>>) -> String {
-    let (body, blocks) = gen_body(input, true, &mut HashSet::new());
+pub fn generate(mut input: Vec<Instruction<Span>>, options: &Options) -> String {
+    let locals = locals(&mut input, true, options);
+    let mut macros = Vec::new();
+    let (body, blocks) = gen_body(
+        input,
+        true,
+        &mut HashSet::new(),
+        &[],
+        &mut macros,
+        &mut Context::Text,
+        options,
+    );
 
-    templates::wrapper(&body, &blocks)
+    templates::wrapper(&locals, &body, &blocks, &macros)
 }
@@ -0,0 +1,497 @@
+use crate::{
+    generate::templates::{
+        self,
+        Access,
+    },
+    parse::{
+        expression::Expression,
+        path::{
+            Path,
+            PathPart,
+        },
+        tree::Instruction,
+        Span,
+    },
+};
+
+/// What a guard chain starts from
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Root {
+    // `context`
+    Context,
+    // `value{n}`, the element of the loop at depth `n`
+    Value(u32),
+}
+
+/// A prefix of guard chains, and the parts of the first path it was found in
+struct Prefix<'a> {
+    root: Root,
+    keys: Vec<Key<'a>>,
+    parts: Vec<PathPart<Span<'a>>>,
+    count: usize,
+}
+
+/// A key in a guard chain, with the key of the loop at a depth after it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Key<'a> {
+    Name(&'a str),
+    Depth(&'a str, u32),
+}
+
+/// The root of the guard chain for a path, and the index of the first part after it,
+/// or `None` if the path doesn't generate a guard chain
+fn chain(path: Path<Span>) -> Option<(Root, usize)> {
+    match path.first()? {
+        PathPart::PartDepth(part, n) if part.starts_with('@') => match *part.fragment() {
+            "@value" => Some((Root::Value(*n), 1)),
+            _ => None,
+        },
+        part => match part.inner() {
+            "@root" if path.len() > 1 => Some((Root::Context, 1)),
+            inner if inner.starts_with('@') => None,
+            _ => Some((Root::Context, 0)),
+        },
+    }
+}
+
+/// Keys at the start of `parts`, up to the first computed key
+fn keys<'a>(parts: Path<Span<'a>>) -> Vec<Key<'a>> {
+    parts
+        .iter()
+        .take_while(|part| matches!(part, PathPart::Part(_) | PathPart::PartDepth(..)))
+        .map(|part| match part {
+            PathPart::PartDepth(name, n) => Key::Depth(name.fragment(), *n),
+            part => Key::Name(part.inner()),
+        })
+        .collect()
+}
+
+/// Walks the paths evaluated within the JS function a body is generated in,
+/// in the order they're evaluated
+struct Paths<'f, F> {
+    f: &'f mut F,
+    // whether a helper or macro may have been called, which can change the data,
+    // so paths after it are left where they're read
+    called: bool,
+}
+
+impl<'a, 'f, F> Paths<'f, F>
+where
+    F: FnMut(&mut Vec<PathPart<Span<'a>>>),
+{
+    /// Apply `f` to each path within an expression, after the paths within its computed keys,
+    /// where `scope` is whether the expression is evaluated in the function
+    fn expression(&mut self, expr: &mut Expression<Span<'a>>, scope: bool) {
+        match expr {
+            Expression::Path { path, .. } => {
+                for part in path.iter_mut() {
+                    if let PathPart::Computed(_, key) | PathPart::ComputedDepth(_, key, _) = part {
+                        self.expression(key, scope);
+                    }
+                }
+                if scope && !self.called {
+                    (self.f)(path);
+                }
+            }
+            Expression::Negative { expr, .. } => self.expression(expr, scope),
+            Expression::Helper { args, kwargs, .. } => {
+                for arg in args.iter_mut().chain(kwargs.iter_mut().map(|(_, arg)| arg)) {
+                    self.expression(arg, scope);
+                }
+                self.called = true;
+            }
+            Expression::LegacyHelper { args, .. } | Expression::MacroCall { args, .. } => {
+                for arg in args {
+                    self.expression(arg, scope);
+                }
+                self.called = true;
+            }
+            Expression::Coalesce { left, right, .. } => {
+                self.expression(left, scope);
+                self.expression(right, scope);
+            }
+            Expression::Ternary {
+                condition,
+                then,
+                otherwise,
+                ..
+            } => {
+                self.expression(condition, scope);
+                self.expression(then, scope);
+                self.expression(otherwise, scope);
+            }
+            Expression::Range { start, end, .. } => {
+                self.expression(start, scope);
+                self.expression(end, scope);
+            }
+            _ => (),
+        }
+    }
+
+    /// Apply `f` to each path within a body, including nested functions which can see
+    /// the variables of the function
+    /// Loop bodies and macros are generated in functions of their own, and when `top` is set,
+    /// loops may be pulled out into block methods, so no paths of them are included,
+    /// though calls within loops are still noticed
+    fn body(&mut self, instructions: &mut [Instruction<Span<'a>>], top: bool, scope: bool) {
+        for instruction in instructions {
+            match instruction {
+                Instruction::InterpEscaped(expr) | Instruction::InterpRaw(expr) => {
+                    self.expression(expr, scope)
+                }
+                Instruction::If { subject, body, alt } => {
+                    self.expression(subject, scope);
+                    self.body(body, top, scope);
+                    self.body(alt, top, scope);
+                }
+                Instruction::Iter {
+                    subject,
+                    clauses,
+                    body,
+                    alt,
+                    ..
+                } => {
+                    let inner = scope && !top;
+                    self.expression(subject, inner);
+                    for expr in [&mut clauses.limit, &mut clauses.offset, &mut clauses.sep]
+                        .iter_mut()
+                        .filter_map(|clause| clause.as_mut())
+                    {
+                        self.expression(expr, inner);
+                    }
+                    // the filter and body are called in loop functions of their own
+                    if let Some(filter) = &mut clauses.filter {
+                        self.expression(filter, false);
+                    }
+                    self.body(body, false, false);
+                    self.body(alt, top, inner);
+                }
+                Instruction::Switch {
+                    subject,
+                    cases,
+                    default,
+                } => {
+                    self.expression(subject, scope);
+                    for (value, body) in cases {
+                        self.expression(value, scope);
+                        self.body(body, top, scope);
+                    }
+                    self.body(default, top, scope);
+                }
+                // the body and alt are called by the helper
+                Instruction::BlockHelper {
+                    args,
+                    kwargs,
+                    body,
+                    alt,
+                    ..
+                } => {
+                    for arg in args.iter_mut().chain(kwargs.iter_mut().map(|(_, arg)| arg)) {
+                        self.expression(arg, scope);
+                    }
+                    self.called = true;
+                    self.body(body, top, scope);
+                    self.body(alt, top, scope);
+                }
+                // top-level values are copied into block methods, where the locals don't exist
+                Instruction::Let { value, body, .. } => {
+                    self.expression(value, scope && !top);
+                    self.body(body, top, scope);
+                }
+                Instruction::Ambiguous { relative, body, .. } => {
+                    self.expression(relative, scope);
+                    self.body(body, top, scope);
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Apply `f` to each path evaluated within the JS function a body is generated in
+/// before any helper or macro may be called
+fn scope_paths<'a, F>(instructions: &mut [Instruction<Span<'a>>], top: bool, f: &mut F)
+where
+    F: FnMut(&mut Vec<PathPart<Span<'a>>>),
+{
+    Paths { f, called: false }.body(instructions, top, true);
+}
+
+/// Hoist guard chain prefixes which are shared by paths within a body into
+/// variables declared at the start of the JS function the body is generated in,
+/// so each is only evaluated once each time the function is called
+///
+/// Only paths evaluated before any helper or macro may be called are hoisted,
+/// since a helper may change the data they read.
+///
+/// A prefix is hoisted when more than one path starts with it, and fewer start
+/// with it followed by any one key. Paths are rewritten to start from the
+/// longest hoisted prefix of them with `@path`, and each prefix is
+/// evaluated from the longest hoisted prefix of it in turn.
+///
+/// Returns the variable declarations, which are empty if nothing is hoisted
+pub fn hoist(instructions: &mut [Instruction<Span>], top: bool, access: Access) -> String {
+    // every prefix of every path, in the order they first appear
+    let mut prefixes: Vec<Prefix> = Vec::new();
+    scope_paths(instructions, top, &mut |path| {
+        if let Some((root, start)) = chain(path) {
+            let keys = keys(&path[start..]);
+            for len in 1..=keys.len() {
+                match prefixes
+                    .iter_mut()
+                    .find(|prefix| prefix.root == root && prefix.keys[..] == keys[..len])
+                {
+                    Some(prefix) => prefix.count += 1,
+                    None => prefixes.push(Prefix {
+                        root,
+                        keys: keys[..len].to_vec(),
+                        parts: path[start..start + len].to_vec(),
+                        count: 1,
+                    }),
+                }
+            }
+        }
+    });
+
+    let hoisted: Vec<&Prefix> = prefixes
+        .iter()
+        .filter(|prefix| {
+            prefix.count > 1
+                && !prefixes.iter().any(|other| {
+                    other.root == prefix.root
+                        && other.count == prefix.count
+                        && other.keys.len() == prefix.keys.len() + 1
+                        && other.keys.starts_with(&prefix.keys)
+                })
+        })
+        .collect();
+
+    // the index and length of the longest hoisted prefix of `keys`, up to `limit` long
+    let longest = |root: Root, keys: &[Key], limit: usize| {
+        hoisted
+            .iter()
+            .enumerate()
+            .filter(|(_, prefix)| {
+                prefix.root == root && prefix.keys.len() <= limit && keys.starts_with(&prefix.keys)
+            })
+            .max_by_key(|(_, prefix)| prefix.keys.len())
+            .map(|(i, prefix)| (i as u32, prefix.keys.len()))
+    };
+
+    // shorter prefixes first appear before longer ones, so are declared before them
+    let chains: Vec<String> = hoisted
+        .iter()
+        .map(|prefix| {
+            let (from, len) = match longest(prefix.root, &prefix.keys, prefix.keys.len() - 1) {
+                Some((i, len)) => (templates::path_i(i), len),
+                None => match prefix.root {
+                    Root::Context => (templates::CONTEXT.to_string(), 0),
+                    Root::Value(n) => (templates::value_i(n), 0),
+                },
+            };
+
+            templates::chain_from(&from, &prefix.parts[len..], access)
+        })
+        .collect();
+
+    scope_paths(instructions, top, &mut |path| {
+        if let Some((root, start)) = chain(path) {
+            if let Some((i, len)) = longest(root, &keys(&path[start..]), usize::MAX) {
+                let keyword = Span::new_extra("@path", path[0].span().extra);
+                let rest = path.split_off(start + len);
+                *path = vec![PathPart::PartDepth(keyword, i)];
+                path.extend(rest);
+            }
+        }
+    });
+
+    templates::hoisted(&chains)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{
+        test::sp,
+        tokens::tokens,
+        tree::{
+            fix_extra_tokens,
+            tree,
+        },
+    };
+    use pretty_assertions::assert_eq;
+
+    fn parsed(source: &str) -> Vec<Instruction<Span<'_>>> {
+        let (_, toks) = tokens(sp(source)).unwrap();
+        let mut instructions = vec![];
+        tree(
+            0,
+            &[],
+            &[],
+            &mut fix_extra_tokens(toks).into_iter(),
+            &mut instructions,
+        )
+        .unwrap();
+
+        instructions
+    }
+
+    fn hoisted(source: &str, top: bool) -> (String, Vec<String>) {
+        let mut instructions = parsed(source);
+        let locals = hoist(&mut instructions, top, Access::Any);
+        let mut output = vec![];
+        scope_paths(&mut instructions, top, &mut |path| {
+            output.push(
+                templates::expression(
+                    Expression::Path {
+                        span: path[0].span(),
+                        path: path.clone(),
+                    },
+                    Access::Any,
+                )
+                .into_owned(),
+            );
+        });
+
+        (locals, output)
+    }
+
+    #[test]
+    fn shared_prefixes() {
+        assert_eq!(
+            hoisted("{a.b.c}{a.b.d}{a.e}", false),
+            (
+                "var path0 = context && context['a'];
+var path1 = path0 && path0['b'];"
+                    .to_string(),
+                vec![
+                    "guard(path1 && path1['c'])".to_string(),
+                    "guard(path1 && path1['d'])".to_string(),
+                    "guard(path0 && path0['e'])".to_string(),
+                ]
+            )
+        );
+        // a prefix only used by longer prefixes is skipped
+        assert_eq!(
+            hoisted("{a.b.c}{a.b.d}", false),
+            (
+                "var path0 = context && context['a'] && context['a']['b'];".to_string(),
+                vec![
+                    "guard(path0 && path0['c'])".to_string(),
+                    "guard(path0 && path0['d'])".to_string(),
+                ]
+            )
+        );
+        // repeated paths are evaluated once
+        assert_eq!(
+            hoisted("{{{ if a.b }}}{a.b}{{{ end }}}", false),
+            (
+                "var path0 = context && context['a'] && context['a']['b'];".to_string(),
+                vec!["guard(path0)".to_string(), "guard(path0)".to_string()]
+            )
+        );
+        assert_eq!(
+            hoisted("{a.b}{c.d}{@root.a.e}", false),
+            (
+                "var path0 = context && context['a'];".to_string(),
+                vec![
+                    "guard(path0 && path0['b'])".to_string(),
+                    "guard(context && context['c'] && context['c']['d'])".to_string(),
+                    "guard(path0 && path0['e'])".to_string(),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn loops() {
+        // the loop body is hoisted within its own function
+        assert_eq!(
+            hoisted("{{{ each a.b }}}{a.b.c}{{{ end }}}{a.b.d}", false),
+            (
+                "var path0 = context && context['a'] && context['a']['b'];".to_string(),
+                vec![
+                    "guard(path0)".to_string(),
+                    "guard(path0 && path0['d'])".to_string()
+                ]
+            )
+        );
+        // loops at the top may be pulled out into block methods
+        assert_eq!(
+            hoisted("{{{ each a.b }}}{a.b.c}{{{ end }}}{a.b.d}", true),
+            (
+                String::new(),
+                vec![
+                    "guard(context && context['a'] && context['a']['b'] && context['a']['b']['d'])"
+                        .to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            hoisted("{{{ each a }}}{../x.y}{../x.z}{{{ end }}}", false),
+            (
+                String::new(),
+                vec!["guard(context && context['a'])".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn computed_keys() {
+        assert_eq!(
+            hoisted("{a.b[c.d].e}{a.b[c.d]}", false),
+            (
+                "var path0 = context && context['c'] && context['c']['d'];
+var path1 = context && context['a'] && context['a']['b'];"
+                    .to_string(),
+                vec![
+                    "guard(path0)".to_string(),
+                    "guard(path1 && path1[guard(path0)] && path1[guard(path0)]['e'])".to_string(),
+                    "guard(path0)".to_string(),
+                    "guard(path1 && path1[guard(path0)])".to_string(),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn calls() {
+        // a helper may change the data, so paths after it are read where they're used
+        let mut instructions = parsed("{a.b.c}{f(a.b.d)}{a.b.e}");
+        assert_eq!(
+            hoist(&mut instructions, false, Access::Any),
+            "var path0 = context && context['a'] && context['a']['b'];"
+        );
+        let output: Vec<String> = instructions
+            .into_iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::InterpEscaped(expr) => {
+                    Some(templates::expression(expr, Access::Any).into_owned())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            output,
+            vec![
+                "guard(path0 && path0['c'])",
+                "helper(context, helpers, 'f', [guard(path0 && path0['d'])])",
+                "guard(context && context['a'] && context['a']['b'] && context['a']['b']['e'])",
+            ]
+        );
+
+        // including helpers called within loops and block helpers
+        assert_eq!(
+            hoisted("{a.b}{{{ each c }}}{f(x)}{{{ end }}}{a.b}", false).0,
+            ""
+        );
+        assert_eq!(
+            hoisted("{a.b}{{{ call f() }}}{{{ end }}}{a.b}", false).0,
+            ""
+        );
+        assert_eq!(
+            hoisted("{a.b}{{{ each c }}}{x}{{{ end }}}{a.b}", false).0,
+            "var path0 = context && context['a'] && context['a']['b'];"
+        );
+    }
+}
@@ -0,0 +1,632 @@
+use crate::{
+    console::warn,
+    parse::{
+        Span,
+        SpanExt,
+    },
+};
+
+/// Element whose content is parsed differently from normal HTML
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Element {
+    Normal,
+    // `<script>`, ended only by `</script`
+    Script,
+    // `<style>`, ended only by `</style`
+    Style,
+    // `<textarea>` or `<title>`, where text is escaped as usual
+    // but tags are not recognized
+    RcData(&'static str),
+}
+
+/// What the value of an attribute is interpreted as
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Attr {
+    Normal,
+    // `href`, `src`, etc
+    Url,
+    // `onclick`, etc
+    Script,
+    // `style`
+    Style,
+}
+
+/// Position within HTML source, from the start of the template up to an interpolation
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Context {
+    // element content
+    Text,
+    // `<!-- ... -->`
+    Comment,
+    // content of a `<script>`, `<style>`, `<textarea>`, or `<title>`
+    // `quote` is set within a JS string literal or comment
+    RawText {
+        element: Element,
+        quote: Option<char>,
+    },
+    // within a tag, between attributes
+    Tag {
+        element: Element,
+    },
+    // after an attribute name
+    AfterAttrName {
+        element: Element,
+        attr: Attr,
+    },
+    // after the `=` of an attribute
+    BeforeValue {
+        element: Element,
+        attr: Attr,
+    },
+    // within an attribute value
+    // `start` is true if nothing has been output in the value yet
+    // `js_quote` is set within a JS string literal in an event handler
+    Value {
+        element: Element,
+        attr: Attr,
+        quote: Option<char>,
+        start: bool,
+        js_quote: Option<char>,
+    },
+}
+
+/// How to escape an interpolated value
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Escaper {
+    // escape HTML special characters
+    Html,
+    // replace URLs with unsafe schemes like `javascript:`, then escape as HTML
+    Url,
+    // escape for the inside of a JS string literal
+    JsString,
+    // a JSON value, for script positions outside of a string
+    JsValue,
+    // a JSON value escaped as HTML, for event handlers outside of a string
+    JsValueAttr,
+    // escape XML special characters, including `'` as `&apos;`
+    Xml,
+    // escape for the inside of a JSON string
+    JsonString,
+    // percent-encode as a URL component
+    UrlComponent,
+    // output the value as is
+    Plain,
+}
+
+const URL_ATTRS: &[&str] = &[
+    "action",
+    "background",
+    "cite",
+    "codebase",
+    "data",
+    "formaction",
+    "href",
+    "icon",
+    "longdesc",
+    "manifest",
+    "poster",
+    "src",
+    "usemap",
+    "xlink:href",
+];
+
+fn attr_kind(name: &str) -> Attr {
+    let name = name.to_ascii_lowercase();
+
+    if name.starts_with("on") {
+        Attr::Script
+    } else if name == "style" {
+        Attr::Style
+    } else if URL_ATTRS.contains(&name.as_str()) {
+        Attr::Url
+    } else {
+        Attr::Normal
+    }
+}
+
+fn element_kind(name: &str) -> Element {
+    match name.to_ascii_lowercase().as_str() {
+        "script" => Element::Script,
+        "style" => Element::Style,
+        "textarea" => Element::RcData("textarea"),
+        "title" => Element::RcData("title"),
+        _ => Element::Normal,
+    }
+}
+
+fn end_tag(element: Element) -> &'static str {
+    match element {
+        Element::Script => "</script",
+        Element::Style => "</style",
+        Element::RcData(name) => {
+            if name == "textarea" {
+                "</textarea"
+            } else {
+                "</title"
+            }
+        }
+        Element::Normal => "",
+    }
+}
+
+fn starts_with_ignore_case(text: &str, prefix: &str) -> bool {
+    text.len() >= prefix.len()
+        && text.is_char_boundary(prefix.len())
+        && text[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+/// Length of a tag or attribute name at the start of `text`
+fn name_len(text: &str) -> usize {
+    text.find(|c: char| c.is_whitespace() || matches!(c, '=' | '>' | '/' | '"' | '\'' | '<'))
+        .unwrap_or(text.len())
+}
+
+/// Track a JS string literal or regular expression, returning the new quote,
+/// which is `/` within a regular expression and `[` within a character class of one
+/// `regex` is whether a `/` here would start a regular expression rather than divide
+fn js_quote(quote: Option<char>, c: char, escaped: &mut bool, regex: bool) -> Option<char> {
+    match quote {
+        _ if *escaped => {
+            *escaped = false;
+            quote
+        }
+        Some(_) if c == '\\' => {
+            *escaped = true;
+            quote
+        }
+        Some('/') if c == '[' => Some('['),
+        Some('[') if c == ']' => Some('/'),
+        Some('[') => quote,
+        Some(q) if c == q => None,
+        None if matches!(c, '"' | '\'' | '`') => Some(c),
+        None if c == '/' && regex => Some('/'),
+        _ => quote,
+    }
+}
+
+const REGEX_KEYWORDS: &[&str] = &[
+    "await",
+    "case",
+    "delete",
+    "do",
+    "else",
+    "in",
+    "instanceof",
+    "new",
+    "of",
+    "return",
+    "throw",
+    "typeof",
+    "void",
+    "yield",
+];
+
+/// Whether a `/` after the JS code `before` starts a regular expression,
+/// which is after an operator, punctuation, or keyword,
+/// or after `start`, the quote or `>` the code started with
+fn starts_regex(before: &str, start: char) -> bool {
+    let before = before.trim_end();
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+    match before.chars().last() {
+        Some(c) if c == start || "(,=:[!&|?{};+-*%<>~^".contains(c) => true,
+        Some(c) if is_word(c) => {
+            let word = before.rsplit(|c| !is_word(c)).next().unwrap_or_default();
+            REGEX_KEYWORDS.contains(&word)
+        }
+        // after a value, or an interpolation at the start of this text
+        _ => false,
+    }
+}
+
+impl Context {
+    fn close_tag(element: Element) -> Self {
+        match element {
+            Element::Normal => Context::Text,
+            element => Context::RawText {
+                element,
+                quote: None,
+            },
+        }
+    }
+
+    /// Advance through template text
+    pub fn text(&mut self, text: &str) {
+        let mut rest = text;
+        // a backslash in a JS string escapes the next character
+        let mut escaped = false;
+
+        while let Some(c) = rest.chars().next() {
+            let mut len = c.len_utf8();
+
+            *self = match *self {
+                Context::Text => {
+                    if rest.starts_with("<!--") {
+                        len = 4;
+                        Context::Comment
+                    } else if let Some(tag) = rest.strip_prefix("</") {
+                        len = 2 + name_len(tag);
+                        Context::Tag {
+                            element: Element::Normal,
+                        }
+                    } else if let Some(tag) = rest
+                        .strip_prefix('<')
+                        .filter(|tag| tag.starts_with(|c: char| c.is_ascii_alphabetic()))
+                    {
+                        let name = &tag[..name_len(tag)];
+                        len = 1 + name.len();
+                        Context::Tag {
+                            element: element_kind(name),
+                        }
+                    } else {
+                        Context::Text
+                    }
+                }
+                Context::Comment => {
+                    if rest.starts_with("-->") {
+                        len = 3;
+                        Context::Text
+                    } else {
+                        Context::Comment
+                    }
+                }
+                Context::RawText { element, quote } => {
+                    if starts_with_ignore_case(rest, end_tag(element)) {
+                        len = end_tag(element).len();
+                        Context::Tag {
+                            element: Element::Normal,
+                        }
+                    } else if element == Element::Script {
+                        // comments are tracked like strings, so quotes within them are ignored
+                        let quote = match quote {
+                            None if rest.starts_with("//") => {
+                                len = 2;
+                                Some('\n')
+                            }
+                            None if rest.starts_with("/*") => {
+                                len = 2;
+                                Some('*')
+                            }
+                            Some('\n') if c == '\n' => None,
+                            Some('*') if rest.starts_with("*/") => {
+                                len = 2;
+                                None
+                            }
+                            Some('\n') | Some('*') => quote,
+                            _ => {
+                                let before = &text[..(text.len() - rest.len())];
+                                js_quote(quote, c, &mut escaped, starts_regex(before, '>'))
+                            }
+                        };
+                        Context::RawText { element, quote }
+                    } else {
+                        Context::RawText { element, quote }
+                    }
+                }
+                Context::Tag { element } => match c {
+                    '>' => Context::close_tag(element),
+                    c if c.is_whitespace() || c == '/' => Context::Tag { element },
+                    _ => {
+                        len = name_len(rest).max(1);
+                        Context::AfterAttrName {
+                            element,
+                            attr: attr_kind(&rest[..len]),
+                        }
+                    }
+                },
+                Context::AfterAttrName { element, attr } => match c {
+                    '=' => Context::BeforeValue { element, attr },
+                    '>' => Context::close_tag(element),
+                    c if c.is_whitespace() => Context::AfterAttrName { element, attr },
+                    _ => {
+                        // another attribute, so handle it from within the tag
+                        len = 0;
+                        Context::Tag { element }
+                    }
+                },
+                Context::BeforeValue { element, attr } => match c {
+                    '"' | '\'' => Context::Value {
+                        element,
+                        attr,
+                        quote: Some(c),
+                        start: true,
+                        js_quote: None,
+                    },
+                    '>' => Context::close_tag(element),
+                    c if c.is_whitespace() => Context::BeforeValue { element, attr },
+                    _ => {
+                        len = 0;
+                        Context::Value {
+                            element,
+                            attr,
+                            quote: None,
+                            start: true,
+                            js_quote: None,
+                        }
+                    }
+                },
+                Context::Value {
+                    element,
+                    attr,
+                    quote,
+                    js_quote: js,
+                    ..
+                } => match quote {
+                    Some(q) if c == q => Context::Tag { element },
+                    None if c.is_whitespace() => Context::Tag { element },
+                    None if c == '>' => Context::close_tag(element),
+                    _ => Context::Value {
+                        element,
+                        attr,
+                        quote,
+                        start: false,
+                        js_quote: if attr == Attr::Script {
+                            let before = &text[..(text.len() - rest.len())];
+                            let regex = starts_regex(before, quote.unwrap_or('='));
+                            js_quote(js, c, &mut escaped, regex)
+                        } else {
+                            None
+                        },
+                    },
+                },
+            };
+
+            rest = &rest[len..];
+        }
+    }
+
+    /// Choose how to escape an interpolation at this position,
+    /// warning if the position isn't safe for one
+    pub fn escaper(&self, span: Span) -> Escaper {
+        let (escaper, problem) = match *self {
+            Context::Text | Context::Comment => (Escaper::Html, None),
+            Context::RawText {
+                element: Element::RcData(_),
+                ..
+            } => (Escaper::Html, None),
+            Context::RawText {
+                element: Element::Script,
+                quote: Some(_),
+            } => (Escaper::JsString, None),
+            Context::RawText {
+                element: Element::Script,
+                quote: None,
+            } => (
+                Escaper::JsValue,
+                Some((
+                    "interpolation in a script outside of a string",
+                    "this is output as JSON, put it within a string if it should be a string",
+                )),
+            ),
+            Context::RawText { .. } => (
+                Escaper::Html,
+                Some((
+                    "interpolation in a stylesheet",
+                    "move this value into a class or a `data-` attribute",
+                )),
+            ),
+            Context::Tag { .. } | Context::AfterAttrName { .. } => (
+                Escaper::Html,
+                Some((
+                    "interpolation where an attribute name is expected",
+                    "use a conditional around the attribute instead",
+                )),
+            ),
+            Context::BeforeValue { attr, .. }
+            | Context::Value {
+                attr, quote: None, ..
+            } => (
+                if attr == Attr::Url {
+                    Escaper::Url
+                } else {
+                    Escaper::Html
+                },
+                Some((
+                    "interpolation in an unquoted attribute value",
+                    "put quotes around the attribute value",
+                )),
+            ),
+            Context::Value {
+                attr: Attr::Url,
+                start,
+                ..
+            } => (if start { Escaper::Url } else { Escaper::Html }, None),
+            Context::Value {
+                attr: Attr::Script,
+                js_quote: Some(_),
+                ..
+            } => (Escaper::JsString, None),
+            Context::Value {
+                attr: Attr::Script, ..
+            } => (
+                Escaper::JsValueAttr,
+                Some((
+                    "interpolation in an event handler outside of a string",
+                    "put this within a string, or pass it with a `data-` attribute",
+                )),
+            ),
+            Context::Value {
+                attr: Attr::Style, ..
+            } => (
+                Escaper::Html,
+                Some((
+                    "interpolation in a style attribute",
+                    "move this value into a class or a `data-` attribute",
+                )),
+            ),
+            Context::Value { .. } => (Escaper::Html, None),
+        };
+
+        if let Some((message, help)) = problem {
+            warn_at(
+                span,
+                message,
+                help,
+                "The value is escaped, but this may not be safe",
+            );
+        }
+
+        escaper
+    }
+
+    /// Advance past an interpolation, which is assumed not to change the context
+    pub fn output(&mut self) {
+        // something has been output in the attribute value
+        if let Context::BeforeValue { element, attr } = *self {
+            *self = Context::Value {
+                element,
+                attr,
+                quote: None,
+                start: false,
+                js_quote: None,
+            };
+        }
+        if let Context::Value { ref mut start, .. } = self {
+            *start = false;
+        }
+    }
+
+    /// The context after output which may come from either `self` or `other`,
+    /// or `None` if they can't be reconciled
+    pub fn join(self, other: Context) -> Option<Context> {
+        match (self, other) {
+            _ if self == other => Some(self),
+            // differ only by whether anything has been output in the value
+            (
+                Context::Value {
+                    element,
+                    attr,
+                    quote,
+                    js_quote,
+                    ..
+                },
+                Context::Value {
+                    element: e,
+                    attr: a,
+                    quote: q,
+                    js_quote: j,
+                    ..
+                },
+            ) if element == e && attr == a && quote == q && js_quote == j => Some(Context::Value {
+                element,
+                attr,
+                quote,
+                start: false,
+                js_quote,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Join the contexts after branches, warning if they differ
+    pub fn join_branches(self, other: Context, span: Span) -> Context {
+        self.join(other).unwrap_or_else(|| {
+            warn_at(
+                span,
+                "branches of this block end in different HTML contexts",
+                "close any tags or attributes within each branch",
+                "Output after this block is escaped as if the first branch was taken",
+            );
+            self
+        })
+    }
+}
+
+#[rustfmt::skip::macros(warn)]
+fn warn_at(span: Span, message: &str, help: &str, note: &str) {
+    let (line, column, padding) = span.get_line_column_padding();
+    warn!("[benchpress] warning: {}", message);
+    warn!("     --> {}:{}:{}",
+        span.extra.filename, span.location_line(), column);
+    warn!("      |");
+    warn!("{:>5} | {}", span.location_line(), line);
+    warn!("      | {}{} help: {}",
+        padding, "^".repeat(span.len()), help);
+    warn!("      = note: {}\n", note);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::test::sp;
+    use pretty_assertions::assert_eq;
+
+    fn escaper(before: &str) -> Escaper {
+        let mut context = Context::Text;
+        context.text(before);
+        context.escaper(sp("x"))
+    }
+
+    #[test]
+    fn escapers() {
+        assert_eq!(escaper("<p class=\"a\">"), Escaper::Html);
+        assert_eq!(escaper("<a title='"), Escaper::Html);
+        assert_eq!(escaper("<a href=\""), Escaper::Url);
+        assert_eq!(escaper("<a href=\"/users/"), Escaper::Html);
+        assert_eq!(escaper("<IMG alt=\"\" SRC='"), Escaper::Url);
+        assert_eq!(escaper("<a href="), Escaper::Url);
+        assert_eq!(escaper("<script>var a = \""), Escaper::JsString);
+        assert_eq!(escaper("<script>var a = \"\\\"\" + "), Escaper::JsValue);
+        assert_eq!(escaper("<script>var a = 1;</script><p>"), Escaper::Html);
+        assert_eq!(escaper("<button onclick=\"go('"), Escaper::JsString);
+        assert_eq!(escaper("<button onclick=\"go("), Escaper::JsValueAttr);
+        assert_eq!(escaper("<textarea><a href=\""), Escaper::Html);
+        assert_eq!(escaper("<!-- <script> -->"), Escaper::Html);
+        assert_eq!(escaper("<script>// it's\nvar a = "), Escaper::JsValue);
+        // quotes within regular expressions don't start strings
+        assert_eq!(escaper("<script>var r = /\"/; var s = "), Escaper::JsValue);
+        assert_eq!(
+            escaper("<script>/[/'\\]]/.test(s) && go("),
+            Escaper::JsValue
+        );
+        assert_eq!(escaper("<script>return /`/, "), Escaper::JsValue);
+        assert_eq!(
+            escaper("<script>var a = b / 2, c = \"d\" / \""),
+            Escaper::JsString
+        );
+        assert_eq!(
+            escaper("<a onclick=\"/'/.test(x) && go("),
+            Escaper::JsValueAttr
+        );
+        assert_eq!(escaper("<a onclick='\"a\" / \""), Escaper::JsString);
+    }
+
+    #[test]
+    fn contexts() {
+        let mut context = Context::Text;
+        context.text("<div class=\"a\" data-x=1 ");
+        assert_eq!(
+            context,
+            Context::Tag {
+                element: Element::Normal
+            }
+        );
+        context.text(">");
+        assert_eq!(context, Context::Text);
+
+        context.text("<script type=\"text/javascript\">");
+        assert_eq!(
+            context,
+            Context::RawText {
+                element: Element::Script,
+                quote: None
+            }
+        );
+        context.text("'</p>'</SCRIPT>");
+        assert_eq!(context, Context::Text);
+    }
+
+    #[test]
+    fn join() {
+        let value = |start| Context::Value {
+            element: Element::Normal,
+            attr: Attr::Url,
+            quote: Some('"'),
+            start,
+            js_quote: None,
+        };
+
+        assert_eq!(value(true).join(value(false)), Some(value(false)));
+        assert_eq!(Context::Text.join(Context::Text), Some(Context::Text));
+        assert_eq!(Context::Text.join(value(true)), None);
+    }
+}
@@ -0,0 +1,543 @@
+use crate::{
+    parse::{
+        expression::Expression,
+        path::PathPart,
+        tokens::EachClauses,
+        tree::Instruction,
+        Span,
+    },
+    prototype::visit,
+};
+
+/// A helper call found within a body
+struct Call<'a> {
+    // the call, regardless of where it is in the template
+    expr: Expression<&'a str>,
+    // whether it reads a name bound by `let` or an ambiguous `BEGIN`,
+    // which may be bound to something else elsewhere in the function
+    bound: bool,
+    // whether it's evaluated again after it was first evaluated
+    reused: bool,
+}
+
+/// Finds helper calls evaluated again after they were first evaluated,
+/// and rewrites them to use the first result
+struct Sharing<'a, 'b> {
+    impure: &'b [String],
+    calls: Vec<Call<'a>>,
+    // the local of each call which is reused, once they're known
+    shared: Option<Vec<Option<u32>>>,
+    // how many times something which may change the data has been evaluated,
+    // after which no calls are available
+    changes: usize,
+}
+
+impl<'a, 'b> Sharing<'a, 'b> {
+    /// Whether a call can be evaluated once for more than one expression,
+    /// where it and the calls within it are to helpers which aren't impure
+    fn pure(&self, expr: &Expression<Span<'a>>) -> bool {
+        let mut pure = true;
+        visit(expr, &mut |expr| match expr {
+            Expression::Helper { name, .. } | Expression::LegacyHelper { name, .. }
+                if self.impure.iter().any(|impure| impure == name.fragment()) =>
+            {
+                pure = false
+            }
+            // macros can call any helper
+            Expression::MacroCall { .. } => pure = false,
+            _ => (),
+        });
+
+        pure
+    }
+
+    /// Whether anything within a body may change the data when it's evaluated,
+    /// which is a call to an impure helper, a macro, or a block helper
+    fn changes_data(&self, instructions: &[Instruction<Span<'a>>]) -> bool {
+        instructions.iter().any(|instruction| match instruction {
+            Instruction::InterpEscaped(expr) | Instruction::InterpRaw(expr) => !self.pure(expr),
+            Instruction::If { subject, body, alt } => {
+                !self.pure(subject) || self.changes_data(body) || self.changes_data(alt)
+            }
+            Instruction::Iter {
+                subject,
+                clauses,
+                body,
+                alt,
+                ..
+            } => {
+                !self.pure(subject)
+                    || self.loop_changes_data(clauses, body)
+                    || [&clauses.limit, &clauses.offset, &clauses.sep]
+                        .iter()
+                        .filter_map(|clause| clause.as_ref())
+                        .any(|expr| !self.pure(expr))
+                    || self.changes_data(alt)
+            }
+            Instruction::Switch {
+                subject,
+                cases,
+                default,
+            } => {
+                !self.pure(subject)
+                    || cases
+                        .iter()
+                        .any(|(value, body)| !self.pure(value) || self.changes_data(body))
+                    || self.changes_data(default)
+            }
+            Instruction::BlockHelper { .. } => true,
+            Instruction::Let { value, body, .. } => !self.pure(value) || self.changes_data(body),
+            Instruction::Ambiguous { relative, body, .. } => {
+                !self.pure(relative) || self.changes_data(body)
+            }
+            Instruction::Text(_) | Instruction::Macro { .. } => false,
+        })
+    }
+
+    /// Whether the loop functions of an `each` may change the data
+    fn loop_changes_data(
+        &self,
+        clauses: &EachClauses<Span<'a>>,
+        body: &[Instruction<Span<'a>>],
+    ) -> bool {
+        clauses
+            .filter
+            .as_ref()
+            .is_some_and(|filter| !self.pure(filter))
+            || self.changes_data(body)
+    }
+
+    /// Note that something which may change the data was evaluated
+    fn change(&mut self, available: &mut Vec<usize>) {
+        self.changes += 1;
+        available.clear();
+    }
+
+    /// Visit something evaluated with the calls in `inner` available,
+    /// after which none in `available` are if it may have changed the data
+    fn within(
+        &mut self,
+        available: &mut Vec<usize>,
+        mut inner: Vec<usize>,
+        visit: impl FnOnce(&mut Self, &mut Vec<usize>),
+    ) {
+        let changes = self.changes;
+        visit(self, &mut inner);
+        if self.changes != changes {
+            available.clear();
+        }
+    }
+
+    /// Visit something which may not be evaluated, like `within` the calls available before it
+    fn branch(
+        &mut self,
+        available: &mut Vec<usize>,
+        visit: impl FnOnce(&mut Self, &mut Vec<usize>),
+    ) {
+        let inner = available.clone();
+        self.within(available, inner, visit);
+    }
+
+    /// Visit an expression in the order it's evaluated,
+    /// where `available` are the calls which have certainly been evaluated before it
+    fn expression(&mut self, expr: &mut Expression<Span<'a>>, available: &mut Vec<usize>) {
+        match expr {
+            Expression::Helper { .. } | Expression::LegacyHelper { .. } if self.pure(expr) => {
+                let key = expr.clone().span_to_str();
+                let index = match self.calls.iter().position(|call| call.expr == key) {
+                    Some(index) => index,
+                    None => {
+                        let mut bound = false;
+                        visit(expr, &mut |expr| {
+                            if let Expression::Path { path, .. } = expr {
+                                if let Some("@local") | Some("@relative") =
+                                    path.first().map(|part| part.inner())
+                                {
+                                    bound = true;
+                                }
+                            }
+                        });
+                        self.calls.push(Call {
+                            expr: key,
+                            bound,
+                            reused: false,
+                        });
+                        self.calls.len() - 1
+                    }
+                };
+
+                if available.contains(&index) {
+                    match &self.shared {
+                        Some(shared) => {
+                            let keyword = Span::new_extra("@call", expr.span().extra);
+                            *expr = Expression::Path {
+                                span: expr.span(),
+                                path: vec![PathPart::PartDepth(keyword, shared[index].unwrap())],
+                            };
+                        }
+                        None => self.calls[index].reused = true,
+                    }
+                    return;
+                }
+
+                self.children(expr, available);
+                available.push(index);
+
+                if let Some(i) = self.shared.as_ref().and_then(|shared| shared[index]) {
+                    let span = expr.span();
+                    let keyword = Span::new_extra("@call", span.extra);
+                    let call = std::mem::replace(expr, Expression::NullLiteral(span));
+                    *expr = Expression::Path {
+                        span,
+                        path: vec![PathPart::ComputedDepth(keyword, Box::new(call), i)],
+                    };
+                }
+            }
+            // the right side is only evaluated if the left is empty
+            Expression::Coalesce { left, right, .. } => {
+                self.expression(left, available);
+                self.branch(available, |this, available| {
+                    this.expression(right, available)
+                });
+            }
+            Expression::Ternary {
+                condition,
+                then,
+                otherwise,
+                ..
+            } => {
+                self.expression(condition, available);
+                self.branch(available, |this, available| {
+                    this.expression(then, available)
+                });
+                self.branch(available, |this, available| {
+                    this.expression(otherwise, available)
+                });
+            }
+            // keys are only evaluated if the path before them is truthy
+            Expression::Path { path, .. } => {
+                for part in path {
+                    if let PathPart::Computed(_, key) | PathPart::ComputedDepth(_, key, _) = part {
+                        self.branch(available, |this, available| this.expression(key, available));
+                    }
+                }
+            }
+            // calls which aren't pure may change the data
+            Expression::Helper { .. }
+            | Expression::LegacyHelper { .. }
+            | Expression::MacroCall { .. } => {
+                self.children(expr, available);
+                self.change(available);
+            }
+            expr => self.children(expr, available),
+        }
+    }
+
+    /// Visit the expressions directly within one which are always evaluated, in order
+    fn children(&mut self, expr: &mut Expression<Span<'a>>, available: &mut Vec<usize>) {
+        match expr {
+            Expression::Helper { args, kwargs, .. } => {
+                for arg in args.iter_mut().chain(kwargs.iter_mut().map(|(_, arg)| arg)) {
+                    self.expression(arg, available);
+                }
+            }
+            Expression::LegacyHelper { args, .. } | Expression::MacroCall { args, .. } => {
+                for arg in args {
+                    self.expression(arg, available);
+                }
+            }
+            Expression::Negative { expr, .. } => self.expression(expr, available),
+            Expression::Range { start, end, .. } => {
+                self.expression(start, available);
+                self.expression(end, available);
+            }
+            _ => (),
+        }
+    }
+
+    /// Calls available within the body of a `let` or ambiguous `BEGIN`,
+    /// where names may be bound to something else
+    fn unbound(&self, available: &[usize]) -> Vec<usize> {
+        available
+            .iter()
+            .copied()
+            .filter(|&index| !self.calls[index].bound)
+            .collect()
+    }
+
+    /// Visit a body in the order it's evaluated, like `hoist` without loop bodies or macros,
+    /// or loops at all when `top` is set
+    fn body(
+        &mut self,
+        instructions: &mut [Instruction<Span<'a>>],
+        top: bool,
+        available: &mut Vec<usize>,
+    ) {
+        for instruction in instructions {
+            match instruction {
+                Instruction::InterpEscaped(expr) | Instruction::InterpRaw(expr) => {
+                    self.expression(expr, available)
+                }
+                Instruction::If { subject, body, alt } => {
+                    self.expression(subject, available);
+                    self.branch(available, |this, available| this.body(body, top, available));
+                    self.branch(available, |this, available| this.body(alt, top, available));
+                }
+                // pulled out into a block method, which is still called here
+                Instruction::Iter { .. }
+                    if top && self.changes_data(std::slice::from_ref(instruction)) =>
+                {
+                    self.change(available)
+                }
+                Instruction::Iter { .. } if top => (),
+                Instruction::Iter {
+                    subject,
+                    clauses,
+                    body,
+                    alt,
+                    ..
+                } => {
+                    self.expression(subject, available);
+                    for expr in [&mut clauses.limit, &mut clauses.offset, &mut clauses.sep]
+                        .iter_mut()
+                        .filter_map(|clause| clause.as_mut())
+                    {
+                        self.expression(expr, available);
+                    }
+                    // the filter and body are called in loop functions of their own
+                    if self.loop_changes_data(clauses, body) {
+                        self.change(available);
+                    }
+                    self.branch(available, |this, available| this.body(alt, top, available));
+                }
+                // each case is only evaluated if those before it don't match
+                Instruction::Switch {
+                    subject,
+                    cases,
+                    default,
+                } => {
+                    self.expression(subject, available);
+                    for (value, body) in cases {
+                        self.branch(available, |this, available| {
+                            this.expression(value, available);
+                            this.body(body, top, available);
+                        });
+                    }
+                    self.branch(available, |this, available| {
+                        this.body(default, top, available)
+                    });
+                }
+                // the helper may change the data,
+                // and the body and alt are evaluated any number of times after the arguments
+                Instruction::BlockHelper {
+                    args,
+                    kwargs,
+                    body,
+                    alt,
+                    ..
+                } => {
+                    for arg in args.iter_mut().chain(kwargs.iter_mut().map(|(_, arg)| arg)) {
+                        self.expression(arg, available);
+                    }
+                    self.change(available);
+                    self.body(body, top, &mut Vec::new());
+                    self.body(alt, top, &mut Vec::new());
+                }
+                // top-level values are copied into block methods, so they're left as they are
+                Instruction::Let { value, body, .. } => {
+                    if !top {
+                        self.expression(value, available);
+                    } else if !self.pure(value) {
+                        self.change(available);
+                    }
+                    let inner = self.unbound(available);
+                    self.within(available, inner, |this, available| {
+                        this.body(body, top, available)
+                    });
+                }
+                Instruction::Ambiguous { relative, body, .. } => {
+                    self.expression(relative, available);
+                    let inner = self.unbound(available);
+                    self.within(available, inner, |this, available| {
+                        this.body(body, top, available)
+                    });
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Share the results of helper calls which are evaluated again within the JS function
+/// a body is generated in, so each is only called once
+///
+/// A call is shared when the same call was certainly evaluated before it, not only in
+/// a branch or a helper's body, and nothing which may change the data was evaluated
+/// between them: a call to a helper in `impure`, a macro, or a block helper.
+/// The first call is assigned to a local, and those after it are rewritten to read it
+/// with `@call`. Calls to helpers in `impure`, or with calls to them or macros within,
+/// are never shared.
+///
+/// Returns the number of locals
+pub fn share(instructions: &mut [Instruction<Span>], top: bool, impure: &[String]) -> u32 {
+    let mut sharing = Sharing {
+        impure,
+        calls: Vec::new(),
+        shared: None,
+        changes: 0,
+    };
+    sharing.body(instructions, top, &mut Vec::new());
+
+    let mut count = 0;
+    let shared = sharing
+        .calls
+        .iter()
+        .map(|call| {
+            if call.reused {
+                count += 1;
+                Some(count - 1)
+            } else {
+                None
+            }
+        })
+        .collect();
+    if count == 0 {
+        return 0;
+    }
+
+    sharing.shared = Some(shared);
+    sharing.body(instructions, top, &mut Vec::new());
+
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        generate::templates::{
+            self,
+            Access,
+        },
+        parse::{
+            test::sp,
+            tokens::tokens,
+            tree::{
+                fix_extra_tokens,
+                tree,
+            },
+        },
+    };
+    use pretty_assertions::assert_eq;
+
+    fn shared(source: &str, impure: &[&str]) -> (u32, Vec<String>) {
+        let (_, toks) = tokens(sp(source)).unwrap();
+        let mut instructions = vec![];
+        tree(
+            0,
+            &[],
+            &[],
+            &mut fix_extra_tokens(toks).into_iter(),
+            &mut instructions,
+        )
+        .unwrap();
+
+        let impure: Vec<String> = impure.iter().map(|name| name.to_string()).collect();
+        let count = share(&mut instructions, false, &impure);
+
+        let mut output = vec![];
+        let mut push = |expr: &Expression<Span>| {
+            output.push(templates::expression(expr.clone(), Access::Any).into_owned())
+        };
+        for instruction in &instructions {
+            match instruction {
+                Instruction::InterpEscaped(expr) => push(expr),
+                Instruction::If { subject, body, .. } => {
+                    push(subject);
+                    for instruction in body {
+                        if let Instruction::InterpEscaped(expr) = instruction {
+                            push(expr);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        (count, output)
+    }
+
+    #[test]
+    fn subject_and_body() {
+        assert_eq!(
+            shared("{{{ if f(x) }}}{f(x)}{{{ end }}}", &[]),
+            (
+                1,
+                vec![
+                    "(call0 = helper(context, helpers, 'f', [guard(context && context['x'])]))"
+                        .to_string(),
+                    "call0".to_string(),
+                ]
+            )
+        );
+        assert_eq!(
+            shared("{f(x)}{g(f(x))}{g(f(x))}", &[]),
+            (
+                2,
+                vec![
+                    "(call0 = helper(context, helpers, 'f', [guard(context && context['x'])]))"
+                        .to_string(),
+                    "(call1 = helper(context, helpers, 'g', [call0]))".to_string(),
+                    "call1".to_string(),
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn not_shared() {
+        // different arguments
+        assert_eq!(shared("{f(x)}{f(y)}", &[]).0, 0);
+        // only called in a branch before
+        assert_eq!(
+            shared(
+                "{{{ if x }}}{f(x)}{{{ end }}}{{{ if y }}}{f(x)}{{{ end }}}",
+                &[]
+            )
+            .0,
+            0
+        );
+        assert_eq!(shared("{x ?? f(x)}{f(x)}", &[]).0, 0);
+        // impure helpers, and calls with them within
+        assert_eq!(shared("{{{ if f(x) }}}{f(x)}{{{ end }}}", &["f"]).0, 0);
+        assert_eq!(shared("{g(f(x))}{g(f(x))}", &["f"]).0, 0);
+        // impure helpers, macros, and block helpers may change the data
+        assert_eq!(shared("{f(x)}{g()}{f(x)}", &["g"]).0, 0);
+        assert_eq!(
+            shared("{f(x)}{{{ if y ?? g() }}}{{{ end }}}{f(x)}", &["g"]).0,
+            0
+        );
+        assert_eq!(
+            shared("{f(x)}{{{ if y }}}{g()}{{{ end }}}{f(x)}", &["g"]).0,
+            0
+        );
+        assert_eq!(
+            shared("{f(x)}{{{ each ys }}}{g()}{{{ end }}}{f(x)}", &["g"]).0,
+            0
+        );
+        assert_eq!(
+            shared("{f(x)}{{{ block h(x) }}}{{{ end }}}{f(x)}", &[]).0,
+            0
+        );
+        assert_eq!(shared("{g()}{f(x)}{f(x)}", &["g"]).0, 1);
+        // `let` may bind the name to something else
+        assert_eq!(
+            shared(
+                "{{{ let a = x }}}{f(@local.a)}{{{ end }}}{{{ let a = y }}}{f(@local.a)}{{{ end }}}",
+                &[]
+            )
+            .0,
+            0
+        );
+    }
+}
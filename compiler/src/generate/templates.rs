@@ -3,6 +3,13 @@ pub const CONTEXT: &str = "context";
 pub const HELPERS: &str = "helpers";
 pub const HELPER: &str = "helper";
 pub const ESCAPE: &str = "__escape";
+pub const ESCAPE_URL: &str = "__escapeUrl";
+pub const ESCAPE_JS: &str = "__escapeJs";
+pub const ESCAPE_JS_VALUE: &str = "__escapeJsValue";
+pub const ESCAPE_XML: &str = "__escapeXml";
+pub const ESCAPE_JSON: &str = "__escapeJson";
+pub const ESCAPE_URL_COMPONENT: &str = "__escapeUrlComponent";
+pub const OWN: &str = "__own";
 pub const GUARD: &str = "guard";
 pub const KEY: &str = "key";
 pub const VALUE: &str = "value";
@@ -11,6 +18,11 @@ pub const LENGTH: &str = "length";
 pub const BLOCKS: &str = "compiled.blocks";
 pub const FIRST: &str = "index === 0";
 pub const LAST: &str = "index === length - 1";
+pub const LOCAL: &str = "local";
+pub const RELATIVE: &str = "relative";
+pub const PATH: &str = "path";
+pub const CALL: &str = "call";
+pub const MACRO: &str = "macro";
 pub const RUNTIME_PARAMS: &str = "helpers, context, guard, iter, helper";
 
 /// key with an indexed suffix
@@ -19,6 +31,78 @@ pub fn key_i(i: u32) -> String {
     format!("{}{}", KEY, i)
 }
 
+/// index with an indexed suffix
+pub fn index_i(i: u32) -> String {
+    format!("{}{}", INDEX, i)
+}
+
+/// length with an indexed suffix
+pub fn length_i(i: u32) -> String {
+    format!("{}{}", LENGTH, i)
+}
+
+/// value with an indexed suffix
+pub fn value_i(i: u32) -> String {
+    format!("{}{}", VALUE, i)
+}
+
+/// whether an ambiguous `BEGIN` is relative, with an indexed suffix
+pub fn relative_i(i: u32) -> String {
+    format!("{}{}", RELATIVE, i)
+}
+
+/// hoisted path prefix, with an index within its function
+pub fn path_i(i: u32) -> String {
+    format!("{}{}", PATH, i)
+}
+
+/// shared result of a helper call, with an index within its function
+pub fn call_i(i: u32) -> String {
+    format!("{}{}", CALL, i)
+}
+
+/// declaration of the shared results of `count` helper calls,
+/// which are assigned where each call is first evaluated
+pub fn shared_calls(count: u32) -> String {
+    if count == 0 {
+        return String::new();
+    }
+
+    let names: Vec<String> = (0..count).map(call_i).collect();
+    format!("var {};", names.join(", "))
+}
+
+/// declarations of hoisted path prefixes, in order of their index
+pub fn hoisted(chains: &[String]) -> String {
+    chains
+        .iter()
+        .enumerate()
+        .map(|(i, chain)| format!("var {} = {};", path_i(i as u32), chain))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// declarations of hoisted path prefixes before the `return` of a function,
+/// where `amount` is the indentation of the `return`
+fn declare(locals: &str, amount: usize) -> String {
+    if locals.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n{}", indent(locals, amount), " ".repeat(amount))
+    }
+}
+
+/// variable for a name bound with `set` or `let`
+/// prefixed so it can't shadow anything else
+pub fn local(name: &str) -> String {
+    format!("{}_{}", LOCAL, name)
+}
+
+/// function for a macro defined with `{{{ macro name() }}}`
+pub fn macro_name(name: &str) -> String {
+    format!("{}_{}", MACRO, name)
+}
+
 /// indent each line (except the first) by a given number of spaces
 pub fn indent(source: &str, amount: usize) -> String {
     let joiner = format!("\n{}", " ".repeat(amount));
@@ -74,9 +158,47 @@ pub fn block_call(name: &str) -> String {
     )
 }
 
+/// macro function template
+/// parameters are passed after the runtime parameters
+/// `locals` are declarations of hoisted path prefixes
+pub fn macro_fn(name: &str, params: &[&str], locals: &str, body: &str) -> String {
+    let params: String = params.iter().map(|p| format!(", {}", local(p))).collect();
+
+    format!(
+        "function {}({}{}) {{
+  var {} = {}.{};
+  var {} = {};
+  {}return {};
+}}",
+        macro_name(name),
+        RUNTIME_PARAMS,
+        params,
+        ESCAPE,
+        HELPERS,
+        ESCAPE,
+        VALUE,
+        CONTEXT,
+        declare(locals, 2),
+        indent(body, 4)
+    )
+}
+
+/// macro call template
+pub fn macro_call(name: &str, args: &[Cow<str>]) -> String {
+    let args: String = args.iter().map(|a| format!(", {}", a)).collect();
+
+    format!("{}({}{})", macro_name(name), RUNTIME_PARAMS, args)
+}
+
 /// module wrapper template
-pub fn wrapper(body: &str, blocks: &[String]) -> String {
+/// macro functions are defined alongside the compiled function
+/// `locals` are declarations of hoisted path prefixes
+pub fn wrapper(locals: &str, body: &str, blocks: &[String], macros: &[String]) -> String {
     let blocks_str = indent(&blocks.join(",\n"), 4);
+    let macros_str: String = macros
+        .iter()
+        .map(|m| format!("{}\n\n  ", indent(m, 2)))
+        .collect();
 
     format!(
         "
@@ -90,10 +212,10 @@ pub fn wrapper(body: &str, blocks: &[String]) -> String {
   function compiled({}) {{
     var {} = {}.{};
     var {} = {};
-    return {};
+    {}return {};
   }}
 
-  {} = {{
+  {}{} = {{
     {}
   }};
 
@@ -106,12 +228,31 @@ pub fn wrapper(body: &str, blocks: &[String]) -> String {
         ESCAPE,
         VALUE,
         CONTEXT,
+        declare(locals, 4),
         indent(body, 6),
+        macros_str,
         BLOCKS,
         blocks_str
     )
 }
 
+use crate::generate::html::Escaper;
+
+/// escape an interpolated value for its HTML context or escape mode
+pub fn escape(escaper: Escaper, value: &str) -> String {
+    match escaper {
+        Escaper::Html => format!("{}({})", ESCAPE, value),
+        Escaper::Url => format!("{}.{}({})", HELPERS, ESCAPE_URL, value),
+        Escaper::JsString => format!("{}.{}({})", HELPERS, ESCAPE_JS, value),
+        Escaper::JsValue => format!("{}.{}({})", HELPERS, ESCAPE_JS_VALUE, value),
+        Escaper::JsValueAttr => format!("{}({}.{}({}))", ESCAPE, HELPERS, ESCAPE_JS_VALUE, value),
+        Escaper::Xml => format!("{}.{}({})", HELPERS, ESCAPE_XML, value),
+        Escaper::JsonString => format!("{}.{}({})", HELPERS, ESCAPE_JSON, value),
+        Escaper::UrlComponent => format!("{}.{}({})", HELPERS, ESCAPE_URL_COMPONENT, value),
+        Escaper::Plain => value.to_string(),
+    }
+}
+
 /// if-else template
 /// `neg` switches body and alt
 pub fn if_else(neg: bool, subject: &str, body: &str, alt: &str) -> String {
@@ -130,26 +271,209 @@ pub fn if_else(neg: bool, subject: &str, body: &str, alt: &str) -> String {
     .to_string()
 }
 
-/// iter template
-pub fn iter(depth: u32, subject: &str, body: &str, alt: &str) -> String {
-    let key = key_i(depth);
+/// function called for each element of a loop
+/// loop variables are suffixed with the depth
+/// so nested loops can refer to those of outer loops
+/// `locals` are declarations of hoisted path prefixes
+fn loop_function(name: &str, depth: u32, locals: &str, body: &str) -> String {
+    let (key, index, length, value) = (
+        key_i(depth),
+        index_i(depth),
+        length_i(depth),
+        value_i(depth),
+    );
 
     format!(
-        "iter({}, function each({}, {}, {}, {}) {{
-  var {} = {};
-  return {};
-}}, function alt() {{
-  return {};
-}})",
-        subject,
+        "function {}({}, {}, {}, {}) {{
+  var {} = {}, {} = {}, {} = {}, {} = {};
+  {}return {};
+}}",
+        name,
+        key,
+        index,
+        length,
+        value,
+        KEY,
         key,
         INDEX,
+        index,
         LENGTH,
+        length,
         VALUE,
-        KEY,
-        key,
+        value,
+        declare(locals, 2),
+        indent(body, 4),
+    )
+}
+
+/// iter template
+/// `options` is an object of clauses from `iter_options`
+pub fn iter(
+    depth: u32,
+    subject: &str,
+    locals: &str,
+    body: &str,
+    alt: &str,
+    options: Option<&str>,
+) -> String {
+    format!(
+        "iter({}, {}, function alt() {{
+  return {};
+}}{})",
+        subject,
+        loop_function("each", depth, locals, body),
+        indent(alt, 4),
+        options.map(|o| format!(", {}", o)).unwrap_or_default()
+    )
+}
+
+/// range template
+/// a plain `for` loop from `start` to `end` inclusive,
+/// calling the loop function with the number as `@value`
+pub fn range(depth: u32, start: &str, end: &str, locals: &str, body: &str, alt: &str) -> String {
+    format!(
+        "(function range(start, end, each, alt) {{
+  start = Math.floor(start);
+  var length = Math.floor(end) - start + 1, output = '';
+  if (!(length > 0 && isFinite(length))) {{
+    return alt();
+  }}
+  for (var i = 0; i < length; i += 1) {{
+    output += each(i, i, length, start + i);
+  }}
+  return output;
+}})({}, {}, {}, function alt() {{
+  return {};
+}})",
+        start,
+        end,
+        loop_function("each", depth, locals, body),
+        indent(alt, 4),
+    )
+}
+
+/// range array template
+/// the numbers from `start` to `end` inclusive, the same as `range` iterates over
+pub fn range_array(start: &str, end: &str) -> String {
+    format!(
+        "(function range(start, end) {{
+  start = Math.floor(start);
+  var length = Math.floor(end) - start + 1, output = [];
+  for (var i = 0; i < length && isFinite(length); i += 1) {{
+    output.push(start + i);
+  }}
+  return output;
+}})({}, {})",
+        start, end
+    )
+}
+
+/// iter options template
+/// `filter` is called like the loop body, with the same variables
+/// `sep` is already escaped
+pub fn iter_options(
+    depth: u32,
+    filter: Option<&str>,
+    limit: Option<&str>,
+    offset: Option<&str>,
+    reverse: bool,
+    sep: Option<&str>,
+) -> String {
+    let mut options = Vec::new();
+    if let Some(filter) = filter {
+        options.push(format!(
+            "where: {}",
+            loop_function("where", depth, "", filter)
+        ));
+    }
+    if let Some(limit) = limit {
+        options.push(format!("limit: {}", limit));
+    }
+    if let Some(offset) = offset {
+        options.push(format!("offset: {}", offset));
+    }
+    if reverse {
+        options.push("reverse: true".to_string());
+    }
+    if let Some(sep) = sep {
+        options.push(format!("sep: {}", sep));
+    }
+
+    format!("{{\n  {}\n}}", indent(&options.join(",\n"), 2))
+}
+
+/// block helper template
+/// the helper is passed an object of functions which render `body` and `alt`
+/// after the other arguments
+pub fn block_helper(name: &str, args: &[Cow<str>], body: &str, alt: &str) -> String {
+    let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    args.push(format!(
+        "{{
+  body: function body() {{
+    return {};
+  }},
+  alt: function alt() {{
+    return {};
+  }}
+}}",
+        indent(body, 6),
+        indent(alt, 6)
+    ));
+
+    format!(
+        "{}({}, {}, '{}', [{}])",
+        HELPER,
+        CONTEXT,
+        HELPERS,
+        name,
+        args.join(", ")
+    )
+}
+
+/// local variable template
+/// `value` is evaluated once and bound to `name` within `body`
+pub fn let_in(name: &str, value: &str, body: &str) -> String {
+    format!(
+        "(function ({}) {{
+  return {};
+}})({})",
+        local(name),
+        indent(body, 4),
+        value
+    )
+}
+
+/// ambiguous `BEGIN` template
+/// `relative` is evaluated once, and chooses between paths in `body` as `relative{depth}`
+pub fn relative_in(depth: u32, relative: &str, body: &str) -> String {
+    format!(
+        "(function ({}) {{
+  return {};
+}})({})",
+        relative_i(depth),
         indent(body, 4),
-        indent(alt, 4)
+        relative
+    )
+}
+
+/// switch template
+/// `cases` are pairs of value and body
+pub fn switch(subject: &str, cases: &[(String, String)], default: &str) -> String {
+    let cases: String = cases
+        .iter()
+        .map(|(value, body)| format!("\n    case {}:\n      return {};", value, indent(body, 8)))
+        .collect();
+
+    format!(
+        "(function (subject) {{
+  switch (subject) {{{}
+    default:
+      return {};
+  }}
+}})({})",
+        cases,
+        indent(default, 8),
+        subject
     )
 }
 
@@ -159,7 +483,10 @@ pub fn concat(input: &[String]) -> String {
 }
 
 use crate::parse::{
-    expression::Expression,
+    expression::{
+        unescape,
+        Expression,
+    },
     path::{
         Path,
         PathPart,
@@ -180,79 +507,176 @@ pub fn escape_path(input: &str) -> String {
         .collect()
 }
 
+/// How guard chains access properties
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Access {
+    // `obj['key']`, which can reach inherited properties like `constructor`
+    Any,
+    // `helpers.__own(obj, 'key')`, which only follows own properties
+    Own,
+}
+
+/// create guarded chained property access
+pub fn guard(input: Path<Span>, access: Access) -> String {
+    guard_from(CONTEXT, input, access)
+}
+
 /// create guarded chained property access
-pub fn guard(input: Path<Span>) -> String {
-    let mut exp = CONTEXT.to_string();
+/// starting from the given JS variable
+pub fn guard_from(root: &str, input: Path<Span>, access: Access) -> String {
+    format!("{}({})", GUARD, chain_from(root, input, access))
+}
+
+/// create chained property access which stops at the first falsy value,
+/// starting from the given JS variable
+pub fn chain_from(root: &str, input: Path<Span>, access: Access) -> String {
+    let mut exp = root.to_string();
     let mut last = exp.clone();
 
     for part in input {
-        last = format!("{}['{}']", last, escape_path(part.inner()));
-        exp.push_str(" && ");
-        exp.push_str(&last);
-
-        if let PathPart::PartDepth(_, n) = part {
-            last = format!("{}[key{}]", last, n);
-            exp.push_str(" && ");
-            exp.push_str(&last);
+        let key = match part {
+            PathPart::Part(s) | PathPart::PartDepth(s, _) => {
+                format!("'{}'", escape_path(s.fragment()))
+            }
+            PathPart::Computed(_, expr) | PathPart::ComputedDepth(_, expr, _) => {
+                expression((**expr).clone(), access).into_owned()
+            }
+        };
+        let mut keys = vec![key];
+        if let PathPart::PartDepth(_, n) | PathPart::ComputedDepth(_, _, n) = part {
+            keys.push(key_i(*n));
+        }
+
+        for key in keys {
+            match access {
+                Access::Any => {
+                    last = format!("{}[{}]", last, key);
+                    exp.push_str(" && ");
+                    exp.push_str(&last);
+                }
+                // `__own` returns undefined for null, so the chain doesn't need `&&`
+                Access::Own => exp = format!("{}.{}({}, {})", HELPERS, OWN, exp, key),
+            }
         }
     }
 
-    format!("{}({})", GUARD, exp)
+    exp
 }
 
 use std::borrow::Cow;
 
-/// Unescape contents of string literal
-fn unescape(input: &str) -> String {
-    // remove first and last quote
-    let input = &input[1..(input.len() - 1)];
-    let mut output = String::new();
-
-    let mut chars = input.chars();
-    while let Some(c) = chars.next() {
-        output.push(if c == '\\' {
-            match chars.next() {
-                Some(c) => match c {
-                    'r' => '\r',
-                    'n' => '\n',
-                    't' => '\t',
-                    _ => c,
-                },
-                _ => c,
-            }
-        } else {
-            c
-        });
-    }
+/// object of keyword arguments, passed to a helper after the positional arguments
+pub fn keyword_arguments(kwargs: Vec<(Span, Expression<Span>)>, access: Access) -> String {
+    let pairs: Vec<String> = kwargs
+        .into_iter()
+        .map(|(key, arg)| {
+            format!(
+                "{}: {}",
+                json::stringify(*key.fragment()),
+                expression(arg, access)
+            )
+        })
+        .collect();
 
-    output
+    format!("{{ {} }}", pairs.join(", "))
+}
+
+/// whether an expression is the result of a helper call shared with other expressions
+fn shared_call(expr: &Expression<Span>) -> bool {
+    match expr {
+        Expression::Path { path, .. } => matches!(
+            path.first(),
+            Some(PathPart::PartDepth(part, _)) | Some(PathPart::ComputedDepth(part, _, _))
+                if *part.fragment() == "@call"
+        ),
+        _ => false,
+    }
 }
 
 /// create JS code for a given expression
-pub fn expression(input: Expression<Span>) -> Cow<str> {
+pub fn expression(input: Expression<Span>, access: Access) -> Cow<str> {
     match input {
         Expression::StringLiteral(value) => {
             json::stringify(json::from(unescape(value.fragment()))).into()
         }
-        Expression::Path { path, .. } => {
-            if let Some(part) = path.get(0).map(|p| p.inner()) {
-                match part {
-                    "@root" => CONTEXT.into(),
-                    "@key" => KEY.into(),
-                    "@index" => INDEX.into(),
-                    "@value" => format!("guard({})", VALUE).into(),
-                    "@first" => FIRST.into(),
-                    "@last" => LAST.into(),
-                    _ => guard(&path).into(),
+        Expression::NumberLiteral(value) => {
+            json::stringify(json::from(value.parse::<f64>().unwrap_or(0.0))).into()
+        }
+        Expression::BooleanLiteral(value) | Expression::NullLiteral(value) => {
+            (*value.fragment()).into()
+        }
+        Expression::Path { path, .. } => match path.first() {
+            // a helper call whose result is used again, from the first time it's evaluated
+            Some(PathPart::ComputedDepth(part, call, n)) if *part.fragment() == "@call" => format!(
+                "({} = {})",
+                call_i(*n),
+                expression((**call).clone(), access)
+            )
+            .into(),
+            // `../@index` refers to the loop at depth `n`
+            Some(PathPart::PartDepth(part, n)) if part.starts_with('@') => match *part.fragment() {
+                "@key" => key_i(*n).into(),
+                "@index" => index_i(*n).into(),
+                "@length" => length_i(*n).into(),
+                "@value" => guard_from(&value_i(*n), &path[1..], access).into(),
+                "@first" => format!("{} === 0", index_i(*n)).into(),
+                "@last" => format!("{} === {} - 1", index_i(*n), length_i(*n)).into(),
+                "@relative" => relative_i(*n).into(),
+                // a hoisted prefix of the path
+                "@path" => guard_from(&path_i(*n), &path[1..], access).into(),
+                // the result of a helper call evaluated before
+                "@call" => call_i(*n).into(),
+                _ => guard(&path, access).into(),
+            },
+            Some(part) => match part.inner() {
+                // `@local.name.a.b` accesses `a.b` of a `set` or `let` variable
+                "@local" if path.len() > 1 => {
+                    let root = match &path[1] {
+                        PathPart::PartDepth(name, n) => {
+                            format!("{}[{}]", local(name.fragment()), key_i(*n))
+                        }
+                        name => local(name.inner()),
+                    };
+                    guard_from(&root, &path[2..], access).into()
                 }
-            } else {
-                guard(&path).into()
+                // `@root.a.b` accesses `a.b` starting from the root context
+                "@root" if path.len() > 1 => guard(&path[1..], access).into(),
+                "@root" => CONTEXT.into(),
+                "@key" => KEY.into(),
+                "@index" => INDEX.into(),
+                "@length" => LENGTH.into(),
+                "@value" => format!("guard({})", VALUE).into(),
+                "@first" => FIRST.into(),
+                "@last" => LAST.into(),
+                _ => guard(&path, access).into(),
+            },
+            None => guard(&path, access).into(),
+        },
+        Expression::Helper {
+            name, args, kwargs, ..
+        } => {
+            let mut args: Vec<Cow<str>> = args
+                .into_iter()
+                .map(|arg| expression(arg, access))
+                .collect();
+            if !kwargs.is_empty() {
+                args.push(keyword_arguments(kwargs, access).into());
             }
+
+            format!(
+                "{}({}, {}, '{}', [{}])",
+                HELPER,
+                CONTEXT,
+                HELPERS,
+                name,
+                args.join(", ")
+            )
+            .into()
         }
-        Expression::Helper { name, args, .. } | Expression::LegacyHelper { name, args, .. } => {
+        Expression::LegacyHelper { name, args, .. } => {
             let args_str = args
                 .into_iter()
-                .map(expression)
+                .map(|arg| expression(arg, access))
                 .collect::<Vec<Cow<str>>>()
                 .join(", ");
 
@@ -262,7 +686,46 @@ pub fn expression(input: Expression<Span>) -> Cow<str> {
             )
             .into()
         }
-        Expression::Negative { expr, .. } => format!("!{}", expression(*expr)).into(),
+        Expression::MacroCall { name, args, .. } => {
+            let args: Vec<Cow<str>> = args
+                .into_iter()
+                .map(|arg| expression(arg, access))
+                .collect();
+
+            macro_call(name.fragment(), &args).into()
+        }
+        Expression::Negative { expr, .. } => format!("!{}", expression(*expr, access)).into(),
+        // `left` is only evaluated once, and is empty if `guard` would output nothing
+        Expression::Coalesce { left, right, .. } => {
+            let left = match *left {
+                // paths are already guarded, apart from shared helper calls
+                left @ Expression::Path { .. } if !shared_call(&left) => expression(left, access),
+                left => format!("{}({})", GUARD, expression(left, access)).into(),
+            };
+
+            format!(
+                "(function (left) {{ return left === '' ? {} : left; }})({})",
+                expression(*right, access),
+                left
+            )
+            .into()
+        }
+        Expression::Ternary {
+            condition,
+            then,
+            otherwise,
+            ..
+        } => format!(
+            "({} ? {} : {})",
+            expression(*condition, access),
+            expression(*then, access),
+            expression(*otherwise, access)
+        )
+        .into(),
+        // an array for `iter`, where the loop over a range has clauses
+        Expression::Range { start, end, .. } => {
+            range_array(&expression(*start, access), &expression(*end, access)).into()
+        }
     }
 }
 
@@ -307,7 +770,7 @@ mod tests {
     #[test]
     fn wrapper_test() {
         assert_eq!(
-            wrapper("'stuff'", &[]),
+            wrapper("", "'stuff'", &[], &[]),
             "
 (function (factory) {
   if (typeof module === 'object' && module.exports) {
@@ -332,7 +795,12 @@ mod tests {
         );
 
         assert_eq!(
-            wrapper("'stuff'", &["one".to_string(), "two\nthree".to_string(),]),
+            wrapper(
+                "var path0 = context && context['a'];",
+                "'stuff'",
+                &["one".to_string(), "two\nthree".to_string(),],
+                &["function a() {\n  return 'a';\n}".to_string()]
+            ),
             "
 (function (factory) {
   if (typeof module === 'object' && module.exports) {
@@ -344,9 +812,14 @@ mod tests {
   function compiled(helpers, context, guard, iter, helper) {
     var __escape = helpers.__escape;
     var value = context;
+    var path0 = context && context['a'];
     return 'stuff';
   }
 
+  function a() {
+    return 'a';
+  }
+
   compiled.blocks = {
     one,
     two
@@ -380,9 +853,18 @@ mod tests {
     #[test]
     fn iter_test() {
         assert_eq!(
-            iter(9, "stuff", "'for ' + \n'each one'", "'if ' + \n'none'"),
-            "iter(stuff, function each(key9, index, length, value) {
-  var key = key9;
+            iter(
+                9,
+                "stuff",
+                "var path0 = value9 && value9['a'];\nvar path1 = path0 && path0['b'];",
+                "'for ' + \n'each one'",
+                "'if ' + \n'none'",
+                None
+            ),
+            "iter(stuff, function each(key9, index9, length9, value9) {
+  var key = key9, index = index9, length = length9, value = value9;
+  var path0 = value9 && value9['a'];
+  var path1 = path0 && path0['b'];
   return 'for ' + 
     'each one';
 }, function alt() {
@@ -393,71 +875,425 @@ mod tests {
         )
     }
 
+    #[test]
+    fn let_in_test() {
+        assert_eq!(
+            let_in("total", "stuff", "'the ' + \nlocal_total"),
+            "(function (local_total) {
+  return 'the ' + 
+    local_total;
+})(stuff)"
+                .to_string()
+        )
+    }
+
+    #[test]
+    fn switch_test() {
+        assert_eq!(
+            switch(
+                "stuff",
+                &[
+                    ("'a'".to_string(), "'first ' + \n'one'".to_string()),
+                    ("2".to_string(), "'second'".to_string())
+                ],
+                "'neither'"
+            ),
+            "(function (subject) {
+  switch (subject) {
+    case 'a':
+      return 'first ' + 
+        'one';
+    case 2:
+      return 'second';
+    default:
+      return 'neither';
+  }
+})(stuff)"
+                .to_string()
+        )
+    }
+
+    #[test]
+    fn macro_test() {
+        assert_eq!(
+            macro_fn("avatar", &["user", "size"], "", "'a ' + \nlocal_size"),
+            "function macro_avatar(helpers, context, guard, iter, helper, local_user, local_size) {
+  var __escape = helpers.__escape;
+  var value = context;
+  return 'a ' + 
+    local_size;
+}"
+            .to_string()
+        );
+
+        assert_eq!(
+            macro_call("avatar", &["value".into(), "'sm'".into()]),
+            "macro_avatar(helpers, context, guard, iter, helper, value, 'sm')".to_string()
+        );
+    }
+
+    #[test]
+    fn block_helper_test() {
+        assert_eq!(
+            block_helper("wrapIf", &["value".into()], "'a ' + \nvalue", "\"\""),
+            "helper(context, helpers, 'wrapIf', [value, {
+  body: function body() {
+    return 'a ' + 
+      value;
+  },
+  alt: function alt() {
+    return \"\";
+  }
+}])"
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn range_test() {
+        assert_eq!(
+            range(1, "1", "rating", "", "'*'", "'none'"),
+            "(function range(start, end, each, alt) {
+  start = Math.floor(start);
+  var length = Math.floor(end) - start + 1, output = '';
+  if (!(length > 0 && isFinite(length))) {
+    return alt();
+  }
+  for (var i = 0; i < length; i += 1) {
+    output += each(i, i, length, start + i);
+  }
+  return output;
+})(1, rating, function each(key1, index1, length1, value1) {
+  var key = key1, index = index1, length = length1, value = value1;
+  return '*';
+}, function alt() {
+  return 'none';
+})"
+        );
+    }
+
+    #[test]
+    fn range_array_test() {
+        assert_eq!(
+            expression(
+                Expression::Range {
+                    span: sp("1..n"),
+                    start: Box::new(Expression::NumberLiteral(sp("1"))),
+                    end: Box::new(Expression::Path {
+                        span: sp("n"),
+                        path: vec![PathPart::Part(sp("n"))],
+                    }),
+                },
+                Access::Any
+            ),
+            "(function range(start, end) {
+  start = Math.floor(start);
+  var length = Math.floor(end) - start + 1, output = [];
+  for (var i = 0; i < length && isFinite(length); i += 1) {
+    output.push(start + i);
+  }
+  return output;
+})(1, guard(context && context['n']))"
+        );
+    }
+
+    #[test]
+    fn iter_options_test() {
+        assert_eq!(
+            iter(
+                0,
+                "stuff",
+                "",
+                "value",
+                "''",
+                Some(&iter_options(
+                    0,
+                    Some("!value"),
+                    Some("5"),
+                    None,
+                    true,
+                    Some("__escape(', ')")
+                ))
+            ),
+            "iter(stuff, function each(key0, index0, length0, value0) {
+  var key = key0, index = index0, length = length0, value = value0;
+  return value;
+}, function alt() {
+  return '';
+}, {
+  where: function where(key0, index0, length0, value0) {
+    var key = key0, index = index0, length = length0, value = value0;
+    return !value;
+  },
+  limit: 5,
+  reverse: true,
+  sep: __escape(', ')
+})"
+            .to_string()
+        )
+    }
+
     #[test]
     fn guard_test() {
         assert_eq!(
-            guard(&[PathPart::Part(sp("thing")), PathPart::Part(sp("stuff"))]),
+            guard(
+                &[PathPart::Part(sp("thing")), PathPart::Part(sp("stuff"))],
+                Access::Any
+            ),
             "guard(context && context['thing'] && context['thing']['stuff'])"
         );
 
         assert_eq!(guard(
             &[PathPart::PartDepth(sp("items"), 1), PathPart::Part(sp("prop"))],
+            Access::Any,
         ), "guard(context && context['items'] && context['items'][key1] && context['items'][key1]['prop'])");
 
         assert_eq!(
-            guard(&[PathPart::Part(sp("foo\\bar"))]),
+            guard(&[PathPart::Part(sp("foo\\bar"))], Access::Any),
             "guard(context && context['foo\\\\bar'])"
-        )
+        );
+
+        assert_eq!(guard(&[
+            PathPart::Part(sp("posts")),
+            PathPart::Computed(sp("[0]"), Box::new(Expression::NumberLiteral(sp("0")))),
+            PathPart::Computed(sp("[\"a b\"]"), Box::new(Expression::StringLiteral(sp("\"a b\"")))),
+        ], Access::Any), "guard(context && context['posts'] && context['posts'][0] && context['posts'][0][\"a b\"])");
+
+        assert_eq!(guard(&[
+            PathPart::Part(sp("translations")),
+            PathPart::ComputedDepth(sp("[lang]"), Box::new(Expression::Path {
+                span: sp("lang"),
+                path: vec![PathPart::Part(sp("lang"))],
+            }), 0),
+        ], Access::Any), "guard(context && context['translations'] && context['translations'][guard(context && context['lang'])] && context['translations'][guard(context && context['lang'])][key0])");
+    }
+
+    #[test]
+    fn guard_own_test() {
+        assert_eq!(
+            guard(
+                &[
+                    PathPart::PartDepth(sp("items"), 1),
+                    PathPart::Part(sp("it's"))
+                ],
+                Access::Own,
+            ),
+            "guard(helpers.__own(helpers.__own(helpers.__own(context, 'items'), key1), 'it\\'s'))"
+        );
+
+        assert_eq!(
+            guard(
+                &[
+                    PathPart::Part(sp("translations")),
+                    PathPart::Computed(
+                        sp("[@key]"),
+                        Box::new(Expression::Path {
+                            span: sp("@key"),
+                            path: vec![PathPart::Part(sp("@key"))],
+                        })
+                    ),
+                ],
+                Access::Own
+            ),
+            "guard(helpers.__own(helpers.__own(context, 'translations'), key))"
+        );
     }
 
     #[test]
     fn expression_test() {
         assert_eq!(
-            expression(Expression::StringLiteral(sp(
-                "\"stuff\\n \\\"about\\\" things\""
-            ))),
+            expression(
+                Expression::StringLiteral(sp("\"stuff\\n \\\"about\\\" things\"")),
+                Access::Any
+            ),
             "\"stuff\\n \\\"about\\\" things\"".to_string()
         );
 
         assert_eq!(
-            expression(Expression::Path {
-                span: sp("thing"),
-                path: vec![PathPart::Part(sp("thing"))]
-            }),
+            expression(
+                Expression::StringLiteral(sp("'it\\'s \"quoted\"'")),
+                Access::Any
+            ),
+            "\"it's \\\"quoted\\\"\"".to_string()
+        );
+
+        assert_eq!(
+            expression(Expression::NumberLiteral(sp("-2.50")), Access::Any),
+            "-2.5".to_string()
+        );
+
+        assert_eq!(
+            expression(Expression::NumberLiteral(sp("1e3")), Access::Any),
+            "1000".to_string()
+        );
+
+        assert_eq!(
+            expression(Expression::BooleanLiteral(sp("false")), Access::Any),
+            "false".to_string()
+        );
+
+        assert_eq!(
+            expression(Expression::NullLiteral(sp("null")), Access::Any),
+            "null".to_string()
+        );
+
+        assert_eq!(
+            expression(
+                Expression::Path {
+                    span: sp("thing"),
+                    path: vec![PathPart::Part(sp("thing"))]
+                },
+                Access::Any
+            ),
             sp("guard(context && context['thing'])").to_string()
         );
 
         assert_eq!(
-            expression(Expression::Path {
-                span: sp("@root"),
-                path: vec![PathPart::Part(sp("@root"))]
-            }),
+            expression(
+                Expression::Path {
+                    span: sp("@root"),
+                    path: vec![PathPart::Part(sp("@root"))]
+                },
+                Access::Any
+            ),
             sp("context").to_string()
         );
 
         assert_eq!(
-            expression(Expression::Path {
-                span: sp("@first"),
-                path: vec![PathPart::Part(sp("@first"))]
-            }),
+            expression(
+                Expression::Path {
+                    span: sp("@root.config.relative_path"),
+                    path: vec![
+                        PathPart::Part(sp("@root")),
+                        PathPart::Part(sp("config")),
+                        PathPart::Part(sp("relative_path")),
+                    ]
+                },
+                Access::Any
+            ),
+            sp("guard(context && context['config'] && context['config']['relative_path'])")
+                .to_string()
+        );
+
+        assert_eq!(
+            expression(
+                Expression::Path {
+                    span: sp("@first"),
+                    path: vec![PathPart::Part(sp("@first"))]
+                },
+                Access::Any
+            ),
             sp("index === 0").to_string()
         );
 
         assert_eq!(
-            expression(Expression::Path {
-                span: sp("@last"),
-                path: vec![PathPart::Part(sp("@last"))]
-            }),
+            expression(
+                Expression::Path {
+                    span: sp("@last"),
+                    path: vec![PathPart::Part(sp("@last"))]
+                },
+                Access::Any
+            ),
             sp("index === length - 1").to_string()
         );
 
+        assert_eq!(
+            expression(
+                Expression::Path {
+                    span: sp("../@index"),
+                    path: vec![PathPart::PartDepth(sp("@index"), 2)]
+                },
+                Access::Any
+            ),
+            "index2".to_string()
+        );
+
+        assert_eq!(
+            expression(
+                Expression::Path {
+                    span: sp("../@last"),
+                    path: vec![PathPart::PartDepth(sp("@last"), 0)]
+                },
+                Access::Any
+            ),
+            "index0 === length0 - 1".to_string()
+        );
+
+        assert_eq!(
+            expression(
+                Expression::Path {
+                    span: sp("../@value.name"),
+                    path: vec![
+                        PathPart::PartDepth(sp("@value"), 1),
+                        PathPart::Part(sp("name"))
+                    ]
+                },
+                Access::Any
+            ),
+            "guard(value1 && value1['name'])".to_string()
+        );
+
         assert_eq!(expression(Expression::Helper {
             span: sp("localeToHTML(userLang, defaultLang)"),
             name: sp("localeToHTML"),
             args: vec![
                 Expression::Path { span: sp("userLang"), path: vec![PathPart::Part(sp("userLang"))] },
                 Expression::Path { span: sp("defaultLang"), path: vec![PathPart::Part(sp("defaultLang"))] },
-            ]
-        }), "helper(context, helpers, 'localeToHTML', [guard(context && context['userLang']), guard(context && context['defaultLang'])])");
+            ],
+            kwargs: vec![],
+        }, Access::Any), "helper(context, helpers, 'localeToHTML', [guard(context && context['userLang']), guard(context && context['defaultLang'])])");
+
+        assert_eq!(expression(Expression::Helper {
+            span: sp("buildAvatar(user, size=\"24\", rounded=true)"),
+            name: sp("buildAvatar"),
+            args: vec![
+                Expression::Path { span: sp("user"), path: vec![PathPart::Part(sp("user"))] },
+            ],
+            kwargs: vec![
+                (sp("size"), Expression::StringLiteral(sp("\"24\""))),
+                (sp("rounded"), Expression::BooleanLiteral(sp("true"))),
+            ],
+        }, Access::Any), "helper(context, helpers, 'buildAvatar', [guard(context && context['user']), { \"size\": \"24\", \"rounded\": true }])");
+
+        assert_eq!(
+            expression(
+                Expression::Ternary {
+                    span: sp("isOwner ? \"owner\" : name ?? null"),
+                    condition: Box::new(Expression::Path {
+                        span: sp("isOwner"),
+                        path: vec![PathPart::Part(sp("isOwner"))]
+                    }),
+                    then: Box::new(Expression::StringLiteral(sp("\"owner\""))),
+                    otherwise: Box::new(Expression::Coalesce {
+                        span: sp("name ?? null"),
+                        left: Box::new(Expression::Path {
+                            span: sp("name"),
+                            path: vec![PathPart::Part(sp("name"))]
+                        }),
+                        right: Box::new(Expression::NullLiteral(sp("null"))),
+                    }),
+                },
+                Access::Any
+            ),
+            "(guard(context && context['isOwner']) ? \"owner\" : \
+             (function (left) { return left === '' ? null : left; })\
+             (guard(context && context['name'])))"
+                .to_string()
+        );
+
+        assert_eq!(
+            expression(
+                Expression::Path {
+                    span: sp("total.value"),
+                    path: vec![
+                        PathPart::Part(sp("@local")),
+                        PathPart::PartDepth(sp("total"), 1),
+                        PathPart::Part(sp("value"))
+                    ]
+                },
+                Access::Any
+            ),
+            "guard(local_total[key1] && local_total[key1]['value'])".to_string()
+        );
     }
 }
@@ -1,5 +1,11 @@
+mod audit;
 mod generate;
+mod limits;
+mod optimize;
+mod options;
 mod parse;
+mod policy;
+mod prototype;
 
 #[cfg(target_arch = "wasm32")]
 mod console {
@@ -54,10 +60,77 @@ mod console {
     };
 }
 
+use parse::SpanExt;
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
+pub fn compile(source: &str, filename: &str) -> Result<String, String> {
+    compile_with_options(source, filename, "{}")
+}
+
+/// Compile a template with options given as a JSON object,
+/// like `{ "escape": "xml" }`
+/// Returns the error messages if the template breaks a rule set by the options
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
+pub fn compile_with_options(source: &str, filename: &str, options: &str) -> Result<String, String> {
+    console_error_panic_hook::set_once();
+
+    let mut options =
+        options::Options::from_json(options).map_err(|e| limits::file_error(filename, &e))?;
+    let tree = parse(source, filename, &mut options)?;
+
+    let mut errors = prototype::check(&tree, options.own_properties);
+    if let Some(allowlist) = &options.raw_allowlist {
+        errors.extend(
+            audit::raw_sites(&tree, options.escape)
+                .iter()
+                .filter(|site| !allowlist.allows(site))
+                .map(audit::not_allowed_error),
+        );
+    }
+    if let Some(policy) = &options.policy {
+        errors.extend(policy.check(&tree));
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    let tree = if options.optimize {
+        optimize::optimize(tree)
+    } else {
+        tree
+    };
+    let output = generate::generator::generate(tree, &options);
+    options.limits.check_output(&output, filename)?;
+
+    Ok(output)
+}
+
+/// List the raw interpolations in a template as a JSON array,
+/// checked against the `rawAllowlist` option if it is given
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
-pub fn compile(source: &str, filename: &str) -> String {
+pub fn audit(source: &str, filename: &str, options: &str) -> Result<String, String> {
     console_error_panic_hook::set_once();
 
+    let mut options =
+        options::Options::from_json(options).map_err(|e| limits::file_error(filename, &e))?;
+    let tree = parse(source, filename, &mut options)?;
+
+    Ok(audit::report(
+        &audit::raw_sites(&tree, options.escape),
+        options.raw_allowlist.as_ref(),
+    ))
+}
+
+/// Parse a template into instructions, applying its pragmas to `options`
+/// Returns an error if the template is over the size, token, or nesting limits,
+/// including how deeply expressions are nested, or if a block is never closed
+fn parse<'a>(
+    source: &'a str,
+    filename: &'a str,
+    options: &mut options::Options,
+) -> Result<Vec<parse::tree::Instruction<parse::Span<'a>>>, String> {
+    options.limits.check_source(source, filename)?;
+
     let program = parse::Span::new_extra(
         &source,
         parse::FileInfo {
@@ -65,7 +138,16 @@ pub fn compile(source: &str, filename: &str) -> String {
             full_source: &source,
         },
     );
-    let (rest, tokens) = parse::tokens::tokens(program).unwrap_or_else(|e| {
+    let tokens = parse::expression::with_depth_limit(options.limits.depth, || {
+        parse::tokens::tokens(program)
+    });
+    let (rest, tokens) = match tokens {
+        Err(nom::Err::Failure(e)) if e.code == nom::error::ErrorKind::TooLarge => {
+            return Err(options.limits.expression_depth_error(e.input));
+        }
+        tokens => tokens,
+    }
+    .unwrap_or_else(|e| {
         console::error!("Failed at parse::tokens::tokens, {:?}", e);
         (
             parse::Span::new_extra(
@@ -81,15 +163,19 @@ pub fn compile(source: &str, filename: &str) -> String {
     if rest.len() > 0 {
         console::error!("There was some source text left over, {:?}", rest);
     }
+    let tokens = options.apply_pragmas(tokens)?;
+    let macros = parse::tree::macro_names(&tokens);
     let fixed = parse::tree::fix_extra_tokens(tokens);
+    options.limits.check_tokens(&fixed)?;
     let mut iter = fixed.into_iter();
     let mut tree = vec![];
-    let rest = parse::tree::tree(0, &[], &mut iter, &mut tree).unwrap_or_else(|e| {
-        console::error!("Failed at parse::tree::tree, {:?}", e);
-        None
-    });
+    let rest = parse::tree::tree(0, &[], &macros, &mut iter, &mut tree)
+        .map_err(|e| e.span.error(e.message, e.help))?;
     if let Some(rest) = rest {
-        console::error!("There was a token left over, {:?}", rest);
+        return Err(rest.span().error(
+            "token is outside of any block",
+            "remove this token or move it into the block it belongs to",
+        ));
     }
-    generate::generator::generate(tree)
+    Ok(tree)
 }
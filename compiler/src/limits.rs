@@ -0,0 +1,274 @@
+use crate::{
+    console::warn,
+    parse::{
+        tokens::Token,
+        Span,
+        SpanExt,
+    },
+};
+
+/// Limits on the resources used to compile a template,
+/// for compiling templates from untrusted sources
+/// `None` is no limit
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Limits {
+    // blocks and `set` variables nested within each other,
+    // and separately, expressions nested within each other
+    pub depth: Option<usize>,
+    // bytes of template source
+    pub source_size: Option<usize>,
+    // tokens parsed
+    pub tokens: Option<usize>,
+    // bytes of generated code
+    pub output_size: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            depth: Some(64),
+            source_size: None,
+            tokens: Some(1_000_000),
+            output_size: None,
+        }
+    }
+}
+
+/// An error which isn't about any part of the template
+pub fn file_error(filename: &str, message: &str) -> String {
+    format!(
+        "[benchpress] error: {}
+     --> {}
+",
+        message, filename
+    )
+}
+
+impl Limits {
+    /// Read limits from a JSON object like `{ "depth": 16, "sourceSize": null }`,
+    /// where `null` removes a limit and missing ones are the default
+    pub fn from_json(value: &json::JsonValue) -> Self {
+        let mut limits = Limits::default();
+        for (key, limit) in [
+            ("depth", &mut limits.depth),
+            ("sourceSize", &mut limits.source_size),
+            ("tokens", &mut limits.tokens),
+            ("outputSize", &mut limits.output_size),
+        ]
+        .iter_mut()
+        {
+            if !value.has_key(key) {
+                continue;
+            }
+            match &value[*key] {
+                json::JsonValue::Null => **limit = None,
+                max => match max.as_usize() {
+                    Some(max) => **limit = Some(max),
+                    None => warn!("[benchpress] warning: invalid `{}` limit, {}", key, max),
+                },
+            }
+        }
+        limits
+    }
+
+    pub fn check_source(&self, source: &str, filename: &str) -> Result<(), String> {
+        match self.source_size {
+            Some(max) if source.len() > max => Err(file_error(
+                filename,
+                &format!(
+                    "template is {} bytes, over the limit of {}",
+                    source.len(),
+                    max
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the nesting depth and the number of tokens which will be parsed,
+    /// before parsing them into a tree
+    pub fn check_tokens(&self, tokens: &[Token<Span>]) -> Result<(), String> {
+        if let Some(max) = self.tokens {
+            if let Some(tok) = tokens.get(max) {
+                return Err(tok.span().error(
+                    &format!("template has more than {} tokens", max),
+                    "the limit was reached here",
+                ));
+            }
+        }
+
+        // whether each open block is a `set` variable
+        let mut open: Vec<Open> = Vec::new();
+
+        for tok in tokens {
+            match tok {
+                Token::LegacyBegin { .. }
+                | Token::Each { .. }
+                | Token::If { .. }
+                | Token::LegacyIf { .. }
+                | Token::With { .. }
+                | Token::Let { .. }
+                | Token::Switch { .. }
+                | Token::Macro { .. }
+                | Token::BlockHelper { .. } => open.push(Open::Block),
+                // the rest of the block is the body of a `set`
+                Token::Set { .. } => open.push(Open::Set),
+                Token::End { .. } | Token::LegacyEnd { .. } => {
+                    while open.last() == Some(&Open::Set) {
+                        open.pop();
+                    }
+                    open.pop();
+                }
+                _ => continue,
+            }
+
+            if let Some(max) = self.depth {
+                if open.len() > max {
+                    return Err(tok.span().error(
+                        &format!("blocks are nested more than {} deep", max),
+                        "the limit was reached here",
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The error for an expression nested deeper than the depth limit, at `span`
+    pub fn expression_depth_error(&self, span: Span) -> String {
+        span.error(
+            &format!(
+                "expressions are nested more than {} deep",
+                self.depth.unwrap_or_default()
+            ),
+            "the limit was reached here",
+        )
+    }
+
+    pub fn check_output(&self, output: &str, filename: &str) -> Result<(), String> {
+        match self.output_size {
+            Some(max) if output.len() > max => Err(file_error(
+                filename,
+                &format!(
+                    "compiled template is {} bytes, over the limit of {}",
+                    output.len(),
+                    max
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Open {
+    Block,
+    Set,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{
+        expression::{
+            expression,
+            with_depth_limit,
+        },
+        test::sp,
+        tokens::tokens,
+    };
+    use nom::error::ErrorKind;
+    use pretty_assertions::assert_eq;
+
+    fn check(limits: &Limits, source: &str) -> Result<(), String> {
+        let (_, toks) = tokens(sp(source)).unwrap();
+        limits
+            .check_tokens(&toks)
+            .map_err(|e| e.lines().next().unwrap().to_string())
+    }
+
+    #[test]
+    fn from_json() {
+        let limits = Limits::from_json(&json::parse("{ \"depth\": 3, \"tokens\": null }").unwrap());
+        assert_eq!(
+            limits,
+            Limits {
+                depth: Some(3),
+                tokens: None,
+                ..Limits::default()
+            }
+        );
+    }
+
+    #[test]
+    fn depth() {
+        let limits = Limits {
+            depth: Some(2),
+            ..Limits::default()
+        };
+
+        assert_eq!(
+            check(
+                &limits,
+                "{{{ if a }}}{{{ each b }}}{c}{{{ end }}}{{{ end }}}"
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                &limits,
+                "{{{ if a }}}{{{ set x = 1 }}}{{{ each b }}}{{{ end }}}{{{ end }}}"
+            ),
+            Err("[benchpress] error: blocks are nested more than 2 deep".to_string())
+        );
+        assert_eq!(
+            check(&limits, "{{{ if a }}}{{{ set x = 1 }}}{{{ end }}}{{{ if b }}}{{{ if c }}}{{{ end }}}{{{ end }}}"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn expression_depth() {
+        let parse = |max, source| {
+            let (_, expr) =
+                with_depth_limit(Some(max), || expression(sp(source))).map_err(|e| match e {
+                    nom::Err::Failure(e) => (e.code, *e.input.fragment()),
+                    e => panic!("{:?}", e),
+                })?;
+            Ok(expr.span_to_str())
+        };
+
+        assert!(parse(3, "f(g(x))").is_ok());
+        assert_eq!(parse(3, "f(g(h(x)))"), Err((ErrorKind::TooLarge, "x")));
+        assert_eq!(parse(3, "a ? b : !!c"), Err((ErrorKind::TooLarge, "c")));
+        assert_eq!(parse(3, "x | f | g | h"), Err((ErrorKind::TooLarge, " ")));
+        assert_eq!(parse(3, "a[b[c[d]]]"), Err((ErrorKind::TooLarge, "d")));
+        // the limit only applies within `with_depth_limit`
+        assert!(expression(sp(&format!("{}x{}", "f(".repeat(20), ")".repeat(20)))).is_ok());
+    }
+
+    #[test]
+    fn token_count() {
+        let limits = Limits {
+            tokens: Some(6),
+            ..Limits::default()
+        };
+        let nested = "<!-- BEGIN a --><!-- BEGIN b --><!-- BEGIN c -->{x}<!-- END c --><!-- END b --><!-- END a -->";
+
+        assert_eq!(
+            check(
+                &Limits {
+                    tokens: Some(7),
+                    ..Limits::default()
+                },
+                nested
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(&limits, nested),
+            Err("[benchpress] error: template has more than 6 tokens".to_string())
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use crate::{
+    parse::{
+        expression::{
+            unescape,
+            Expression,
+        },
+        tree::Instruction,
+        Span,
+    },
+    prototype::visit,
+};
+
+/// Simplify instructions before generating code, without changing what they output
+///
+/// - adjacent text is merged, and empty text is dropped
+/// - an `if` on a literal is replaced by the branch it always takes
+/// - an `if` whose branches are the same is replaced by that branch
+/// - an `if !x` directly within a branch of `if x` is replaced by the branch it always takes
+///
+/// Conditions which call helpers are only removed when they're on literals,
+/// since calling a helper may do more than return a value
+pub fn optimize(instructions: Vec<Instruction<Span>>) -> Vec<Instruction<Span>> {
+    let mut output = Vec::new();
+
+    for instruction in instructions {
+        let instruction = match instruction {
+            Instruction::If { subject, body, alt } => {
+                let (body, alt) = (optimize(body), optimize(alt));
+
+                let taken = match truthy(&subject) {
+                    Some(true) => Some(body),
+                    Some(false) => Some(alt),
+                    None if !pure(&subject) => {
+                        output.push(Instruction::If { subject, body, alt });
+                        continue;
+                    }
+                    None => {
+                        let body = within(&subject, true, body);
+                        let alt = within(&subject, false, alt);
+                        if same(&body, &alt) {
+                            Some(body)
+                        } else {
+                            output.push(Instruction::If { subject, body, alt });
+                            continue;
+                        }
+                    }
+                };
+
+                for instruction in taken.into_iter().flatten() {
+                    push(&mut output, instruction);
+                }
+                continue;
+            }
+            Instruction::Iter {
+                depth,
+                subject,
+                clauses,
+                body,
+                alt,
+            } => Instruction::Iter {
+                depth,
+                subject,
+                clauses,
+                body: optimize(body),
+                alt: optimize(alt),
+            },
+            Instruction::Switch {
+                subject,
+                cases,
+                default,
+            } => Instruction::Switch {
+                subject,
+                cases: cases
+                    .into_iter()
+                    .map(|(value, body)| (value, optimize(body)))
+                    .collect(),
+                default: optimize(default),
+            },
+            Instruction::Macro { name, params, body } => Instruction::Macro {
+                name,
+                params,
+                body: optimize(body),
+            },
+            Instruction::BlockHelper {
+                name,
+                args,
+                kwargs,
+                body,
+                alt,
+            } => Instruction::BlockHelper {
+                name,
+                args,
+                kwargs,
+                body: optimize(body),
+                alt: optimize(alt),
+            },
+            Instruction::Let { name, value, body } => Instruction::Let {
+                name,
+                value,
+                body: optimize(body),
+            },
+            Instruction::Ambiguous {
+                depth,
+                relative,
+                body,
+            } => Instruction::Ambiguous {
+                depth,
+                relative,
+                body: optimize(body),
+            },
+            instruction => instruction,
+        };
+
+        push(&mut output, instruction);
+    }
+
+    output
+}
+
+/// Add an instruction to the end of `output`,
+/// merging text with text before it and dropping empty text
+fn push<'a>(output: &mut Vec<Instruction<Span<'a>>>, instruction: Instruction<Span<'a>>) {
+    match instruction {
+        Instruction::Text(mut pieces) => {
+            pieces.retain(|piece| !piece.fragment().is_empty());
+            if pieces.is_empty() {
+                return;
+            }
+
+            match output.last_mut() {
+                Some(Instruction::Text(before)) => before.append(&mut pieces),
+                _ => output.push(Instruction::Text(pieces)),
+            }
+        }
+        instruction => output.push(instruction),
+    }
+}
+
+/// Whether a literal is truthy in JS, or `None` if it isn't a literal
+fn truthy(expr: &Expression<Span>) -> Option<bool> {
+    match expr {
+        Expression::StringLiteral(value) => Some(!unescape(value.fragment()).is_empty()),
+        Expression::NumberLiteral(value) => {
+            let value = value.parse::<f64>().unwrap_or(0.0);
+            Some(value != 0.0 && !value.is_nan())
+        }
+        Expression::BooleanLiteral(value) => Some(*value.fragment() == "true"),
+        Expression::NullLiteral(_) => Some(false),
+        Expression::Negative { expr, .. } => truthy(expr).map(|value| !value),
+        _ => None,
+    }
+}
+
+/// Whether an expression only reads values, so evaluating it again gives the same result
+/// and not evaluating it has no effect
+fn pure(expr: &Expression<Span>) -> bool {
+    let mut calls = false;
+    visit(expr, &mut |expr| {
+        if let Expression::Helper { .. }
+        | Expression::LegacyHelper { .. }
+        | Expression::MacroCall { .. } = expr
+        {
+            calls = true;
+        }
+    });
+
+    !calls
+}
+
+/// Whether two expressions are the same, wherever they are in the template
+fn same_expression(a: &Expression<Span>, b: &Expression<Span>) -> bool {
+    a.clone().span_to_str() == b.clone().span_to_str()
+}
+
+/// Whether two bodies are the same, wherever they are in the template
+fn same(a: &[Instruction<Span>], b: &[Instruction<Span>]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.clone().span_to_str() == b.clone().span_to_str())
+}
+
+/// Whether one expression is the other negated
+fn negation(a: &Expression<Span>, b: &Expression<Span>) -> bool {
+    let negates = |a: &Expression<Span>, b| matches!(a, Expression::Negative { expr, .. } if same_expression(expr, b));
+
+    negates(a, b) || negates(b, a)
+}
+
+/// Simplify a branch of `if subject`, which is only output when `subject` is `value`
+/// If the branch is only another `if` on `subject` or `!subject`, it is replaced by the
+/// branch of that which is always taken
+fn within<'a>(
+    subject: &Expression<Span<'a>>,
+    value: bool,
+    mut branch: Vec<Instruction<Span<'a>>>,
+) -> Vec<Instruction<Span<'a>>> {
+    loop {
+        let taken = match branch.as_slice() {
+            [Instruction::If { subject: inner, .. }] if same_expression(inner, subject) => value,
+            [Instruction::If { subject: inner, .. }] if negation(inner, subject) => !value,
+            _ => return branch,
+        };
+
+        if let Some(Instruction::If { body, alt, .. }) = branch.pop() {
+            branch = if taken { body } else { alt };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{
+        path::PathPart,
+        test::sp,
+        tokens::tokens,
+        tree::{
+            fix_extra_tokens,
+            tree,
+        },
+    };
+    use pretty_assertions::assert_eq;
+
+    fn parsed(source: &str) -> Vec<Instruction<Span<'_>>> {
+        let (_, toks) = tokens(sp(source)).unwrap();
+        let mut instructions = vec![];
+        tree(
+            0,
+            &[],
+            &[],
+            &mut fix_extra_tokens(toks).into_iter(),
+            &mut instructions,
+        )
+        .unwrap();
+
+        instructions
+    }
+
+    fn optimized(source: &str) -> Vec<Instruction<&str>> {
+        optimize(parsed(source))
+            .into_iter()
+            .map(|i| i.span_to_str())
+            .collect()
+    }
+
+    fn unoptimized(source: &str) -> Vec<Instruction<&str>> {
+        parsed(source)
+            .into_iter()
+            .map(|i| i.span_to_str())
+            .collect()
+    }
+
+    fn path(name: &str) -> Expression<&str> {
+        Expression::Path {
+            span: name,
+            path: vec![PathPart::Part(name)],
+        }
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            optimized("a \\{b} c"),
+            vec![Instruction::Text(vec!["a ", "{b} c"])]
+        );
+        assert_eq!(
+            optimized("a{{{ if x }}}{{{ end }}}b"),
+            vec![Instruction::Text(vec!["a", "b"])]
+        );
+        assert_eq!(
+            optimized("{{{ if x }}}a \\{b}{y}{{{ end }}}"),
+            vec![Instruction::If {
+                subject: path("x"),
+                body: vec![
+                    Instruction::Text(vec!["a ", "{b}"]),
+                    Instruction::InterpEscaped(path("y")),
+                ],
+                alt: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn literal_conditions() {
+        assert_eq!(
+            optimized("{{{ if \"yes\" }}}A{{{ else }}}B{{{ end }}}"),
+            vec![Instruction::Text(vec!["A"])]
+        );
+        assert_eq!(
+            optimized("{{{ if !\"yes\" }}}A{{{ else }}}B{{{ end }}}"),
+            vec![Instruction::Text(vec!["B"])]
+        );
+        assert_eq!(optimized("{{{ if '' }}}A{{{ end }}}"), vec![]);
+        assert_eq!(
+            optimized("x{{{ if 'y' }}}A{y}{{{ end }}}z"),
+            vec![
+                Instruction::Text(vec!["x", "A"]),
+                Instruction::InterpEscaped(path("y")),
+                Instruction::Text(vec!["z"]),
+            ]
+        );
+        assert_eq!(
+            optimized(
+                "{{{ if 0 }}}A{{{ end }}}{{{ if true }}}B{{{ end }}}{{{ if null }}}C{{{ end }}}"
+            ),
+            vec![Instruction::Text(vec!["B"])]
+        );
+        assert_eq!(
+            optimized("{{{ if x }}}A{{{ end }}}"),
+            unoptimized("{{{ if x }}}A{{{ end }}}")
+        );
+    }
+
+    #[test]
+    fn same_branches() {
+        assert_eq!(
+            optimized("{{{ if x }}}A{y}{{{ else }}}A{y}{{{ end }}}"),
+            unoptimized("A{y}")
+        );
+        assert_eq!(optimized("{{{ if !x.y }}}{{{ end }}}"), vec![]);
+        // a helper is still called
+        assert_eq!(
+            optimized("{{{ if f(x) }}}A{{{ else }}}A{{{ end }}}"),
+            unoptimized("{{{ if f(x) }}}A{{{ else }}}A{{{ end }}}")
+        );
+        assert_eq!(
+            optimized("{{{ if x }}}A{y}{{{ else }}}A{z}{{{ end }}}"),
+            unoptimized("{{{ if x }}}A{y}{{{ else }}}A{z}{{{ end }}}")
+        );
+    }
+
+    #[test]
+    fn negated_conditions() {
+        assert_eq!(
+            optimized("{{{ if x }}}A{{{ else }}}{{{ if !x }}}B{{{ else }}}C{{{ end }}}{{{ end }}}"),
+            unoptimized("{{{ if x }}}A{{{ else }}}B{{{ end }}}")
+        );
+        assert_eq!(
+            optimized("{{{ if !x }}}A{{{ else }}}{{{ if x }}}B{{{ end }}}{{{ end }}}"),
+            unoptimized("{{{ if !x }}}A{{{ else }}}B{{{ end }}}")
+        );
+        assert_eq!(
+            optimized("{{{ if x }}}{{{ if x }}}A{{{ else }}}B{{{ end }}}{{{ end }}}"),
+            unoptimized("{{{ if x }}}A{{{ end }}}")
+        );
+        // other text in the branch is left as is
+        assert_eq!(
+            optimized("{{{ if x }}}A{{{ else }}} {{{ if !x }}}B{{{ end }}}{{{ end }}}"),
+            unoptimized("{{{ if x }}}A{{{ else }}} {{{ if !x }}}B{{{ end }}}{{{ end }}}")
+        );
+        // a helper may return something else the second time
+        assert_eq!(
+            optimized("{{{ if f(x) }}}A{{{ else }}}{{{ if !f(x) }}}B{{{ end }}}{{{ end }}}"),
+            unoptimized("{{{ if f(x) }}}A{{{ else }}}{{{ if !f(x) }}}B{{{ end }}}{{{ end }}}")
+        );
+    }
+}
@@ -0,0 +1,340 @@
+use crate::{
+    audit::Allowlist,
+    console::warn,
+    limits::Limits,
+    generate::html::Escaper,
+    parse::{
+        tokens::Token,
+        Span,
+        SpanExt,
+    },
+    policy::Policy,
+};
+use nom::Slice;
+
+/// How `{escaped}` interpolations are escaped
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EscapeMode {
+    // HTML escaping chosen by the context of each interpolation
+    Html,
+    // XML entities
+    Xml,
+    // no escaping, for plain text
+    Plain,
+    // the inside of a JSON string
+    JsonString,
+    // a URL component
+    Url,
+}
+
+impl EscapeMode {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "html" => Some(EscapeMode::Html),
+            "xml" => Some(EscapeMode::Xml),
+            "plain" | "none" => Some(EscapeMode::Plain),
+            "json-string" => Some(EscapeMode::JsonString),
+            "url" => Some(EscapeMode::Url),
+            _ => None,
+        }
+    }
+
+    /// Whether values are escaped so they can't add markup to HTML
+    pub fn escapes_markup(self) -> bool {
+        matches!(self, EscapeMode::Html | EscapeMode::Xml)
+    }
+
+    /// The escaper used for every interpolation in this mode,
+    /// where `Html` is only the default for text which doesn't have a context
+    pub fn escaper(self) -> Escaper {
+        match self {
+            EscapeMode::Html => Escaper::Html,
+            EscapeMode::Xml => Escaper::Xml,
+            EscapeMode::Plain => Escaper::Plain,
+            EscapeMode::JsonString => Escaper::JsonString,
+            EscapeMode::Url => Escaper::UrlComponent,
+        }
+    }
+}
+
+/// Options for compiling a template
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Options {
+    pub escape: EscapeMode,
+    // if set, raw interpolations of anything else are errors
+    pub raw_allowlist: Option<Allowlist>,
+    // only follow own properties in paths,
+    // and reject `__proto__`, `constructor`, and `prototype` keys
+    pub own_properties: bool,
+    pub limits: Limits,
+    // if set, helpers and paths it doesn't allow are errors
+    pub policy: Option<Policy>,
+    // simplify instructions before generating code,
+    // and evaluate paths and helper calls shared within a function once
+    pub optimize: bool,
+    // helpers which may return something else when called again with the same arguments,
+    // so calls to them are never shared
+    pub impure_helpers: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            escape: EscapeMode::Html,
+            raw_allowlist: None,
+            own_properties: false,
+            limits: Limits::default(),
+            policy: None,
+            optimize: true,
+            impure_helpers: Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    /// Read options from a JSON object like `{ "escape": "xml" }`,
+    /// using the default for any which are missing or invalid
+    /// except for `policy`, which is an error if it is invalid
+    ///
+    /// `rawAllowlist` is either an array of entries or the contents of an allowlist file
+    pub fn from_json(source: &str) -> Result<Self, String> {
+        let mut options = Options::default();
+
+        let value = match json::parse(source) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("[benchpress] warning: invalid compile options, {}", e);
+                return Ok(options);
+            }
+        };
+
+        if let Some(escape) = value["escape"].as_str() {
+            match EscapeMode::from_name(escape) {
+                Some(mode) => options.escape = mode,
+                None => warn!("[benchpress] warning: unknown escape mode `{}`", escape),
+            }
+        }
+
+        if let Some(own_properties) = value["ownProperties"].as_bool() {
+            options.own_properties = own_properties;
+        }
+
+        if let Some(optimize) = value["optimize"].as_bool() {
+            options.optimize = optimize;
+        }
+
+        options.impure_helpers = value["impureHelpers"]
+            .members()
+            .filter_map(|name| name.as_str())
+            .map(String::from)
+            .collect();
+
+        if value.has_key("limits") {
+            options.limits = Limits::from_json(&value["limits"]);
+        }
+
+        match &value["policy"] {
+            json::JsonValue::Null => {}
+            policy if policy.is_object() => options.policy = Some(Policy::from_json(policy)?),
+            _ => return Err("`policy` must be an object".to_string()),
+        }
+
+        let raw_allowlist = &value["rawAllowlist"];
+        if let Some(source) = raw_allowlist.as_str() {
+            options.raw_allowlist = Some(Allowlist::parse(source));
+        } else if raw_allowlist.is_array() {
+            options.raw_allowlist = Some(Allowlist::from_entries(
+                raw_allowlist.members().filter_map(|entry| entry.as_str()),
+            ));
+        }
+
+        Ok(options)
+    }
+
+    /// Apply a `{{{ pragma name value }}}` from a template
+    /// Returns an error if it turns off escaping which the raw allowlist or policy relies on
+    #[rustfmt::skip::macros(warn)]
+    fn pragma(&mut self, name: Span, value: Span) -> Result<(), String> {
+        let problem = match *name.fragment() {
+            "escape" => match EscapeMode::from_name(value.fragment()) {
+                Some(mode)
+                    if self.escape.escapes_markup()
+                        && !mode.escapes_markup()
+                        && (self.raw_allowlist.is_some() || self.policy.is_some()) =>
+                {
+                    return Err(value.error(
+                        &format!("pragma `escape {}` turns off escaping", value),
+                        "remove this pragma, since a raw allowlist or policy is set",
+                    ));
+                }
+                Some(mode) => {
+                    self.escape = mode;
+                    return Ok(());
+                }
+                None => "unknown escape mode",
+            },
+            _ => "unknown pragma",
+        };
+
+        let span = if problem == "unknown pragma" {
+            name
+        } else {
+            value
+        };
+        let (line, column, padding) = span.get_line_column_padding();
+        warn!("[benchpress] warning: {} `{}`", problem, span);
+        warn!(
+            "     --> {}:{}:{}",
+            span.extra.filename,
+            span.location_line(),
+            column
+        );
+        warn!("      |");
+        warn!("{:>5} | {}", span.location_line(), line);
+        warn!("      | {}{}", padding, "^".repeat(span.len()));
+        warn!("      = note: This pragma will be ignored\n");
+        Ok(())
+    }
+
+    /// Apply pragmas at the start of a template, and remove them from the tokens
+    /// along with the whitespace around them
+    /// Returns an error if a pragma turns off escaping while a raw allowlist or policy is set
+    #[rustfmt::skip::macros(warn)]
+    pub fn apply_pragmas<'a>(
+        &mut self,
+        tokens: Vec<Token<Span<'a>>>,
+    ) -> Result<Vec<Token<Span<'a>>>, String> {
+        let mut header = true;
+        let mut after_pragma = false;
+        let mut error = None;
+
+        let tokens = tokens
+            .into_iter()
+            .filter_map(|tok| match tok {
+                Token::Pragma { name, value, .. } if header => {
+                    if let Err(e) = self.pragma(name, value) {
+                        error.get_or_insert(e);
+                    }
+                    after_pragma = true;
+                    None
+                }
+                Token::Pragma { span, .. } => {
+                    let (line, column, padding) = span.get_line_column_padding();
+                    warn!("[benchpress] warning: pragma after the start of the template");
+                    warn!(
+                        "     --> {}:{}:{}",
+                        span.extra.filename,
+                        span.location_line(),
+                        column
+                    );
+                    warn!("      |");
+                    warn!("{:>5} | {}", span.location_line(), line);
+                    warn!(
+                        "      | {}{} help: move this to the top of the file",
+                        padding,
+                        "^".repeat(span.len())
+                    );
+                    warn!("      = note: This pragma will be ignored\n");
+                    None
+                }
+                Token::Text(text) if header && text.trim().is_empty() => None,
+                Token::Text(text) if header => {
+                    header = false;
+
+                    // remove the line break after the last pragma
+                    if !after_pragma {
+                        return Some(Token::Text(text));
+                    }
+                    let trimmed = text.trim_start_matches([' ', '\t']);
+                    let trimmed = trimmed
+                        .strip_prefix("\r\n")
+                        .or_else(|| trimmed.strip_prefix('\n'))
+                        .unwrap_or(text.fragment());
+                    Some(Token::Text(text.slice((text.len() - trimmed.len())..)))
+                }
+                tok => {
+                    header = false;
+                    Some(tok)
+                }
+            })
+            .collect();
+
+        match error {
+            Some(e) => Err(e),
+            None => Ok(tokens),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{
+        test::sp,
+        tokens::tokens,
+    };
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn from_json() {
+        assert_eq!(Options::from_json("{}").unwrap(), Options::default());
+        assert_eq!(
+            Options::from_json("{ \"escape\": \"json-string\" }")
+                .unwrap()
+                .escape,
+            EscapeMode::JsonString
+        );
+        assert_eq!(
+            Options::from_json("{ \"escape\": \"rot13\" }")
+                .unwrap()
+                .escape,
+            EscapeMode::Html
+        );
+        assert_eq!(
+            Options::from_json("{ \"rawAllowlist\": [\"html\", \"buildAvatar()\"] }")
+                .unwrap()
+                .raw_allowlist,
+            Some(Allowlist::parse("html\nbuildAvatar()"))
+        );
+        assert!(Options::from_json("{ \"policy\": { \"paths\": [] } }").is_err());
+        assert!(Options::from_json("{ \"policy\": [\"formatDate\"] }").is_err());
+    }
+
+    #[test]
+    fn pragmas() {
+        let source = sp("{{{ pragma escape plain }}}\n{{{ pragma escape xml }}}\nHello {name}\n");
+        let (_, toks) = tokens(source).unwrap();
+
+        let mut options = Options::default();
+        let toks = options.apply_pragmas(toks).unwrap();
+
+        assert_eq!(options.escape, EscapeMode::Xml);
+        assert_eq!(
+            toks.into_iter()
+                .map(|tok| *tok.span().fragment())
+                .collect::<Vec<_>>(),
+            vec!["Hello ", "{name}", "\n"]
+        );
+    }
+
+    #[test]
+    fn pragma_weakens_escaping() {
+        let source = sp("{{{ pragma escape plain }}}\n<p>{name}</p>\n");
+        let (_, toks) = tokens(source).unwrap();
+
+        let mut options = Options::from_json("{ \"rawAllowlist\": [] }").unwrap();
+        let error = options.apply_pragmas(toks.clone()).unwrap_err();
+        assert!(error.starts_with("[benchpress] error: pragma `escape plain` turns off escaping"));
+        assert_eq!(options.escape, EscapeMode::Html);
+
+        let mut options = Options::from_json("{ \"policy\": {} }").unwrap();
+        assert!(options.apply_pragmas(toks.clone()).is_err());
+
+        let mut options =
+            Options::from_json("{ \"rawAllowlist\": [], \"escape\": \"plain\" }").unwrap();
+        assert!(options.apply_pragmas(toks.clone()).is_ok());
+        let mut options = Options::default();
+        assert!(options.apply_pragmas(toks).is_ok());
+        assert_eq!(options.escape, EscapeMode::Plain);
+    }
+}
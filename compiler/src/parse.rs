@@ -18,9 +18,10 @@ pub struct FileInfo<'a> {
 }
 pub type Span<'a> = nom_locate::LocatedSpan<&'a str, FileInfo<'a>>;
 
-trait SpanExt {
+pub(crate) trait SpanExt {
     fn get_line(&self) -> &str;
     fn get_line_column_padding(&self) -> (&str, usize, String);
+    fn error(&self, message: &str, help: &str) -> String;
 }
 impl<'a> SpanExt for Span<'a> {
     fn get_line(&self) -> &str {
@@ -53,6 +54,29 @@ impl<'a> SpanExt for Span<'a> {
 
         (line, column, "\t".repeat(tabs) + &(" ".repeat(spaces)))
     }
+
+    /// A compile error message pointing at this span, in the format of warnings
+    fn error(&self, message: &str, help: &str) -> String {
+        let (line, column, padding) = self.get_line_column_padding();
+
+        format!(
+            "[benchpress] error: {}
+     --> {}:{}:{}
+      |
+{:>5} | {}
+      | {}{} help: {}
+",
+            message,
+            self.extra.filename,
+            self.location_line(),
+            column,
+            self.location_line(),
+            line,
+            padding,
+            "^".repeat(self.len()),
+            help
+        )
+    }
 }
 
 /// A combinator that takes a parser `inner` and produces a parser that also consumes both leading and
@@ -14,10 +14,17 @@ use nom::{
         tag,
         take,
     },
-    character::complete::alphanumeric1,
+    character::complete::{
+        alpha1,
+        alphanumeric1,
+        digit1,
+        one_of,
+    },
     combinator::{
         consumed,
         map,
+        map_opt,
+        not,
         opt,
         recognize,
     },
@@ -26,21 +33,33 @@ use nom::{
         many0_count,
         many1_count,
         separated_list0,
-        separated_list1,
     },
     sequence::{
         delimited,
         pair,
         preceded,
+        terminated,
+        tuple,
+    },
+    error::{
+        Error,
+        ErrorKind,
     },
     IResult,
     Slice,
 };
+use std::cell::Cell;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Expression<S> {
-    // "this \"works\" as you'd expect"
+    // "this \"works\" as you'd expect" or 'so does \'this\''
     StringLiteral(S),
+    // 80, -1, 2.5, 1e3
+    NumberLiteral(S),
+    // true or false
+    BooleanLiteral(S),
+    // null
+    NullLiteral(S),
     // a.b.c.d
     Path {
         span: S,
@@ -51,11 +70,12 @@ pub enum Expression<S> {
         span: S,
         expr: Box<Expression<S>>,
     },
-    // name(arg0, arg1, arg2, ...)
+    // name(arg0, arg1, ..., key0=arg, key1=arg, ...)
     Helper {
         span: S,
         name: S,
         args: Vec<Expression<S>>,
+        kwargs: Vec<(S, Expression<S>)>,
     },
     // function.name, arg0, arg1, arg2, ...
     LegacyHelper {
@@ -63,16 +83,107 @@ pub enum Expression<S> {
         name: S,
         args: Vec<Expression<S>>,
     },
+    // name(arg0, arg1, arg2, ...) where `name` is a macro
+    MacroCall {
+        span: S,
+        name: S,
+        args: Vec<Expression<S>>,
+    },
+    // left ?? right
+    Coalesce {
+        span: S,
+        left: Box<Expression<S>>,
+        right: Box<Expression<S>>,
+    },
+    // condition ? then : otherwise
+    Ternary {
+        span: S,
+        condition: Box<Expression<S>>,
+        then: Box<Expression<S>>,
+        otherwise: Box<Expression<S>>,
+    },
+    // range(start, end) or start..end, only as the subject of `each` or `for`
+    Range {
+        span: S,
+        start: Box<Expression<S>>,
+        end: Box<Expression<S>>,
+    },
+}
+
+impl<S> Expression<S> {
+    /// Apply `f` to each expression directly within this one,
+    /// not including the keys of paths
+    pub fn map_children<F>(self, mut f: F) -> Self
+    where
+        F: FnMut(Expression<S>) -> Expression<S>,
+    {
+        match self {
+            Expression::Negative { span, expr } => Expression::Negative {
+                span,
+                expr: Box::new(f(*expr)),
+            },
+            Expression::Helper {
+                span,
+                name,
+                args,
+                kwargs,
+            } => Expression::Helper {
+                span,
+                name,
+                args: args.into_iter().map(&mut f).collect(),
+                kwargs: kwargs.into_iter().map(|(key, arg)| (key, f(arg))).collect(),
+            },
+            Expression::LegacyHelper { span, name, args } => Expression::LegacyHelper {
+                span,
+                name,
+                args: args.into_iter().map(f).collect(),
+            },
+            Expression::MacroCall { span, name, args } => Expression::MacroCall {
+                span,
+                name,
+                args: args.into_iter().map(f).collect(),
+            },
+            Expression::Coalesce { span, left, right } => Expression::Coalesce {
+                span,
+                left: Box::new(f(*left)),
+                right: Box::new(f(*right)),
+            },
+            Expression::Ternary {
+                span,
+                condition,
+                then,
+                otherwise,
+            } => Expression::Ternary {
+                span,
+                condition: Box::new(f(*condition)),
+                then: Box::new(f(*then)),
+                otherwise: Box::new(f(*otherwise)),
+            },
+            Expression::Range { span, start, end } => Expression::Range {
+                span,
+                start: Box::new(f(*start)),
+                end: Box::new(f(*end)),
+            },
+            expr => expr,
+        }
+    }
 }
 
 impl<'a> Expression<Span<'a>> {
     pub fn span(&self) -> Span<'a> {
         match self {
             Expression::StringLiteral(span)
+            | Expression::NumberLiteral(span)
+            | Expression::BooleanLiteral(span)
+            | Expression::NullLiteral(span)
             | Expression::Path { span, .. }
             | Expression::Negative { span, .. }
             | Expression::Helper { span, .. }
-            | Expression::LegacyHelper { span, .. } => *span,
+            | Expression::LegacyHelper { span, .. }
+            | Expression::MacroCall { span, .. }
+            | Expression::Coalesce { span, .. }
+            | Expression::Ternary { span, .. }
+            | Expression::Range { span, .. } => *span,
         }
     }
 
@@ -82,19 +193,196 @@ impl<'a> Expression<Span<'a>> {
             path: vec![PathPart::Part(span)],
         }
     }
+
+    /// Replace spans with their source text, to compare expressions
+    /// regardless of where they are in a template
+    pub fn span_to_str(self) -> Expression<&'a str> {
+        match self {
+            Expression::StringLiteral(span) => Expression::StringLiteral(*span.fragment()),
+            Expression::NumberLiteral(span) => Expression::NumberLiteral(*span.fragment()),
+            Expression::BooleanLiteral(span) => Expression::BooleanLiteral(*span.fragment()),
+            Expression::NullLiteral(span) => Expression::NullLiteral(*span.fragment()),
+            Expression::Path { span, path } => Expression::Path {
+                span: *span.fragment(),
+                path: path.into_iter().map(|p| p.span_to_str()).collect(),
+            },
+            Expression::Negative { span, expr } => Expression::Negative {
+                span: *span.fragment(),
+                expr: Box::new(expr.span_to_str()),
+            },
+            Expression::Helper {
+                span,
+                name,
+                args,
+                kwargs,
+            } => Expression::Helper {
+                span: *span.fragment(),
+                name: *name.fragment(),
+                args: args.into_iter().map(|a| a.span_to_str()).collect(),
+                kwargs: kwargs
+                    .into_iter()
+                    .map(|(k, a)| (*k.fragment(), a.span_to_str()))
+                    .collect(),
+            },
+            Expression::LegacyHelper { span, name, args } => Expression::LegacyHelper {
+                span: *span.fragment(),
+                name: *name.fragment(),
+                args: args.into_iter().map(|a| a.span_to_str()).collect(),
+            },
+            Expression::MacroCall { span, name, args } => Expression::MacroCall {
+                span: *span.fragment(),
+                name: *name.fragment(),
+                args: args.into_iter().map(|a| a.span_to_str()).collect(),
+            },
+            Expression::Coalesce { span, left, right } => Expression::Coalesce {
+                span: *span.fragment(),
+                left: Box::new(left.span_to_str()),
+                right: Box::new(right.span_to_str()),
+            },
+            Expression::Ternary {
+                span,
+                condition,
+                then,
+                otherwise,
+            } => Expression::Ternary {
+                span: *span.fragment(),
+                condition: Box::new(condition.span_to_str()),
+                then: Box::new(then.span_to_str()),
+                otherwise: Box::new(otherwise.span_to_str()),
+            },
+            Expression::Range { span, start, end } => Expression::Range {
+                span: *span.fragment(),
+                start: Box::new(start.span_to_str()),
+                end: Box::new(end.span_to_str()),
+            },
+        }
+    }
+}
+
+thread_local! {
+    // how deeply the expression being parsed is nested, and the limit on it
+    static DEPTH: Cell<(usize, Option<usize>)> = const { Cell::new((0, None)) };
+}
+
+/// Parse expressions within `f` nested at most `max` deep, past which parsing fails
+/// with `ErrorKind::TooLarge` at the start of the expression over the limit
+pub fn with_depth_limit<T>(max: Option<usize>, f: impl FnOnce() -> T) -> T {
+    DEPTH.with(|depth| depth.set((0, max)));
+    let output = f();
+    DEPTH.with(|depth| depth.set((0, None)));
+    output
+}
+
+/// Fail if `levels` deeper than the current expression is over the limit
+fn check_depth(input: Span, levels: usize) -> Result<(), nom::Err<Error<Span>>> {
+    DEPTH.with(|depth| match depth.get() {
+        (current, Some(max)) if current + levels > max => {
+            let end = input.chars().next().map_or(0, char::len_utf8);
+            Err(nom::Err::Failure(Error::new(
+                input.slice(..end),
+                ErrorKind::TooLarge,
+            )))
+        }
+        _ => Ok(()),
+    })
+}
+
+/// Apply `parser` to an expression nested one deeper
+fn nested<'a, O>(
+    input: Span<'a>,
+    parser: impl FnOnce(Span<'a>) -> IResult<Span<'a>, O>,
+) -> IResult<Span<'a>, O> {
+    check_depth(input, 1)?;
+    DEPTH.with(|depth| {
+        let (current, max) = depth.get();
+        depth.set((current + 1, max));
+    });
+    let output = parser(input);
+    DEPTH.with(|depth| {
+        let (current, max) = depth.get();
+        depth.set((current - 1, max));
+    });
+    output
 }
 
 fn string_literal(input: Span) -> IResult<Span, Expression<Span>> {
     map(
-        recognize(delimited(
-            tag("\""),
-            many0_count(alt((preceded(tag("\\"), take(1_usize)), is_not("\\\"")))),
-            tag("\""),
-        )),
+        recognize(alt((
+            delimited(
+                tag("\""),
+                many0_count(alt((preceded(tag("\\"), take(1_usize)), is_not("\\\"")))),
+                tag("\""),
+            ),
+            delimited(
+                tag("'"),
+                many0_count(alt((preceded(tag("\\"), take(1_usize)), is_not("\\'")))),
+                tag("'"),
+            ),
+        ))),
         Expression::StringLiteral,
     )(input)
 }
 
+/// Succeeds if the input does not continue with a path character,
+/// so literals like `true` don't swallow the start of `trueValue` or `1.x`
+fn literal_end(input: Span) -> IResult<Span, ()> {
+    not(alt((alphanumeric1, is_a("_-:@.["))))(input)
+}
+
+fn number_literal(input: Span) -> IResult<Span, Expression<Span>> {
+    map(
+        terminated(
+            recognize(tuple((
+                opt(tag("-")),
+                digit1,
+                opt(pair(tag("."), digit1)),
+                opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+            ))),
+            literal_end,
+        ),
+        Expression::NumberLiteral,
+    )(input)
+}
+
+fn keyword_literal(input: Span) -> IResult<Span, Expression<Span>> {
+    alt((
+        map(
+            terminated(alt((tag("true"), tag("false"))), literal_end),
+            Expression::BooleanLiteral,
+        ),
+        map(
+            terminated(tag("null"), literal_end),
+            Expression::NullLiteral,
+        ),
+    ))(input)
+}
+
+/// Unescape contents of string literal
+pub fn unescape(input: &str) -> String {
+    // remove first and last quote
+    let input = &input[1..(input.len() - 1)];
+    let mut output = String::new();
+
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        output.push(if c == '\\' {
+            match chars.next() {
+                Some(c) => match c {
+                    'r' => '\r',
+                    'n' => '\n',
+                    't' => '\t',
+                    _ => c,
+                },
+                _ => c,
+            }
+        } else {
+            c
+        });
+    }
+
+    output
+}
+
 fn identifier(input: Span) -> IResult<Span, Span> {
     let (rest, res): (Span, Span) =
         recognize(many1_count(alt((alphanumeric1, is_a("_-:@")))))(input)?;
@@ -107,13 +395,38 @@ fn identifier(input: Span) -> IResult<Span, Span> {
     }
 }
 
+/// `["some key"]`, `[0]`, or `[@key]`
+fn computed(input: Span) -> IResult<Span, PathPart<Span>> {
+    map(
+        consumed(delimited(tag("["), ws(expression), tag("]"))),
+        |(span, expr)| PathPart::Computed(span, Box::new(expr)),
+    )(input)
+}
+
+/// `.prop` or `[key]`
+fn path_segment(input: Span) -> IResult<Span, PathPart<Span>> {
+    alt((
+        preceded(tag("."), map(identifier, PathPart::Part)),
+        computed,
+    ))(input)
+}
+
 fn path(input: Span) -> IResult<Span, Expression<Span>> {
     alt((
+        // `@root` or `@root.some.path`
+        map(
+            consumed(pair(map(tag("@root"), PathPart::Part), many0(path_segment))),
+            |(span, (root, mut rest))| {
+                let mut path = vec![root];
+                path.append(&mut rest);
+                Expression::Path { span, path }
+            },
+        ),
         map(
             alt((
-                tag("@root"),
                 tag("@key"),
                 tag("@index"),
+                tag("@length"),
                 tag("@value"),
                 tag("@first"),
                 tag("@last"),
@@ -121,13 +434,15 @@ fn path(input: Span) -> IResult<Span, Expression<Span>> {
             Expression::path_from_span,
         ),
         map(
-            consumed(pair(
+            consumed(tuple((
                 many0(map(alt((tag("./"), tag("../"))), PathPart::Part)),
-                separated_list1(tag("."), map(identifier, PathPart::Part)),
-            )),
-            |(span, (mut first, mut second))| {
-                first.append(&mut second);
-                Expression::Path { span, path: first }
+                map(identifier, PathPart::Part),
+                many0(path_segment),
+            ))),
+            |(span, (mut path, first, mut rest))| {
+                path.push(first);
+                path.append(&mut rest);
+                Expression::Path { span, path }
             },
         ),
     ))(input)
@@ -135,7 +450,7 @@ fn path(input: Span) -> IResult<Span, Expression<Span>> {
 
 fn negative(input: Span) -> IResult<Span, Expression<Span>> {
     map(
-        consumed(preceded(ws(tag("!")), expression)),
+        consumed(preceded(ws(tag("!")), |input| nested(input, operand))),
         |(span, expr)| Expression::Negative {
             span,
             expr: Box::new(expr),
@@ -143,17 +458,54 @@ fn negative(input: Span) -> IResult<Span, Expression<Span>> {
     )(input)
 }
 
-fn helper(input: Span) -> IResult<Span, Expression<Span>> {
-    map(
-        consumed(pair(
-            identifier,
-            delimited(
-                tag("("),
-                separated_list0(tag(","), ws(expression)),
-                tag(")"),
-            ),
+/// `arg` or `key=arg`
+fn argument(input: Span) -> IResult<Span, (Option<Span>, Expression<Span>)> {
+    pair(
+        opt(terminated(
+            recognize(pair(
+                alt((alpha1, tag("_"))),
+                many0_count(alt((alphanumeric1, tag("_")))),
+            )),
+            ws(terminated(tag("="), not(tag("=")))),
         )),
-        |(span, (name, args))| Expression::Helper { span, name, args },
+        expression,
+    )(input)
+}
+
+type Arguments<'a> = (
+    Vec<Expression<Span<'a>>>,
+    Vec<(Span<'a>, Expression<Span<'a>>)>,
+);
+
+/// `(arg0, arg1, ..., key0=arg, key1=arg, ...)`
+/// keyword arguments must come after all positional arguments
+fn arguments(input: Span) -> IResult<Span, Arguments> {
+    map_opt(
+        delimited(tag("("), separated_list0(tag(","), ws(argument)), tag(")")),
+        |list| {
+            let mut args = vec![];
+            let mut kwargs = vec![];
+            for (key, arg) in list {
+                match key {
+                    Some(key) => kwargs.push((key, arg)),
+                    None if kwargs.is_empty() => args.push(arg),
+                    None => return None,
+                }
+            }
+            Some((args, kwargs))
+        },
+    )(input)
+}
+
+pub fn helper(input: Span) -> IResult<Span, Expression<Span>> {
+    map(
+        consumed(pair(identifier, arguments)),
+        |(span, (name, (args, kwargs)))| Expression::Helper {
+            span,
+            name,
+            args,
+            kwargs,
+        },
     )(input)
 }
 
@@ -180,9 +532,142 @@ fn legacy_helper(input: Span) -> IResult<Span, Expression<Span>> {
     )(input)
 }
 
-pub fn expression(input: Span) -> IResult<Span, Expression<Span>> {
+fn operand(input: Span) -> IResult<Span, Expression<Span>> {
     // This order is important
-    alt((negative, legacy_helper, helper, string_literal, path))(input)
+    alt((
+        negative,
+        legacy_helper,
+        helper,
+        string_literal,
+        number_literal,
+        keyword_literal,
+        path,
+    ))(input)
+}
+
+/// The part of `input` consumed to get to `rest`
+fn consumed_to<'a>(input: Span<'a>, rest: Span<'a>) -> Span<'a> {
+    input.slice(..(rest.location_offset() - input.location_offset()))
+}
+
+/// `value | filter(arg) | other`, desugared to `other(filter(value, arg))`
+fn pipe(input: Span) -> IResult<Span, Expression<Span>> {
+    let (mut rest, mut expr) = operand(input)?;
+
+    // `||` is not a pipe, so things like `{ a || b }` in scripts stay text
+    let mut filter = preceded(
+        ws(terminated(tag("|"), not(tag("|")))),
+        pair(identifier, opt(arguments)),
+    );
+    let mut filters = 0;
+    loop {
+        let (next, (name, args)) = match filter(rest) {
+            Ok(filtered) => filtered,
+            Err(nom::Err::Error(_)) => break,
+            Err(e) => return Err(e),
+        };
+        // each filter is called with the expression before it
+        check_depth(rest, filters + 1)?;
+        filters += 1;
+
+        let (mut args, kwargs) = args.unwrap_or_default();
+        args.insert(0, expr);
+
+        expr = Expression::Helper {
+            span: consumed_to(input, next),
+            name,
+            args,
+            kwargs,
+        };
+        rest = next;
+    }
+
+    Ok((rest, expr))
+}
+
+/// `a ?? b ?? c`
+fn coalesce(input: Span) -> IResult<Span, Expression<Span>> {
+    let (mut rest, mut expr) = pipe(input)?;
+
+    let mut right = preceded(ws(tag("??")), pipe);
+    let mut coalesced = 0;
+    loop {
+        let (next, right) = match right(rest) {
+            Ok(right) => right,
+            Err(nom::Err::Error(_)) => break,
+            Err(e) => return Err(e),
+        };
+        // the expression before is the left side of this one
+        check_depth(rest, coalesced + 1)?;
+        coalesced += 1;
+
+        expr = Expression::Coalesce {
+            span: consumed_to(input, next),
+            left: Box::new(expr),
+            right: Box::new(right),
+        };
+        rest = next;
+    }
+
+    Ok((rest, expr))
+}
+
+/// `condition ? then : otherwise`
+fn ternary(input: Span) -> IResult<Span, Expression<Span>> {
+    let (rest, condition) = coalesce(input)?;
+
+    match pair(
+        preceded(ws(terminated(tag("?"), not(tag("?")))), expression),
+        preceded(ws(tag(":")), expression),
+    )(rest)
+    {
+        Ok((rest, (then, otherwise))) => Ok((
+            rest,
+            Expression::Ternary {
+                span: consumed_to(input, rest),
+                condition: Box::new(condition),
+                then: Box::new(then),
+                otherwise: Box::new(otherwise),
+            },
+        )),
+        Err(nom::Err::Error(_)) => Ok((rest, condition)),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn expression(input: Span) -> IResult<Span, Expression<Span>> {
+    nested(input, ternary)
+}
+
+/// `1`, `n`, or `count(items)` in `1..n`, where a path or number can't include the dots
+fn range_bound(input: Span) -> IResult<Span, Expression<Span>> {
+    alt((
+        helper,
+        map(
+            terminated(recognize(pair(opt(tag("-")), digit1)), not(alphanumeric1)),
+            Expression::NumberLiteral,
+        ),
+        path,
+    ))(input)
+}
+
+/// `range(start, end)` or `start..end`, both including `end`
+pub fn range(input: Span) -> IResult<Span, Expression<Span>> {
+    map(
+        consumed(alt((
+            delimited(
+                tag("range("),
+                pair(ws(expression), preceded(tag(","), ws(expression))),
+                tag(")"),
+            ),
+            pair(range_bound, preceded(tag(".."), range_bound)),
+        ))),
+        |(span, (start, end))| Expression::Range {
+            span,
+            start: Box::new(start),
+            end: Box::new(end),
+        },
+    )(input)
 }
 
 #[cfg(test)]
@@ -211,32 +696,11 @@ mod test {
             string_literal(src),
             Ok((src.slice(7..), Expression::StringLiteral(src.slice(..7))))
         );
-    }
-
-    impl<'a> Expression<Span<'a>> {
-        pub fn span_to_str(self) -> Expression<&'a str> {
-            match self {
-                Expression::StringLiteral(span) => Expression::StringLiteral(*span.fragment()),
-                Expression::Path { span, path } => Expression::Path {
-                    span: *span.fragment(),
-                    path: path.into_iter().map(|p| p.span_to_str()).collect(),
-                },
-                Expression::Negative { span, expr } => Expression::Negative {
-                    span: *span.fragment(),
-                    expr: Box::new(expr.span_to_str()),
-                },
-                Expression::Helper { span, name, args } => Expression::Helper {
-                    span: *span.fragment(),
-                    name: *name.fragment(),
-                    args: args.into_iter().map(|a| a.span_to_str()).collect(),
-                },
-                Expression::LegacyHelper { span, name, args } => Expression::LegacyHelper {
-                    span: *span.fragment(),
-                    name: *name.fragment(),
-                    args: args.into_iter().map(|a| a.span_to_str()).collect(),
-                },
-            }
-        }
+        let src = sp(r#"'it\'s "quoted"' "#);
+        assert_eq!(
+            string_literal(src),
+            Ok((src.slice(16..), Expression::StringLiteral(src.slice(..16))))
+        );
     }
 
     fn span_to_str<'a>(
@@ -281,6 +745,81 @@ mod test {
             ))
         );
 
+        assert_eq_unspan!(
+            path(sp("a[\"some key\"][0].c[@key]")),
+            Ok((
+                "",
+                Expression::Path {
+                    span: "a[\"some key\"][0].c[@key]",
+                    path: vec![
+                        PathPart::Part("a"),
+                        PathPart::Computed(
+                            "[\"some key\"]",
+                            Box::new(Expression::StringLiteral("\"some key\""))
+                        ),
+                        PathPart::Computed("[0]", Box::new(Expression::NumberLiteral("0"))),
+                        PathPart::Part("c"),
+                        PathPart::Computed(
+                            "[@key]",
+                            Box::new(Expression::Path {
+                                span: "@key",
+                                path: vec![PathPart::Part("@key")]
+                            })
+                        ),
+                    ]
+                }
+            ))
+        );
+
+        assert_eq_unspan!(
+            path(sp("translations[ lang.code ]")),
+            Ok((
+                "",
+                Expression::Path {
+                    span: "translations[ lang.code ]",
+                    path: vec![
+                        PathPart::Part("translations"),
+                        PathPart::Computed(
+                            "[ lang.code ]",
+                            Box::new(Expression::Path {
+                                span: "lang.code",
+                                path: vec![PathPart::Part("lang"), PathPart::Part("code")]
+                            })
+                        ),
+                    ]
+                }
+            ))
+        );
+
+        assert_eq_unspan!(
+            path(sp("@root.config[\"relative_path\"]}")),
+            Ok((
+                "}",
+                Expression::Path {
+                    span: "@root.config[\"relative_path\"]",
+                    path: vec![
+                        PathPart::Part("@root"),
+                        PathPart::Part("config"),
+                        PathPart::Computed(
+                            "[\"relative_path\"]",
+                            Box::new(Expression::StringLiteral("\"relative_path\""))
+                        ),
+                    ]
+                }
+            ))
+        );
+
+        assert_eq_unspan!(
+            path(sp("@root, b")),
+            Ok((
+                ", b",
+                Expression::Path {
+                    span: "@root",
+                    path: vec![PathPart::Part("@root")]
+                }
+            ))
+        );
+
         assert_eq_unspan!(
             path(sp("./../abc.def")),
             Ok((
@@ -298,6 +837,38 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_number_literal() {
+        assert_eq_unspan!(
+            number_literal(sp("80)")),
+            Ok((")", Expression::NumberLiteral("80")))
+        );
+        assert_eq_unspan!(
+            number_literal(sp("-2.5e+3, a")),
+            Ok((", a", Expression::NumberLiteral("-2.5e+3")))
+        );
+        assert!(number_literal(sp("123abc")).is_err());
+        assert!(number_literal(sp("1.x")).is_err());
+    }
+
+    #[test]
+    fn test_keyword_literal() {
+        assert_eq_unspan!(
+            keyword_literal(sp("true)")),
+            Ok((")", Expression::BooleanLiteral("true")))
+        );
+        assert_eq_unspan!(
+            keyword_literal(sp("false ")),
+            Ok((" ", Expression::BooleanLiteral("false")))
+        );
+        assert_eq_unspan!(
+            keyword_literal(sp("null")),
+            Ok(("", Expression::NullLiteral("null")))
+        );
+        assert!(keyword_literal(sp("trueValue")).is_err());
+        assert!(keyword_literal(sp("null.prop")).is_err());
+    }
+
     #[test]
     fn test_negative() {
         assert_eq_unspan!(
@@ -337,10 +908,30 @@ mod test {
                             span: "k",
                             path: vec![PathPart::Part("k")]
                         }
-                    ]
+                    ],
+                    kwargs: vec![],
                 }
             ))
-        )
+        );
+        assert_eq_unspan!(
+            helper(sp("buildAvatar(user, size=\"24\", rounded = true)")),
+            Ok((
+                "",
+                Expression::Helper {
+                    span: "buildAvatar(user, size=\"24\", rounded = true)",
+                    name: "buildAvatar",
+                    args: vec![Expression::Path {
+                        span: "user",
+                        path: vec![PathPart::Part("user")]
+                    }],
+                    kwargs: vec![
+                        ("size", Expression::StringLiteral("\"24\"")),
+                        ("rounded", Expression::BooleanLiteral("true")),
+                    ],
+                }
+            ))
+        );
+        assert!(helper(sp("foo(a=1, b)")).is_err());
     }
 
     #[test]
@@ -409,7 +1000,8 @@ mod test {
                             name: "bar",
                             args: vec![Expression::StringLiteral("\"boom\"")]
                         }
-                    ]
+                    ],
+                    kwargs: vec![],
                 }
             ))
         );
@@ -432,10 +1024,171 @@ mod test {
                                 span: "a.b",
                                 path: vec![PathPart::Part("a"), PathPart::Part("b")]
                             },
-                        ]
+                        ],
+                        kwargs: vec![],
                     })
                 }
             ))
         );
+
+        assert_eq_unspan!(
+            expression(sp("truncate(title, 80, 'end', true, null)")),
+            Ok((
+                "",
+                Expression::Helper {
+                    span: "truncate(title, 80, 'end', true, null)",
+                    name: "truncate",
+                    args: vec![
+                        Expression::Path {
+                            span: "title",
+                            path: vec![PathPart::Part("title")]
+                        },
+                        Expression::NumberLiteral("80"),
+                        Expression::StringLiteral("'end'"),
+                        Expression::BooleanLiteral("true"),
+                        Expression::NullLiteral("null"),
+                    ],
+                    kwargs: vec![],
+                }
+            ))
+        );
+
+        assert_eq_unspan!(
+            expression(sp("trueish")),
+            Ok((
+                "",
+                Expression::Path {
+                    span: "trueish",
+                    path: vec![PathPart::Part("trueish")]
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_pipe() {
+        assert_eq_unspan!(
+            expression(sp("post.content | stripTags | truncate(100) }")),
+            Ok((
+                " }",
+                Expression::Helper {
+                    span: "post.content | stripTags | truncate(100)",
+                    name: "truncate",
+                    args: vec![
+                        Expression::Helper {
+                            span: "post.content | stripTags",
+                            name: "stripTags",
+                            args: vec![Expression::Path {
+                                span: "post.content",
+                                path: vec![PathPart::Part("post"), PathPart::Part("content")]
+                            }],
+                            kwargs: vec![],
+                        },
+                        Expression::NumberLiteral("100"),
+                    ],
+                    kwargs: vec![],
+                }
+            ))
+        );
+
+        assert_eq_unspan!(
+            expression(sp("a || b")),
+            Ok((
+                " || b",
+                Expression::Path {
+                    span: "a",
+                    path: vec![PathPart::Part("a")]
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_ternary() {
+        assert_eq_unspan!(
+            expression(sp("isOwner ? 'owner' : a ?? b ?? \"member\" }")),
+            Ok((
+                " }",
+                Expression::Ternary {
+                    span: "isOwner ? 'owner' : a ?? b ?? \"member\"",
+                    condition: Box::new(Expression::Path {
+                        span: "isOwner",
+                        path: vec![PathPart::Part("isOwner")]
+                    }),
+                    then: Box::new(Expression::StringLiteral("'owner'")),
+                    otherwise: Box::new(Expression::Coalesce {
+                        span: "a ?? b ?? \"member\"",
+                        left: Box::new(Expression::Coalesce {
+                            span: "a ?? b",
+                            left: Box::new(Expression::Path {
+                                span: "a",
+                                path: vec![PathPart::Part("a")]
+                            }),
+                            right: Box::new(Expression::Path {
+                                span: "b",
+                                path: vec![PathPart::Part("b")]
+                            }),
+                        }),
+                        right: Box::new(Expression::StringLiteral("\"member\"")),
+                    }),
+                }
+            ))
+        );
+
+        assert_eq_unspan!(
+            expression(sp("a ? b }")),
+            Ok((
+                " ? b }",
+                Expression::Path {
+                    span: "a",
+                    path: vec![PathPart::Part("a")]
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        assert_eq_unspan!(
+            range(sp("range(1, pagination.pageCount) }}}")),
+            Ok((
+                " }}}",
+                Expression::Range {
+                    span: "range(1, pagination.pageCount)",
+                    start: Box::new(Expression::NumberLiteral("1")),
+                    end: Box::new(Expression::Path {
+                        span: "pagination.pageCount",
+                        path: vec![PathPart::Part("pagination"), PathPart::Part("pageCount")]
+                    }),
+                }
+            ))
+        );
+
+        assert_eq_unspan!(
+            range(sp("1..rating }}}")),
+            Ok((
+                " }}}",
+                Expression::Range {
+                    span: "1..rating",
+                    start: Box::new(Expression::NumberLiteral("1")),
+                    end: Box::new(Expression::Path {
+                        span: "rating",
+                        path: vec![PathPart::Part("rating")]
+                    }),
+                }
+            ))
+        );
+
+        assert_eq_unspan!(
+            range(sp("-2..2")),
+            Ok((
+                "",
+                Expression::Range {
+                    span: "-2..2",
+                    start: Box::new(Expression::NumberLiteral("-2")),
+                    end: Box::new(Expression::NumberLiteral("2")),
+                }
+            ))
+        );
     }
 }
@@ -1,15 +1,29 @@
-use crate::parse::Span;
+use crate::parse::{
+    expression::{
+        unescape,
+        Expression,
+    },
+    Span,
+};
+use std::borrow::Cow;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum PathPart<S> {
+    // `.prop`
     Part(S),
     PartDepth(S, u32),
+    // `["some key"]`, `[0]`, `[@key]`
+    Computed(S, Box<Expression<S>>),
+    ComputedDepth(S, Box<Expression<S>>, u32),
 }
 
 impl<'a> PathPart<Span<'a>> {
     pub fn span(&self) -> Span<'a> {
         match self {
-            PathPart::Part(span) | PathPart::PartDepth(span, _) => *span,
+            PathPart::Part(span)
+            | PathPart::PartDepth(span, _)
+            | PathPart::Computed(span, _)
+            | PathPart::ComputedDepth(span, _, _) => *span,
         }
     }
 
@@ -18,8 +32,48 @@ impl<'a> PathPart<Span<'a>> {
     }
 
     pub fn with_depth(&mut self, depth: u32) {
-        *self = match *self {
-            PathPart::Part(s) | PathPart::PartDepth(s, _) => PathPart::PartDepth(s, depth),
+        *self = match self {
+            PathPart::Part(s) | PathPart::PartDepth(s, _) => PathPart::PartDepth(*s, depth),
+            PathPart::Computed(s, expr) | PathPart::ComputedDepth(s, expr, _) => {
+                PathPart::ComputedDepth(*s, expr.clone(), depth)
+            }
+        }
+    }
+
+    /// The property name this part accesses, if it is known at compile time
+    /// `.abc`, `["abc"]`, and `['abc']` all have the key `abc`
+    pub fn key(&self) -> Option<Cow<'a, str>> {
+        match self {
+            PathPart::Part(s) | PathPart::PartDepth(s, _) => Some(Cow::Borrowed(*s.fragment())),
+            PathPart::Computed(_, expr) | PathPart::ComputedDepth(_, expr, _) => match **expr {
+                Expression::StringLiteral(s) => Some(Cow::Owned(unescape(s.fragment()))),
+                Expression::NumberLiteral(s) => Some(Cow::Borrowed(*s.fragment())),
+                _ => None,
+            },
+        }
+    }
+
+    /// Whether two parts refer to the same property
+    /// computed parts with dynamic keys are compared by source text
+    fn same_as(&self, other: &Self) -> bool {
+        match (self.key(), other.key()) {
+            (Some(a), Some(b)) => a == b,
+            (None, None) => self.inner() == other.inner(),
+            _ => false,
+        }
+    }
+
+    /// Replace spans with their source text
+    pub fn span_to_str(self) -> PathPart<&'a str> {
+        match self {
+            PathPart::Part(span) => PathPart::Part(*span.fragment()),
+            PathPart::PartDepth(span, depth) => PathPart::PartDepth(*span.fragment(), depth),
+            PathPart::Computed(span, expr) => {
+                PathPart::Computed(*span.fragment(), Box::new(expr.span_to_str()))
+            }
+            PathPart::ComputedDepth(span, expr, depth) => {
+                PathPart::ComputedDepth(*span.fragment(), Box::new(expr.span_to_str()), depth)
+            }
         }
     }
 }
@@ -27,12 +81,44 @@ impl<'a> PathPart<Span<'a>> {
 pub type PathBuf<S> = Vec<PathPart<S>>;
 pub type Path<'b, S> = &'b [PathPart<S>];
 
+/// Resolve `../@index`, `../../@key`, etc to the keyword of an enclosing loop
+/// `depth` is the number of loops around the path
+/// Returns `None` if `rel` is not a relative keyword or there is no such loop
+pub fn resolve_keyword<'a, 'b>(depth: u32, rel: Path<'b, Span<'a>>) -> Option<PathBuf<Span<'a>>> {
+    let prefix = rel.iter().take_while(|p| p.inner().ends_with("./")).count();
+    let keyword = rel.get(prefix)?;
+
+    if prefix == 0
+        || !matches!(
+            keyword.inner(),
+            "@key" | "@index" | "@length" | "@value" | "@first" | "@last"
+        )
+        // only `@value` can have properties
+        || (prefix + 1 < rel.len() && keyword.inner() != "@value")
+    {
+        return None;
+    }
+
+    let up = rel[..prefix].iter().filter(|p| p.inner() == "../").count() as u32;
+    // `./@index` refers to the innermost loop, at `depth - 1`
+    let loop_depth = depth.checked_sub(up + 1)?;
+
+    let mut out = vec![PathPart::PartDepth(keyword.span(), loop_depth)];
+    out.extend_from_slice(&rel[(prefix + 1)..]);
+    Some(out)
+}
+
 pub fn resolve<'a, 'b>(base: Path<'b, Span<'a>>, rel: PathBuf<Span<'a>>) -> PathBuf<Span<'a>> {
     // ignore special paths
     if rel.len() == 1 && rel[0].inner().starts_with('@') {
         return rel.to_vec();
     }
 
+    // `@root.a.b` is always absolute
+    if rel[0].inner() == "@root" {
+        return rel;
+    }
+
     // handle explicitly relative paths
     if rel[0].inner().ends_with("./") {
         // discard first one
@@ -76,10 +162,7 @@ pub fn resolve<'a, 'b>(base: Path<'b, Span<'a>>, rel: PathBuf<Span<'a>>) -> Path
             // check every element from (j) to (j + l) for equality
             // if not equal, break right away
             for i in 0..l {
-                let b_part = base[j + i].inner();
-                let r_part = rel[i].inner();
-
-                if b_part == r_part {
+                if base[j + i].same_as(&rel[i]) {
                     found = true;
 
                     if i == l - 1 {
@@ -116,13 +199,125 @@ pub fn resolve<'a, 'b>(base: Path<'b, Span<'a>>, rel: PathBuf<Span<'a>>) -> Path
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::parse::test::{
+        assert_eq,
+        sp,
+    };
 
-    impl<'a> PathPart<Span<'a>> {
-        pub fn span_to_str(self) -> PathPart<&'a str> {
-            match self {
-                PathPart::Part(span) => PathPart::Part(*span.fragment()),
-                PathPart::PartDepth(span, depth) => PathPart::PartDepth(*span.fragment(), depth),
-            }
+    #[test]
+    fn test_resolve_keyword() {
+        fn span_to_str<'a>(path: Option<PathBuf<Span<'a>>>) -> Option<PathBuf<&'a str>> {
+            path.map(|path| path.into_iter().map(|p| p.span_to_str()).collect())
+        }
+
+        assert_eq!(
+            span_to_str(resolve_keyword(
+                2,
+                &[PathPart::Part(sp("../")), PathPart::Part(sp("@index"))]
+            )),
+            Some(vec![PathPart::PartDepth("@index", 0)])
+        );
+
+        assert_eq!(
+            span_to_str(resolve_keyword(
+                3,
+                &[
+                    PathPart::Part(sp("../")),
+                    PathPart::Part(sp("@value")),
+                    PathPart::Part(sp("name"))
+                ]
+            )),
+            Some(vec![
+                PathPart::PartDepth("@value", 1),
+                PathPart::Part("name")
+            ])
+        );
+
+        // not nested deep enough
+        assert_eq!(
+            span_to_str(resolve_keyword(
+                1,
+                &[PathPart::Part(sp("../")), PathPart::Part(sp("@key"))]
+            )),
+            None
+        );
+
+        // not a keyword
+        assert_eq!(
+            span_to_str(resolve_keyword(
+                2,
+                &[PathPart::Part(sp("../")), PathPart::Part(sp("name"))]
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn test_resolve_root() {
+        let base = vec![PathPart::PartDepth(sp("config"), 0)];
+
+        assert_eq!(
+            resolve(
+                &base,
+                vec![
+                    PathPart::Part(sp("@root")),
+                    PathPart::Part(sp("config")),
+                    PathPart::Part(sp("relative_path")),
+                ]
+            )
+            .into_iter()
+            .map(|p| p.span_to_str())
+            .collect::<Vec<_>>(),
+            vec![
+                PathPart::Part("@root"),
+                PathPart::Part("config"),
+                PathPart::Part("relative_path"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_computed() {
+        fn span_to_str<'a>(path: PathBuf<Span<'a>>) -> PathBuf<&'a str> {
+            path.into_iter().map(|p| p.span_to_str()).collect()
         }
+
+        let base = vec![PathPart::Part(sp("a")), PathPart::PartDepth(sp("b"), 0)];
+
+        // `a["b"].c` matches the base `a.b`
+        assert_eq!(
+            span_to_str(resolve(
+                &base,
+                vec![
+                    PathPart::Part(sp("a")),
+                    PathPart::Computed(
+                        sp("[\"b\"]"),
+                        Box::new(Expression::StringLiteral(sp("\"b\""))),
+                    ),
+                    PathPart::Part(sp("c")),
+                ]
+            )),
+            vec![
+                PathPart::Part("a"),
+                PathPart::PartDepth("b", 0),
+                PathPart::Part("c"),
+            ]
+        );
+
+        // `b[0]` matches `b`, but the index is kept
+        assert_eq!(
+            span_to_str(resolve(
+                &base,
+                vec![
+                    PathPart::Part(sp("b")),
+                    PathPart::Computed(sp("[0]"), Box::new(Expression::NumberLiteral(sp("0")))),
+                ]
+            )),
+            vec![
+                PathPart::Part("a"),
+                PathPart::PartDepth("b", 0),
+                PathPart::Computed("[0]", Box::new(Expression::NumberLiteral("0"))),
+            ]
+        );
     }
 }
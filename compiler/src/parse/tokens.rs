@@ -3,6 +3,8 @@ use crate::{
     parse::{
         expression::{
             expression,
+            helper,
+            range,
             Expression,
         },
         path::PathPart,
@@ -17,45 +19,197 @@ use nom::{
         tag,
         take_until,
     },
+    character::complete::{
+        alpha1,
+        alphanumeric1,
+        multispace0,
+        multispace1,
+    },
     combinator::{
         consumed,
         map,
+        map_opt,
+        opt,
         recognize,
     },
     error::ParseError,
+    multi::{
+        fold_many0,
+        many0_count,
+        separated_list0,
+    },
     sequence::{
         delimited,
         pair,
+        preceded,
+        terminated,
     },
     IResult,
     Slice,
 };
 
+/// Clauses after the subject of `{{{ each arr where cond limit 5 offset 1 reverse sep ", " }}}`
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct EachClauses<S> {
+    // `where cond`
+    pub filter: Option<Expression<S>>,
+    // `limit 5`
+    pub limit: Option<Expression<S>>,
+    // `offset 1`
+    pub offset: Option<Expression<S>>,
+    // `reverse`
+    pub reverse: bool,
+    // `sep ", "`
+    pub sep: Option<Expression<S>>,
+}
+
+impl<S> Default for EachClauses<S> {
+    fn default() -> Self {
+        EachClauses {
+            filter: None,
+            limit: None,
+            offset: None,
+            reverse: false,
+            sep: None,
+        }
+    }
+}
+
+impl<'a> EachClauses<Span<'a>> {
+    /// Replace spans with their source text
+    pub fn span_to_str(self) -> EachClauses<&'a str> {
+        EachClauses {
+            filter: self.filter.map(|e| e.span_to_str()),
+            limit: self.limit.map(|e| e.span_to_str()),
+            offset: self.offset.map(|e| e.span_to_str()),
+            reverse: self.reverse,
+            sep: self.sep.map(|e| e.span_to_str()),
+        }
+    }
+}
+
+impl<S> EachClauses<S> {
+    pub fn is_empty(&self) -> bool {
+        self.filter.is_none()
+            && self.limit.is_none()
+            && self.offset.is_none()
+            && !self.reverse
+            && self.sep.is_none()
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum Token<S> {
     // Template text passed through
     Text(S),
     // `{obj.prop}`
-    InterpEscaped { span: S, expr: Expression<S> },
+    InterpEscaped {
+        span: S,
+        expr: Expression<S>,
+    },
     // `{{obj.prop}}`
-    InterpRaw { span: S, expr: Expression<S> },
+    InterpRaw {
+        span: S,
+        expr: Expression<S>,
+    },
     // `{{{ if condition }}}`
-    If { span: S, subject: Expression<S> },
-    // `{{{ each arr }}}`
-    Each { span: S, subject: Expression<S> },
+    If {
+        span: S,
+        subject: Expression<S>,
+    },
+    // `{{{ each arr }}}` or `{{{ each value in arr }}}` or `{{{ each key, value in arr }}}`
+    // followed by optional clauses, or `{{{ each i in range(1, n) }}}` or `{{{ for i in 1..n }}}`
+    Each {
+        span: S,
+        subject: Expression<S>,
+        key: Option<S>,
+        value: Option<S>,
+        clauses: EachClauses<S>,
+    },
+    // `{{{ with obj }}}`
+    With {
+        span: S,
+        subject: Expression<S>,
+    },
+    // `{{{ set name = expr }}}`
+    Set {
+        span: S,
+        name: S,
+        value: Expression<S>,
+    },
+    // `{{{ let name = expr }}}`
+    Let {
+        span: S,
+        name: S,
+        value: Expression<S>,
+    },
+    // `{{{ switch expr }}}`
+    Switch {
+        span: S,
+        subject: Expression<S>,
+    },
+    // `{{{ case expr }}}`
+    Case {
+        span: S,
+        value: Expression<S>,
+    },
+    // `{{{ default }}}`
+    Default {
+        span: S,
+    },
+    // `{{{ macro name(param0, param1, ...) }}}`
+    Macro {
+        span: S,
+        name: S,
+        params: Vec<S>,
+    },
+    // `{{{ call name(arg0, arg1, ...) }}}`
+    Call {
+        span: S,
+        expr: Expression<S>,
+    },
+    // `{{{ block name(arg0, arg1, ...) }}}`,
+    // a helper passed the body up to the next `{{{ end }}}`
+    BlockHelper {
+        span: S,
+        name: S,
+        args: Vec<Expression<S>>,
+        kwargs: Vec<(S, Expression<S>)>,
+    },
+    // `{{{ pragma name value }}}`
+    Pragma {
+        span: S,
+        name: S,
+        value: S,
+    },
     // `{{{ else }}}`
-    Else { span: S },
+    Else {
+        span: S,
+    },
     // `{{{ end }}}`
-    End { span: S },
+    End {
+        span: S,
+    },
     // `<!-- IF condition -->`
-    LegacyIf { span: S, subject: Expression<S> },
+    LegacyIf {
+        span: S,
+        subject: Expression<S>,
+    },
     // `<!-- BEGIN arr -->`
-    LegacyBegin { span: S, subject: Expression<S> },
+    LegacyBegin {
+        span: S,
+        subject: Expression<S>,
+    },
     // `<!-- ELSE -->`
-    LegacyElse { span: S },
+    LegacyElse {
+        span: S,
+    },
     // `<!-- END -->` or `<!-- ENDIF -->` or
     // `<!-- END subject -->` or `<!-- ENDIF subject -->`
-    LegacyEnd { span: S, subject_raw: S },
+    LegacyEnd {
+        span: S,
+        subject_raw: S,
+    },
 }
 
 impl<'a> Token<Span<'a>> {
@@ -66,6 +220,16 @@ impl<'a> Token<Span<'a>> {
             Token::InterpRaw { span, .. } => *span,
             Token::If { span, .. } => *span,
             Token::Each { span, .. } => *span,
+            Token::With { span, .. } => *span,
+            Token::Set { span, .. } => *span,
+            Token::Let { span, .. } => *span,
+            Token::Switch { span, .. } => *span,
+            Token::Case { span, .. } => *span,
+            Token::Default { span, .. } => *span,
+            Token::Macro { span, .. } => *span,
+            Token::Call { span, .. } => *span,
+            Token::BlockHelper { span, .. } => *span,
+            Token::Pragma { span, .. } => *span,
             Token::Else { span, .. } => *span,
             Token::End { span, .. } => *span,
             Token::LegacyIf { span, .. } => *span,
@@ -90,14 +254,94 @@ fn interp_raw(input: Span) -> IResult<Span, Token<Span>> {
     )(input)
 }
 
+/// a name bound to a value, like `item` in `{{{ each item in arr }}}`
+fn name(input: Span) -> IResult<Span, Span> {
+    recognize(pair(
+        alt((alpha1, tag("_"))),
+        many0_count(alt((alphanumeric1, tag("_")))),
+    ))(input)
+}
+
+/// `value in ` or `key, value in `
+fn each_names(input: Span) -> IResult<Span, (Option<Span>, Span)> {
+    terminated(
+        pair(opt(terminated(name, ws(tag(",")))), name),
+        delimited(multispace1, tag("in"), multispace1),
+    )(input)
+}
+
+/// `where cond`, `limit n`, `offset n`, `reverse`, or `sep ", "`
+fn each_clause(input: Span) -> IResult<Span, (Span, Option<Expression<Span>>)> {
+    alt((
+        pair(
+            alt((tag("where"), tag("limit"), tag("offset"), tag("sep"))),
+            map(preceded(multispace1, expression), Some),
+        ),
+        map(tag("reverse"), |keyword| (keyword, None)),
+    ))(input)
+}
+
+fn each_clauses(input: Span) -> IResult<Span, EachClauses<Span>> {
+    fold_many0(
+        terminated(each_clause, multispace0),
+        EachClauses::default(),
+        |mut clauses, (keyword, expr)| {
+            match *keyword.fragment() {
+                "where" => clauses.filter = expr,
+                "limit" => clauses.limit = expr,
+                "offset" => clauses.offset = expr,
+                "sep" => clauses.sep = expr,
+                _ => clauses.reverse = true,
+            }
+            clauses
+        },
+    )(input)
+}
+
+/// `arr` or a range, followed by optional clauses
+fn each_subject(input: Span) -> IResult<Span, (Expression<Span>, EachClauses<Span>)> {
+    pair(ws(alt((range, expression))), each_clauses)(input)
+}
+
 fn new_each(input: Span) -> IResult<Span, Token<Span>> {
     map(
         consumed(delimited(
             pair(tag("{{{"), ws(tag("each"))),
-            ws(expression),
+            pair(opt(each_names), each_subject),
+            tag("}}}"),
+        )),
+        |(span, (names, (subject, clauses)))| {
+            let (key, value) = match names {
+                Some((key, value)) => (key, Some(value)),
+                None => (None, None),
+            };
+
+            Token::Each {
+                span,
+                subject,
+                key,
+                value,
+                clauses,
+            }
+        },
+    )(input)
+}
+
+/// `{{{ for i in 1..n }}}` is `{{{ each i in 1..n }}}`
+fn new_for(input: Span) -> IResult<Span, Token<Span>> {
+    map(
+        consumed(delimited(
+            pair(tag("{{{"), ws(tag("for"))),
+            pair(each_names, pair(ws(range), each_clauses)),
             tag("}}}"),
         )),
-        |(span, subject)| Token::Each { span, subject },
+        |(span, ((key, value), (subject, clauses)))| Token::Each {
+            span,
+            subject,
+            key,
+            value: Some(value),
+            clauses,
+        },
     )(input)
 }
 
@@ -112,6 +356,140 @@ fn new_if(input: Span) -> IResult<Span, Token<Span>> {
     )(input)
 }
 
+fn new_with(input: Span) -> IResult<Span, Token<Span>> {
+    map(
+        consumed(delimited(
+            pair(tag("{{{"), ws(tag("with"))),
+            ws(expression),
+            tag("}}}"),
+        )),
+        |(span, subject)| Token::With { span, subject },
+    )(input)
+}
+
+/// `name = expr`
+fn assignment(input: Span) -> IResult<Span, (Span, Expression<Span>)> {
+    pair(name, preceded(ws(tag("=")), expression))(input)
+}
+
+fn new_set(input: Span) -> IResult<Span, Token<Span>> {
+    map(
+        consumed(delimited(
+            pair(tag("{{{"), ws(tag("set"))),
+            ws(assignment),
+            tag("}}}"),
+        )),
+        |(span, (name, value))| Token::Set { span, name, value },
+    )(input)
+}
+
+fn new_let(input: Span) -> IResult<Span, Token<Span>> {
+    map(
+        consumed(delimited(
+            pair(tag("{{{"), ws(tag("let"))),
+            ws(assignment),
+            tag("}}}"),
+        )),
+        |(span, (name, value))| Token::Let { span, name, value },
+    )(input)
+}
+
+fn new_switch(input: Span) -> IResult<Span, Token<Span>> {
+    map(
+        consumed(delimited(
+            pair(tag("{{{"), ws(tag("switch"))),
+            ws(expression),
+            tag("}}}"),
+        )),
+        |(span, subject)| Token::Switch { span, subject },
+    )(input)
+}
+
+fn new_case(input: Span) -> IResult<Span, Token<Span>> {
+    map(
+        consumed(delimited(
+            pair(tag("{{{"), ws(tag("case"))),
+            ws(expression),
+            tag("}}}"),
+        )),
+        |(span, value)| Token::Case { span, value },
+    )(input)
+}
+
+fn new_default(input: Span) -> IResult<Span, Token<Span>> {
+    map(
+        recognize(delimited(tag("{{{"), ws(tag("default")), tag("}}}"))),
+        |span| Token::Default { span },
+    )(input)
+}
+
+fn new_macro(input: Span) -> IResult<Span, Token<Span>> {
+    map(
+        consumed(delimited(
+            pair(tag("{{{"), ws(tag("macro"))),
+            ws(pair(
+                name,
+                delimited(tag("("), separated_list0(tag(","), ws(name)), tag(")")),
+            )),
+            tag("}}}"),
+        )),
+        |(span, (name, params))| Token::Macro { span, name, params },
+    )(input)
+}
+
+fn new_call(input: Span) -> IResult<Span, Token<Span>> {
+    map(
+        consumed(delimited(
+            pair(tag("{{{"), ws(tag("call"))),
+            ws(expression),
+            tag("}}}"),
+        )),
+        |(span, expr)| Token::Call { span, expr },
+    )(input)
+}
+
+fn new_block(input: Span) -> IResult<Span, Token<Span>> {
+    map_opt(
+        consumed(delimited(
+            pair(
+                tag("{{{"),
+                delimited(multispace0, tag("block"), multispace1),
+            ),
+            ws(helper),
+            tag("}}}"),
+        )),
+        |(span, expr)| match expr {
+            Expression::Helper {
+                name, args, kwargs, ..
+            } => Some(Token::BlockHelper {
+                span,
+                name,
+                args,
+                kwargs,
+            }),
+            _ => None,
+        },
+    )(input)
+}
+
+fn new_pragma(input: Span) -> IResult<Span, Token<Span>> {
+    map(
+        consumed(delimited(
+            pair(tag("{{{"), ws(tag("pragma"))),
+            pair(
+                name,
+                ws(recognize(many0_count(alt((
+                    alphanumeric1,
+                    tag("-"),
+                    tag("_"),
+                ))))),
+            ),
+            tag("}}}"),
+        )),
+        |(span, (name, value))| Token::Pragma { span, name, value },
+    )(input)
+}
+
 fn new_else(input: Span) -> IResult<Span, Token<Span>> {
     map(
         recognize(delimited(tag("{{{"), ws(tag("else")), tag("}}}"))),
@@ -203,7 +581,18 @@ fn token(input: Span) -> IResult<Span, Token<Span>> {
         interp_escaped,
         interp_raw,
         new_each,
+        new_for,
         new_if,
+        new_with,
+        new_set,
+        new_let,
+        new_switch,
+        new_case,
+        new_default,
+        new_macro,
+        new_call,
+        new_block,
+        new_pragma,
         new_else,
         new_end,
         legacy_begin,
@@ -355,10 +744,72 @@ mod test {
                     span: *span.fragment(),
                     subject: subject.span_to_str(),
                 },
-                Token::Each { span, subject } => Token::Each {
+                Token::With { span, subject } => Token::With {
                     span: *span.fragment(),
                     subject: subject.span_to_str(),
                 },
+                Token::Switch { span, subject } => Token::Switch {
+                    span: *span.fragment(),
+                    subject: subject.span_to_str(),
+                },
+                Token::Case { span, value } => Token::Case {
+                    span: *span.fragment(),
+                    value: value.span_to_str(),
+                },
+                Token::Default { span } => Token::Default {
+                    span: *span.fragment(),
+                },
+                Token::Macro { span, name, params } => Token::Macro {
+                    span: *span.fragment(),
+                    name: *name.fragment(),
+                    params: params.into_iter().map(|p| *p.fragment()).collect(),
+                },
+                Token::Call { span, expr } => Token::Call {
+                    span: *span.fragment(),
+                    expr: expr.span_to_str(),
+                },
+                Token::BlockHelper {
+                    span,
+                    name,
+                    args,
+                    kwargs,
+                } => Token::BlockHelper {
+                    span: *span.fragment(),
+                    name: *name.fragment(),
+                    args: args.into_iter().map(|a| a.span_to_str()).collect(),
+                    kwargs: kwargs
+                        .into_iter()
+                        .map(|(k, a)| (*k.fragment(), a.span_to_str()))
+                        .collect(),
+                },
+                Token::Pragma { span, name, value } => Token::Pragma {
+                    span: *span.fragment(),
+                    name: *name.fragment(),
+                    value: *value.fragment(),
+                },
+                Token::Set { span, name, value } => Token::Set {
+                    span: *span.fragment(),
+                    name: *name.fragment(),
+                    value: value.span_to_str(),
+                },
+                Token::Let { span, name, value } => Token::Let {
+                    span: *span.fragment(),
+                    name: *name.fragment(),
+                    value: value.span_to_str(),
+                },
+                Token::Each {
+                    span,
+                    subject,
+                    key,
+                    value,
+                    clauses,
+                } => Token::Each {
+                    span: *span.fragment(),
+                    subject: subject.span_to_str(),
+                    key: key.map(|k| *k.fragment()),
+                    value: value.map(|v| *v.fragment()),
+                    clauses: clauses.span_to_str(),
+                },
                 Token::Else { span } => Token::Else {
                     span: *span.fragment(),
                 },
@@ -422,7 +873,8 @@ mod test {
                     expr: Expression::Helper {
                         span: "call()",
                         name: "call",
-                        args: vec![]
+                        args: vec![],
+                        kwargs: vec![],
                     }
                 }
             ))
@@ -453,7 +905,8 @@ mod test {
                     expr: Expression::Helper {
                         span: "call()",
                         name: "call",
-                        args: vec![]
+                        args: vec![],
+                        kwargs: vec![],
                     }
                 }
             ))
@@ -484,11 +937,158 @@ mod test {
                     subject: Expression::Helper {
                         span: "call()",
                         name: "call",
-                        args: vec![]
+                        args: vec![],
+                        kwargs: vec![],
+                    }
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_new_with() {
+        assert_eq_unspan!(
+            new_with(sp("{{{ with a.b.c }}}")),
+            Ok((
+                "",
+                Token::With {
+                    span: "{{{ with a.b.c }}}",
+                    subject: Expression::Path {
+                        span: "a.b.c",
+                        path: vec![
+                            PathPart::Part("a"),
+                            PathPart::Part("b"),
+                            PathPart::Part("c")
+                        ]
+                    }
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_new_set() {
+        assert_eq_unspan!(
+            new_set(sp("{{{ set total = add(a, 1) }}}")),
+            Ok((
+                "",
+                Token::Set {
+                    span: "{{{ set total = add(a, 1) }}}",
+                    name: "total",
+                    value: Expression::Helper {
+                        span: "add(a, 1)",
+                        name: "add",
+                        args: vec![
+                            Expression::Path {
+                                span: "a",
+                                path: vec![PathPart::Part("a")]
+                            },
+                            Expression::NumberLiteral("1"),
+                        ],
+                        kwargs: vec![],
+                    }
+                }
+            ))
+        );
+        assert_eq_unspan!(
+            new_let(sp("{{{let x=y}}}")),
+            Ok((
+                "",
+                Token::Let {
+                    span: "{{{let x=y}}}",
+                    name: "x",
+                    value: Expression::Path {
+                        span: "y",
+                        path: vec![PathPart::Part("y")]
+                    }
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_new_switch() {
+        assert_eq_unspan!(
+            new_switch(sp("{{{ switch notification.type }}}")),
+            Ok((
+                "",
+                Token::Switch {
+                    span: "{{{ switch notification.type }}}",
+                    subject: Expression::Path {
+                        span: "notification.type",
+                        path: vec![PathPart::Part("notification"), PathPart::Part("type")]
+                    }
+                }
+            ))
+        );
+        assert_eq_unspan!(
+            new_case(sp("{{{ case \"mention\" }}}")),
+            Ok((
+                "",
+                Token::Case {
+                    span: "{{{ case \"mention\" }}}",
+                    value: Expression::StringLiteral("\"mention\"")
+                }
+            ))
+        );
+        assert_eq_unspan!(
+            new_default(sp("{{{default}}}")),
+            Ok((
+                "",
+                Token::Default {
+                    span: "{{{default}}}"
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_new_macro() {
+        assert_eq_unspan!(
+            new_macro(sp("{{{ macro avatar(user, size) }}}")),
+            Ok((
+                "",
+                Token::Macro {
+                    span: "{{{ macro avatar(user, size) }}}",
+                    name: "avatar",
+                    params: vec!["user", "size"],
+                }
+            ))
+        );
+        assert_eq_unspan!(
+            new_call(sp("{{{ call avatar(post.user) }}}")),
+            Ok((
+                "",
+                Token::Call {
+                    span: "{{{ call avatar(post.user) }}}",
+                    expr: Expression::Helper {
+                        span: "avatar(post.user)",
+                        name: "avatar",
+                        args: vec![Expression::Path {
+                            span: "post.user",
+                            path: vec![PathPart::Part("post"), PathPart::Part("user")]
+                        }],
+                        kwargs: vec![],
                     }
                 }
             ))
         );
+        assert_eq_unspan!(
+            new_block(sp("{{{ block wrapIf(user.online, tag=\"b\") }}}")),
+            Ok((
+                "",
+                Token::BlockHelper {
+                    span: "{{{ block wrapIf(user.online, tag=\"b\") }}}",
+                    name: "wrapIf",
+                    args: vec![Expression::Path {
+                        span: "user.online",
+                        path: vec![PathPart::Part("user"), PathPart::Part("online")]
+                    }],
+                    kwargs: vec![("tag", Expression::StringLiteral("\"b\""))],
+                }
+            ))
+        );
+        assert!(new_block(sp("{{{ blockquote(post) }}}")).is_err());
     }
 
     #[test]
@@ -502,7 +1102,10 @@ mod test {
                     subject: Expression::Path {
                         span: "abc.def",
                         path: vec![PathPart::Part("abc"), PathPart::Part("def")]
-                    }
+                    },
+                    key: None,
+                    value: None,
+                    clauses: Default::default(),
                 }
             ))
         );
@@ -515,13 +1118,177 @@ mod test {
                     subject: Expression::Helper {
                         span: "call()",
                         name: "call",
-                        args: vec![]
-                    }
+                        args: vec![],
+                        kwargs: vec![],
+                    },
+                    key: None,
+                    value: None,
+                    clauses: Default::default(),
+                }
+            ))
+        );
+        assert_eq_unspan!(
+            new_each(sp("{{{ each post in posts }}}")),
+            Ok((
+                "",
+                Token::Each {
+                    span: "{{{ each post in posts }}}",
+                    subject: Expression::Path {
+                        span: "posts",
+                        path: vec![PathPart::Part("posts")]
+                    },
+                    key: None,
+                    value: Some("post"),
+                    clauses: Default::default(),
+                }
+            ))
+        );
+        assert_eq_unspan!(
+            new_each(sp("{{{each uid, user_2 in users.byId}}}")),
+            Ok((
+                "",
+                Token::Each {
+                    span: "{{{each uid, user_2 in users.byId}}}",
+                    subject: Expression::Path {
+                        span: "users.byId",
+                        path: vec![PathPart::Part("users"), PathPart::Part("byId")]
+                    },
+                    key: Some("uid"),
+                    value: Some("user_2"),
+                    clauses: Default::default(),
+                }
+            ))
+        );
+        assert_eq_unspan!(
+            new_each(sp(
+                "{{{ each posts where !deleted limit 5 offset 1 reverse sep \", \" }}}"
+            )),
+            Ok((
+                "",
+                Token::Each {
+                    span: "{{{ each posts where !deleted limit 5 offset 1 reverse sep \", \" }}}",
+                    subject: Expression::Path {
+                        span: "posts",
+                        path: vec![PathPart::Part("posts")]
+                    },
+                    key: None,
+                    value: None,
+                    clauses: EachClauses {
+                        filter: Some(Expression::Negative {
+                            span: "!deleted",
+                            expr: Box::new(Expression::Path {
+                                span: "deleted",
+                                path: vec![PathPart::Part("deleted")]
+                            })
+                        }),
+                        limit: Some(Expression::NumberLiteral("5")),
+                        offset: Some(Expression::NumberLiteral("1")),
+                        reverse: true,
+                        sep: Some(Expression::StringLiteral("\", \"")),
+                    },
+                }
+            ))
+        );
+        assert_eq_unspan!(
+            new_each(sp("{{{ each inputs }}}")),
+            Ok((
+                "",
+                Token::Each {
+                    span: "{{{ each inputs }}}",
+                    subject: Expression::Path {
+                        span: "inputs",
+                        path: vec![PathPart::Part("inputs")]
+                    },
+                    key: None,
+                    value: None,
+                    clauses: Default::default(),
                 }
             ))
         );
     }
 
+    #[test]
+    fn test_new_for() {
+        assert_eq_unspan!(
+            new_for(sp("{{{ for i in 1..5 }}}")),
+            Ok((
+                "",
+                Token::Each {
+                    span: "{{{ for i in 1..5 }}}",
+                    subject: Expression::Range {
+                        span: "1..5",
+                        start: Box::new(Expression::NumberLiteral("1")),
+                        end: Box::new(Expression::NumberLiteral("5")),
+                    },
+                    key: None,
+                    value: Some("i"),
+                    clauses: Default::default(),
+                }
+            ))
+        );
+        assert_eq_unspan!(
+            new_each(sp("{{{ each page in range(1, pageCount) }}}")),
+            Ok((
+                "",
+                Token::Each {
+                    span: "{{{ each page in range(1, pageCount) }}}",
+                    subject: Expression::Range {
+                        span: "range(1, pageCount)",
+                        start: Box::new(Expression::NumberLiteral("1")),
+                        end: Box::new(Expression::Path {
+                            span: "pageCount",
+                            path: vec![PathPart::Part("pageCount")]
+                        }),
+                    },
+                    key: None,
+                    value: Some("page"),
+                    clauses: Default::default(),
+                }
+            ))
+        );
+        assert_eq_unspan!(
+            new_for(sp("{{{ for i in 1..n limit 3 reverse }}}")),
+            Ok((
+                "",
+                Token::Each {
+                    span: "{{{ for i in 1..n limit 3 reverse }}}",
+                    subject: Expression::Range {
+                        span: "1..n",
+                        start: Box::new(Expression::NumberLiteral("1")),
+                        end: Box::new(Expression::Path {
+                            span: "n",
+                            path: vec![PathPart::Part("n")]
+                        }),
+                    },
+                    key: None,
+                    value: Some("i"),
+                    clauses: EachClauses {
+                        limit: Some(Expression::NumberLiteral("3")),
+                        reverse: true,
+                        ..Default::default()
+                    },
+                }
+            ))
+        );
+        assert!(new_for(sp("{{{ for items }}}")).is_err());
+    }
+
+    #[test]
+    fn test_new_pragma() {
+        assert_eq_unspan!(
+            new_pragma(sp("{{{ pragma escape json-string }}}")),
+            Ok((
+                "",
+                Token::Pragma {
+                    span: "{{{ pragma escape json-string }}}",
+                    name: "escape",
+                    value: "json-string",
+                }
+            ))
+        );
+        assert!(new_pragma(sp("{{{ pragma }}}")).is_err());
+    }
+
     #[test]
     fn test_new_else() {
         assert_eq_unspan!(
@@ -580,7 +1347,8 @@ mod test {
                     subject: Expression::Helper {
                         span: "call()",
                         name: "call",
-                        args: vec![]
+                        args: vec![],
+                        kwargs: vec![],
                     }
                 }
             ))
@@ -638,7 +1406,8 @@ mod test {
                     subject: Expression::Helper {
                         span: "call()",
                         name: "call",
-                        args: vec![]
+                        args: vec![],
+                        kwargs: vec![],
                     }
                 }
             ))
@@ -789,7 +1558,10 @@ mod test {
                         subject: Expression::Path {
                             span: "abc",
                             path: vec![PathPart::Part("abc")]
-                        }
+                        },
+                        key: None,
+                        value: None,
+                        clauses: Default::default(),
                     },
                     Token::Text(" for each thing "),
                     Token::End {
@@ -809,7 +1581,10 @@ mod test {
                         subject: Expression::Path {
                             span: "abc",
                             path: vec![PathPart::Part("abc")]
-                        }
+                        },
+                        key: None,
+                        value: None,
+                        clauses: Default::default(),
                     },
                     Token::Text(" for each thing "),
                     Token::End {
@@ -845,5 +1620,10 @@ mod test {
                 ]
             ))
         );
+
+        assert_eq_unspan!(
+            tokens(sp("if (a) { a || b } else { c | }")),
+            Ok(("", vec![Token::Text("if (a) { a || b } else { c | }")]))
+        );
     }
 }
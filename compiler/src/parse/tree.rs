@@ -4,11 +4,15 @@ use crate::{
         expression::Expression,
         path::{
             resolve,
+            resolve_keyword,
             Path,
             PathBuf,
             PathPart,
         },
-        tokens::Token,
+        tokens::{
+            EachClauses,
+            Token,
+        },
         Span,
         SpanExt,
     },
@@ -17,8 +21,8 @@ use std::collections::HashSet;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Instruction<S> {
-    // Template text passed through
-    Text(S),
+    // Template text passed through, in pieces which are output one after another
+    Text(Vec<S>),
     // `{obj.prop}`
     InterpEscaped(Expression<S>),
     // `{{obj.prop}}`
@@ -31,9 +35,128 @@ pub enum Instruction<S> {
     Iter {
         depth: u32,
         subject: Expression<S>,
+        clauses: EachClauses<S>,
+        body: Vec<Instruction<S>>,
+        alt: Vec<Instruction<S>>,
+    },
+    Switch {
+        subject: Expression<S>,
+        cases: Vec<(Expression<S>, Vec<Instruction<S>>)>,
+        default: Vec<Instruction<S>>,
+    },
+    // function defined with `{{{ macro name(params) }}}`
+    Macro {
+        name: S,
+        params: Vec<S>,
+        body: Vec<Instruction<S>>,
+    },
+    // helper called with render functions for `body` and `alt`
+    BlockHelper {
+        name: S,
+        args: Vec<Expression<S>>,
+        kwargs: Vec<(S, Expression<S>)>,
         body: Vec<Instruction<S>>,
         alt: Vec<Instruction<S>>,
     },
+    // `name` bound to the value of an expression within `body`
+    Let {
+        name: S,
+        value: Expression<S>,
+        body: Vec<Instruction<S>>,
+    },
+    // an ambiguous legacy `<!-- BEGIN x -->` within a loop, which iterates over `./x`
+    // if it is truthy or `x` otherwise, with the loop in `body`
+    // `relative` is evaluated once, and paths in `body` which resolve differently
+    // for each choose between them with `@relative` at `depth`
+    Ambiguous {
+        depth: u32,
+        relative: Expression<S>,
+        body: Vec<Instruction<S>>,
+    },
+}
+
+impl<'a> Instruction<Span<'a>> {
+    /// Replace spans with their source text, to compare instructions
+    /// regardless of where they are in a template
+    pub fn span_to_str(self) -> Instruction<&'a str> {
+        match self {
+            Instruction::Text(pieces) => {
+                Instruction::Text(pieces.into_iter().map(|p| *p.fragment()).collect())
+            }
+            Instruction::InterpEscaped(expr) => Instruction::InterpEscaped(expr.span_to_str()),
+            Instruction::InterpRaw(expr) => Instruction::InterpRaw(expr.span_to_str()),
+            Instruction::If { subject, body, alt } => Instruction::If {
+                subject: subject.span_to_str(),
+                body: body.into_iter().map(|i| i.span_to_str()).collect(),
+                alt: alt.into_iter().map(|i| i.span_to_str()).collect(),
+            },
+            Instruction::Iter {
+                depth,
+                subject,
+                clauses,
+                body,
+                alt,
+            } => Instruction::Iter {
+                depth,
+                subject: subject.span_to_str(),
+                clauses: clauses.span_to_str(),
+                body: body.into_iter().map(|i| i.span_to_str()).collect(),
+                alt: alt.into_iter().map(|i| i.span_to_str()).collect(),
+            },
+            Instruction::Switch {
+                subject,
+                cases,
+                default,
+            } => Instruction::Switch {
+                subject: subject.span_to_str(),
+                cases: cases
+                    .into_iter()
+                    .map(|(value, body)| {
+                        (
+                            value.span_to_str(),
+                            body.into_iter().map(|i| i.span_to_str()).collect(),
+                        )
+                    })
+                    .collect(),
+                default: default.into_iter().map(|i| i.span_to_str()).collect(),
+            },
+            Instruction::Macro { name, params, body } => Instruction::Macro {
+                name: *name.fragment(),
+                params: params.into_iter().map(|p| *p.fragment()).collect(),
+                body: body.into_iter().map(|i| i.span_to_str()).collect(),
+            },
+            Instruction::BlockHelper {
+                name,
+                args,
+                kwargs,
+                body,
+                alt,
+            } => Instruction::BlockHelper {
+                name: *name.fragment(),
+                args: args.into_iter().map(|a| a.span_to_str()).collect(),
+                kwargs: kwargs
+                    .into_iter()
+                    .map(|(k, a)| (*k.fragment(), a.span_to_str()))
+                    .collect(),
+                body: body.into_iter().map(|i| i.span_to_str()).collect(),
+                alt: alt.into_iter().map(|i| i.span_to_str()).collect(),
+            },
+            Instruction::Let { name, value, body } => Instruction::Let {
+                name: *name.fragment(),
+                value: value.span_to_str(),
+                body: body.into_iter().map(|i| i.span_to_str()).collect(),
+            },
+            Instruction::Ambiguous {
+                depth,
+                relative,
+                body,
+            } => Instruction::Ambiguous {
+                depth,
+                relative: relative.span_to_str(),
+                body: body.into_iter().map(|i| i.span_to_str()).collect(),
+            },
+        }
+    }
 }
 
 /// in a case where there are extra End tokens
@@ -56,12 +179,19 @@ pub fn fix_extra_tokens<'a>(input: Vec<Token<Span<'a>>>) -> Vec<Token<Span<'a>>>
             Token::LegacyIf { subject, .. }
             | Token::LegacyBegin { subject, .. }
             | Token::If { subject, .. }
-            | Token::Each { subject, .. } => {
+            | Token::Each { subject, .. }
+            | Token::With { subject, .. }
+            | Token::Let { value: subject, .. }
+            | Token::Switch { subject, .. } => {
                 let subject_raw = *subject.span().fragment();
 
                 expected_subjects.push(subject_raw);
                 starts_count += 1;
             }
+            Token::Macro { name, .. } | Token::BlockHelper { name, .. } => {
+                expected_subjects.push(name.fragment());
+                starts_count += 1;
+            }
             Token::LegacyEnd { .. } | Token::End { .. } => {
                 ends_count += 1;
 
@@ -84,7 +214,12 @@ pub fn fix_extra_tokens<'a>(input: Vec<Token<Span<'a>>>) -> Vec<Token<Span<'a>>>
                                 Token::LegacyIf { .. }
                                 | Token::LegacyBegin { .. }
                                 | Token::If { .. }
-                                | Token::Each { .. } => {
+                                | Token::Each { .. }
+                                | Token::With { .. }
+                                | Token::Let { .. }
+                                | Token::Switch { .. }
+                                | Token::Macro { .. }
+                                | Token::BlockHelper { .. } => {
                                     break;
                                 }
                                 Token::LegacyEnd { subject_raw, .. } => {
@@ -145,49 +280,367 @@ pub fn fix_extra_tokens<'a>(input: Vec<Token<Span<'a>>>) -> Vec<Token<Span<'a>>>
     }
 }
 
+/// What a name bound in a template refers to
+#[derive(Debug, Clone)]
+pub enum Binding<'a> {
+    // a value, like `value` in `{{{ each value in arr }}}`
+    Path(PathBuf<Span<'a>>),
+    // a macro defined with `{{{ macro name(params) }}}`
+    Macro(Vec<&'a str>),
+}
+
+/// Names bound by `{{{ each value in arr }}}`, `{{{ set name = expr }}}`, etc
+/// and what they refer to, later names shadow earlier ones
+pub type Names<'b, 'a> = &'b [(&'a str, Binding<'a>)];
+
+/// The path which relative paths are resolved against
+///
+/// The body of an ambiguous legacy `<!-- BEGIN x -->` is parsed once against the bases for
+/// both `./x` and `x`, and each path in it is resolved against both
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Base<'a> {
+    Path(PathBuf<Span<'a>>),
+    // chosen at runtime by the `@relative` keyword of the loop at `depth`
+    Ambiguous {
+        keyword: Span<'a>,
+        depth: u32,
+        relative: Box<Base<'a>>,
+        absolute: Box<Base<'a>>,
+    },
+}
+
+impl<'a> Base<'a> {
+    fn ambiguous(keyword: Span<'a>, depth: u32, relative: Base<'a>, absolute: Base<'a>) -> Self {
+        if relative == absolute {
+            relative
+        } else {
+            Base::Ambiguous {
+                keyword,
+                depth,
+                relative: Box::new(relative),
+                absolute: Box::new(absolute),
+            }
+        }
+    }
+
+    /// Apply `f` to each path this could be
+    fn map<F>(&self, f: &mut F) -> Self
+    where
+        F: FnMut(Path<'_, Span<'a>>) -> PathBuf<Span<'a>>,
+    {
+        match self {
+            Base::Path(path) => Base::Path(f(path)),
+            Base::Ambiguous {
+                keyword,
+                depth,
+                relative,
+                absolute,
+            } => Base::ambiguous(*keyword, *depth, relative.map(f), absolute.map(f)),
+        }
+    }
+
+    /// Create an expression with `f` for each path this could be,
+    /// choosing between them where they are different
+    fn choose<F>(&self, f: &mut F) -> Expression<Span<'a>>
+    where
+        F: FnMut(Path<'_, Span<'a>>) -> Expression<Span<'a>>,
+    {
+        match self {
+            Base::Path(path) => f(path),
+            Base::Ambiguous {
+                keyword,
+                depth,
+                relative,
+                absolute,
+            } => choice(*keyword, *depth, relative.choose(f), absolute.choose(f)),
+        }
+    }
+
+    /// Whether every path this could be is the element of the loop at `depth`
+    fn is_element_of(&self, depth: u32) -> bool {
+        match self {
+            Base::Path(path) => matches!(
+                path.last(),
+                Some(PathPart::PartDepth(_, n)) | Some(PathPart::ComputedDepth(_, _, n)) if *n == depth
+            ),
+            Base::Ambiguous {
+                relative, absolute, ..
+            } => relative.is_element_of(depth) && absolute.is_element_of(depth),
+        }
+    }
+}
+
+/// `then` if the ambiguous `BEGIN` at `depth` is relative, otherwise `otherwise`
+fn choice<'a>(
+    keyword: Span<'a>,
+    depth: u32,
+    then: Expression<Span<'a>>,
+    otherwise: Expression<Span<'a>>,
+) -> Expression<Span<'a>> {
+    if then == otherwise {
+        return then;
+    }
+
+    Expression::Ternary {
+        span: then.span(),
+        condition: Box::new(Expression::Path {
+            span: keyword,
+            path: vec![PathPart::PartDepth(keyword, depth)],
+        }),
+        then: Box::new(then),
+        otherwise: Box::new(otherwise),
+    }
+}
+
+/// The base for the body of a block, which is its subject if that is a path
+/// `loop_depth` is set on the subject for the body of a loop
+fn body_base<'a>(
+    depth: u32,
+    base: &Base<'a>,
+    names: Names<'_, 'a>,
+    subject: &Expression<Span<'a>>,
+    loop_depth: Option<u32>,
+) -> Base<'a> {
+    base.map(&mut |base| match resolve_expression_paths(
+        depth,
+        &Base::Path(base.to_vec()),
+        names,
+        subject.clone(),
+    ) {
+        Expression::Path { mut path, .. } => {
+            if let (Some(last), Some(loop_depth)) = (path.last_mut(), loop_depth) {
+                last.with_depth(loop_depth)
+            }
+            path
+        }
+        _ => base.to_vec(),
+    })
+}
+
+fn lookup_name<'a, 'b>(names: Names<'b, 'a>, name: &str) -> Option<&'b Binding<'a>> {
+    names
+        .iter()
+        .rev()
+        .find(|(bound, _)| *bound == name)
+        .map(|(_, binding)| binding)
+}
+
+/// Replace a bound name at the start of a path with the path it refers to
+fn resolve_name<'a, 'b>(
+    names: Names<'b, 'a>,
+    path: &[PathPart<Span<'a>>],
+) -> Option<PathBuf<Span<'a>>> {
+    let first = match path.first()? {
+        PathPart::Part(first) => *first.fragment(),
+        _ => return None,
+    };
+
+    match lookup_name(names, first)? {
+        Binding::Path(bound) => {
+            let mut out = bound.clone();
+            out.extend_from_slice(&path[1..]);
+            Some(out)
+        }
+        Binding::Macro(_) => None,
+    }
+}
+
+/// Add a binding for a local variable declared with `set` or `let`
+fn bind_local<'a>(names: Names<'_, 'a>, name: Span<'a>) -> Vec<(&'a str, Binding<'a>)> {
+    let mut names = names.to_vec();
+    names.push((
+        name.fragment(),
+        Binding::Path(vec![
+            PathPart::Part(Span::new_extra("@local", name.extra)),
+            PathPart::Part(name),
+        ]),
+    ));
+    names
+}
+
+/// Bind the names of all macros defined in the template,
+/// so they can be called before their definition and recursively
+pub fn macro_names<'a>(tokens: &[Token<Span<'a>>]) -> Vec<(&'a str, Binding<'a>)> {
+    tokens
+        .iter()
+        .filter_map(|tok| match tok {
+            Token::Macro { name, params, .. } => Some((
+                *name.fragment(),
+                Binding::Macro(params.iter().map(|param| *param.fragment()).collect()),
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
 fn resolve_expression_paths<'a, 'b>(
-    base: Path<'b, Span<'a>>,
+    depth: u32,
+    base: &Base<'a>,
+    names: Names<'b, 'a>,
     expr: Expression<Span<'a>>,
 ) -> Expression<Span<'a>> {
     match expr {
-        s @ Expression::StringLiteral(_) => s,
-        Expression::Path { span, path } => Expression::Path {
-            span,
-            path: resolve(base, path),
-        },
-        Expression::Negative { span, expr } => Expression::Negative {
-            span,
-            expr: Box::new(resolve_expression_paths(base, *expr)),
-        },
-        Expression::Helper { span, name, args } => Expression::Helper {
-            span,
-            name,
-            args: args
-                .into_iter()
-                .map(|x| resolve_expression_paths(base, x))
-                .collect(),
-        },
-        Expression::LegacyHelper { span, name, args } => Expression::LegacyHelper {
+        Expression::Path { span, path } => base.choose(&mut |base| {
+            let leaf = Base::Path(base.to_vec());
+            // resolve paths used as keys, like `[./key]`
+            let path: PathBuf<Span> = path
+                .iter()
+                .cloned()
+                .map(|part| match part {
+                    PathPart::Computed(span, expr) => PathPart::Computed(
+                        span,
+                        Box::new(resolve_expression_paths(depth, &leaf, names, *expr)),
+                    ),
+                    part => part,
+                })
+                .collect();
+
+            Expression::Path {
+                span,
+                path: match resolve_name(names, &path) {
+                    // loop names already carry the depth of their own loop
+                    Some(bound) if matches!(bound.first(), Some(PathPart::PartDepth(..))) => bound,
+                    Some(bound) => resolve(base, bound),
+                    None => resolve_keyword(depth, &path).unwrap_or_else(|| resolve(base, path)),
+                },
+            }
+        }),
+        Expression::Helper {
             span,
             name,
-            args: args
+            args,
+            kwargs,
+        } => {
+            let resolve_arg = |x| resolve_expression_paths(depth, base, names, x);
+            let args: Vec<_> = args.into_iter().map(resolve_arg).collect();
+            let kwargs: Vec<_> = kwargs
                 .into_iter()
-                .map(|x| resolve_expression_paths(base, x))
-                .collect(),
-        },
+                .map(|(key, x)| (key, resolve_arg(x)))
+                .collect();
+
+            match lookup_name(names, name.fragment()) {
+                Some(Binding::Macro(params)) => Expression::MacroCall {
+                    span,
+                    name,
+                    args: macro_arguments(name, params, args, kwargs),
+                },
+                _ => Expression::Helper {
+                    span,
+                    name,
+                    args,
+                    kwargs,
+                },
+            }
+        }
+        expr => expr.map_children(|x| resolve_expression_paths(depth, base, names, x)),
     }
 }
 
+/// Place keyword arguments of a macro call at the position of the parameter with that name,
+/// filling any gaps with `null`
+#[rustfmt::skip::macros(warn)]
+fn macro_arguments<'a>(
+    name: Span<'a>,
+    params: &[&str],
+    mut args: Vec<Expression<Span<'a>>>,
+    kwargs: Vec<(Span<'a>, Expression<Span<'a>>)>,
+) -> Vec<Expression<Span<'a>>> {
+    for (key, arg) in kwargs {
+        let index = match params.iter().position(|param| param == key.fragment()) {
+            Some(index) => index,
+            None => {
+                let (line, column, padding) = key.get_line_column_padding();
+                warn!("[benchpress] warning: macro `{}` has no parameter `{}`", name, key);
+                warn!("     --> {}:{}:{}",
+                    key.extra.filename, key.location_line(), column);
+                warn!("      |");
+                warn!("{:>5} | {}", key.location_line(), line);
+                warn!("      | {}{} help: the parameters are `{}`",
+                    padding, "^".repeat(key.len()), params.join("`, `"));
+                warn!("      = note: This argument will be ignored\n");
+
+                continue;
+            }
+        };
+
+        while args.len() <= index {
+            args.push(Expression::NullLiteral(Span::new_extra("null", key.extra)));
+        }
+        args[index] = arg;
+    }
+
+    args
+}
+
+/// Make paths in a `where` clause which would refer to top-level values
+/// relative to the element instead, so `where !deleted` checks `./deleted`
+fn relative_filter<'a, 'b>(
+    base: Path<'b, Span<'a>>,
+    names: Names<'b, 'a>,
+    expr: Expression<Span<'a>>,
+) -> Expression<Span<'a>> {
+    match expr {
+        Expression::Path { span, path } => {
+            let top_level = matches!(
+                path.first(),
+                Some(PathPart::Part(first)) if !first.starts_with(&['.', '@'] as &[char])
+            ) && resolve_name(names, &path).is_none()
+                && resolve(base, path.clone()) == path;
+
+            if top_level {
+                let mut relative = vec![PathPart::Part(Span::new_extra("./", span.extra))];
+                relative.extend(path);
+                Expression::Path {
+                    span,
+                    path: relative,
+                }
+            } else {
+                Expression::Path { span, path }
+            }
+        }
+        expr => expr.map_children(|x| relative_filter(base, names, x)),
+    }
+}
+
+/// A block which couldn't be parsed, at the token which opened it
 #[derive(Debug)]
-pub struct TreeError;
+pub struct TreeError<'a> {
+    pub span: Span<'a>,
+    pub message: &'static str,
+    pub help: &'static str,
+}
+
+impl<'a> TreeError<'a> {
+    fn unclosed(span: Span<'a>) -> Self {
+        TreeError {
+            span,
+            message: "block is never closed",
+            help: "this block has no matching end",
+        }
+    }
+}
 
-#[rustfmt::skip::macros(warn)]
 pub fn tree<'a, 'b, I>(
     depth: u32,
     base: Path<'b, Span<'a>>,
+    names: Names<'b, 'a>,
+    input: &mut I,
+    output: &mut Vec<Instruction<Span<'a>>>,
+) -> Result<Option<Token<Span<'a>>>, TreeError<'a>>
+where
+    I: Iterator<Item = Token<Span<'a>>> + Clone,
+{
+    tree_from(depth, &Base::Path(base.to_vec()), names, input, output)
+}
+
+#[rustfmt::skip::macros(warn)]
+fn tree_from<'a, 'b, I>(
+    depth: u32,
+    base: &Base<'a>,
+    names: Names<'b, 'a>,
     input: &mut I,
     output: &mut Vec<Instruction<Span<'a>>>,
-) -> Result<Option<Token<Span<'a>>>, TreeError>
+) -> Result<Option<Token<Span<'a>>>, TreeError<'a>>
 where
     I: Iterator<Item = Token<Span<'a>>> + Clone,
 {
@@ -229,42 +682,56 @@ where
     };
 
     while let Some(tok) = input.next() {
+        // `{{{ set name = expr }}}` is in scope until the end of the enclosing block,
+        // so the rest of the block becomes the body of the binding
+        if let Token::Set { name, value, .. } = tok {
+            let mut body = vec![];
+            let end = tree_from(depth, base, &bind_local(names, name), input, &mut body)?;
+
+            output.push(Instruction::Let {
+                name,
+                value: resolve_expression_paths(depth, base, names, value),
+                body,
+            });
+            return Ok(end);
+        }
+
         output.push(match tok {
             // convert a text token to a text instruction
-            Token::Text(value) => Instruction::Text(value),
+            Token::Text(value) => Instruction::Text(vec![value]),
             // convert token to expression
             // generate expression
             Token::InterpEscaped { expr, .. } => {
-                Instruction::InterpEscaped(resolve_expression_paths(base, expr))
+                Instruction::InterpEscaped(resolve_expression_paths(depth, base, names, expr))
             }
             Token::InterpRaw { expr, .. } => {
-                Instruction::InterpRaw(resolve_expression_paths(base, expr))
+                Instruction::InterpRaw(resolve_expression_paths(depth, base, names, expr))
             }
             // create an if-then-else instruction
             Token::If { span, subject, .. } => {
                 let mut body = vec![];
                 let mut alt = vec![];
 
-                match tree(depth, base, input, &mut body)? {
+                match tree_from(depth, base, names, input, &mut body)? {
                     Some(els @ Token::Else { .. }) | Some(els @ Token::LegacyElse { .. }) => {
                         if let Token::LegacyElse { .. } = els {
                             mixed_warning("if", span, els)
                         }
 
                         // consume the end after the else
-                        match tree(depth, base, input, &mut alt)? {
+                        match tree_from(depth, base, names, input, &mut alt)? {
                             Some(Token::End { .. }) => {}
                             Some(end @ Token::LegacyEnd { .. }) => mixed_warning("if", span, end),
-                            _ => return Err(TreeError),
+                            _ => return Err(TreeError::unclosed(span)),
                         }
                     }
                     Some(Token::End { .. }) => {}
                     Some(end @ Token::LegacyEnd { .. }) => mixed_warning("if", span, end),
-                    _ => return Err(TreeError),
+                    _ => return Err(TreeError::unclosed(span)),
                 }
 
                 Instruction::If {
-                    subject: resolve_expression_paths(base, subject),
+                    subject: resolve_expression_paths(depth, base, names, subject),
                     body,
                     alt,
                 }
@@ -273,70 +740,250 @@ where
             Token::Each {
                 span,
                 subject,
+                key,
+                value,
+                clauses,
             } => {
                 let mut body = vec![];
                 let mut alt = vec![];
 
-                let subject = resolve_expression_paths(base, subject);
-                let base: PathBuf<Span> = if let Expression::Path { path: base, .. } = &subject {
-                    let mut base = base.clone();
-                    if let Some(last) = base.last_mut() {
-                        last.with_depth(depth)
-                    }
-                    base
-                } else {
-                    base.to_vec()
+                let base_outer = base;
+                let base = body_base(depth, base, names, &subject, Some(depth));
+                let subject = resolve_expression_paths(depth, base_outer, names, subject);
+
+                // bind `key, value` in `{{{ each key, value in arr }}}`
+                // to the `@key` and `@value` of this loop
+                let mut body_names = names.to_vec();
+                if let Some(key) = key {
+                    let keyword = Span::new_extra("@key", key.extra);
+                    body_names.push((
+                        key.fragment(),
+                        Binding::Path(vec![PathPart::PartDepth(keyword, depth)]),
+                    ));
+                }
+                if let Some(value) = value {
+                    let keyword = Span::new_extra("@value", value.extra);
+                    body_names.push((
+                        value.fragment(),
+                        Binding::Path(vec![PathPart::PartDepth(keyword, depth)]),
+                    ));
+                }
+
+                let clauses = EachClauses {
+                    filter: clauses.filter.map(|expr| {
+                        base.choose(&mut |base| {
+                            let expr = relative_filter(base, &body_names, expr.clone());
+                            let base = Base::Path(base.to_vec());
+                            resolve_expression_paths(depth + 1, &base, &body_names, expr)
+                        })
+                    }),
+                    limit: clauses
+                        .limit
+                        .map(|expr| resolve_expression_paths(depth, base_outer, names, expr)),
+                    offset: clauses
+                        .offset
+                        .map(|expr| resolve_expression_paths(depth, base_outer, names, expr)),
+                    reverse: clauses.reverse,
+                    sep: clauses
+                        .sep
+                        .map(|expr| resolve_expression_paths(depth, base_outer, names, expr)),
                 };
 
-                match tree(depth + 1, &base, input, &mut body)? {
+                match tree_from(depth + 1, &base, &body_names, input, &mut body)? {
                     Some(els @ Token::Else { .. }) | Some(els @ Token::LegacyElse { .. }) => {
                         if let Token::LegacyElse { .. } = els {
                             mixed_warning("each", span, els)
                         }
 
                         // consume the end after the else
-                        match tree(depth, &base, input, &mut alt)? {
+                        match tree_from(depth, &base, names, input, &mut alt)? {
                             Some(Token::End { .. }) => {}
                             Some(end @ Token::LegacyEnd { .. }) => mixed_warning("each", span, end),
-                            _ => return Err(TreeError),
+                            _ => return Err(TreeError::unclosed(span)),
                         }
                     }
                     Some(Token::End { .. }) => {}
                     Some(end @ Token::LegacyEnd { .. }) => mixed_warning("each", span, end),
-                    _ => return Err(TreeError),
+                    _ => return Err(TreeError::unclosed(span)),
                 }
 
                 Instruction::Iter {
                     depth,
                     subject,
+                    clauses,
                     body,
                     alt,
                 }
             }
+            // create an if-then-else instruction with paths in the body relative to the subject
+            Token::With { span, subject } => {
+                let mut body = vec![];
+                let mut alt = vec![];
+
+                let body_base = body_base(depth, base, names, &subject, None);
+                let subject = resolve_expression_paths(depth, base, names, subject);
+
+                match tree_from(depth, &body_base, names, input, &mut body)? {
+                    Some(els @ Token::Else { .. }) | Some(els @ Token::LegacyElse { .. }) => {
+                        if let Token::LegacyElse { .. } = els {
+                            mixed_warning("with", span, els)
+                        }
+
+                        // consume the end after the else
+                        match tree_from(depth, base, names, input, &mut alt)? {
+                            Some(Token::End { .. }) => {}
+                            Some(end @ Token::LegacyEnd { .. }) => mixed_warning("with", span, end),
+                            _ => return Err(TreeError::unclosed(span)),
+                        }
+                    }
+                    Some(Token::End { .. }) => {}
+                    Some(end @ Token::LegacyEnd { .. }) => mixed_warning("with", span, end),
+                    _ => return Err(TreeError::unclosed(span)),
+                }
+
+                Instruction::If { subject, body, alt }
+            }
+            // create a switch instruction with a body for each case
+            Token::Switch { span, subject } => {
+                // only whitespace is allowed before the first case
+                let mut before = vec![];
+                let mut next = tree_from(depth, base, names, input, &mut before)?;
+                if !before.iter().all(|i| {
+                    matches!(i, Instruction::Text(text) if text.iter().all(|t| t.fragment().trim().is_empty()))
+                }) {
+                    return Err(TreeError {
+                        span,
+                        message: "switch has content outside of a case",
+                        help: "only whitespace can come before the first case",
+                    });
+                }
+
+                let mut cases = vec![];
+                let mut default = None;
+                loop {
+                    let mut body = vec![];
+                    match next {
+                        Some(Token::Case { value, .. }) if default.is_none() => {
+                            next = tree_from(depth, base, names, input, &mut body)?;
+                            cases.push((resolve_expression_paths(depth, base, names, value), body));
+                        }
+                        Some(Token::Default { .. }) if default.is_none() => {
+                            next = tree_from(depth, base, names, input, &mut body)?;
+                            default = Some(body);
+                        }
+                        Some(Token::End { .. }) => break,
+                        _ => return Err(TreeError::unclosed(span)),
+                    }
+                }
+
+                Instruction::Switch {
+                    subject: resolve_expression_paths(depth, base, names, subject),
+                    cases,
+                    default: default.unwrap_or_default(),
+                }
+            }
+            // define a macro, which can't refer to anything around it except other macros
+            Token::Macro { span, name, params } => {
+                let mut body_names: Vec<(&str, Binding)> = names
+                    .iter()
+                    .filter(|(_, binding)| matches!(binding, Binding::Macro(_)))
+                    .cloned()
+                    .collect();
+                for param in &params {
+                    body_names = bind_local(&body_names, *param);
+                }
+
+                let mut body = vec![];
+                match tree(0, &[], &body_names, input, &mut body)? {
+                    Some(Token::End { .. }) => {}
+                    _ => return Err(TreeError::unclosed(span)),
+                }
+
+                Instruction::Macro { name, params, body }
+            }
+            // `{{{ call name(args) }}}` outputs the result of a macro or helper as-is
+            Token::Call { expr, .. } => {
+                Instruction::InterpRaw(resolve_expression_paths(depth, base, names, expr))
+            }
+            // create a block helper instruction with the body and alt
+            Token::BlockHelper {
+                span,
+                name,
+                args,
+                kwargs,
+            } => {
+                let mut body = vec![];
+                let mut alt = vec![];
+
+                match tree_from(depth, base, names, input, &mut body)? {
+                    Some(els @ Token::Else { .. }) | Some(els @ Token::LegacyElse { .. }) => {
+                        if let Token::LegacyElse { .. } = els {
+                            mixed_warning("block", span, els)
+                        }
+
+                        // consume the end after the else
+                        match tree_from(depth, base, names, input, &mut alt)? {
+                            Some(Token::End { .. }) => {}
+                            Some(end @ Token::LegacyEnd { .. }) => mixed_warning("block", span, end),
+                            _ => return Err(TreeError::unclosed(span)),
+                        }
+                    }
+                    Some(Token::End { .. }) => {}
+                    Some(end @ Token::LegacyEnd { .. }) => mixed_warning("block", span, end),
+                    _ => return Err(TreeError::unclosed(span)),
+                }
+
+                let resolve_arg = |arg| resolve_expression_paths(depth, base, names, arg);
+                Instruction::BlockHelper {
+                    name,
+                    args: args.into_iter().map(resolve_arg).collect(),
+                    kwargs: kwargs
+                        .into_iter()
+                        .map(|(key, arg)| (key, resolve_arg(arg)))
+                        .collect(),
+                    body,
+                    alt,
+                }
+            }
+            // bind a name within the block
+            Token::Let { span, name, value } => {
+                let mut body = vec![];
+
+                match tree_from(depth, base, &bind_local(names, name), input, &mut body)? {
+                    Some(Token::End { .. }) => {}
+                    _ => return Err(TreeError::unclosed(span)),
+                }
+
+                Instruction::Let {
+                    name,
+                    value: resolve_expression_paths(depth, base, names, value),
+                    body,
+                }
+            }
             // create an if-then-else instruction
             Token::LegacyIf { span, subject, .. } => {
                 let mut body = vec![];
                 let mut alt = vec![];
-                match tree(depth, base, input, &mut body)? {
+                match tree_from(depth, base, names, input, &mut body)? {
                     Some(els @ Token::LegacyElse { .. }) | Some(els @ Token::Else { .. }) => {
                         if let Token::Else { .. } = els {
                             mixed_warning("IF", span, els)
                         }
 
                         // consume the end after the else
-                        match tree(depth, base, input, &mut alt)? {
+                        match tree_from(depth, base, names, input, &mut alt)? {
                             Some(Token::LegacyEnd { .. }) => {}
                             Some(end @ Token::End { .. }) => mixed_warning("IF", span, end),
-                            _ => return Err(TreeError),
+                            _ => return Err(TreeError::unclosed(span)),
                         }
                     }
                     Some(Token::LegacyEnd { .. }) => {}
                     Some(end @ Token::End { .. }) => mixed_warning("IF", span, end),
-                    _ => return Err(TreeError),
+                    _ => return Err(TreeError::unclosed(span)),
                 }
 
                 Instruction::If {
-                    subject: resolve_expression_paths(base, subject),
+                    subject: resolve_expression_paths(depth, base, names, subject),
                     body,
                     alt,
                 }
@@ -346,42 +993,32 @@ where
                 span,
                 subject,
             } => {
-                let normal = |input: &mut I, subject| {
+                let normal = |input: &mut I, subject, base: Base<'a>| {
                     let mut body = vec![];
                     let mut alt = vec![];
 
-                    let subject = resolve_expression_paths(base, subject);
-                    let base: PathBuf<Span> = if let Expression::Path { path: base, .. } = &subject {
-                        let mut base = base.clone();
-                        if let Some(last) = base.last_mut() {
-                            last.with_depth(depth)
-                        }
-                        base
-                    } else {
-                        base.to_vec()
-                    };
-
-                    match tree(depth + 1, &base, input, &mut body)? {
+                    match tree_from(depth + 1, &base, names, input, &mut body)? {
                         Some(els @ Token::LegacyElse { .. }) | Some(els @ Token::Else { .. }) => {
                             if let Token::Else { .. } = els {
                                 mixed_warning("BEGIN", span, els)
                             }
 
                             // consume the end after the else
-                            match tree(depth, &base, input, &mut alt)? {
+                            match tree_from(depth, &base, names, input, &mut alt)? {
                                 Some(Token::LegacyEnd { .. }) => {}
                                 Some(end @ Token::End { .. }) => mixed_warning("BEGIN", span, end),
-                                _ => return Err(TreeError),
+                                _ => return Err(TreeError::unclosed(span)),
                             }
                         }
                         Some(Token::LegacyEnd { .. }) => {}
                         Some(end @ Token::End { .. }) => mixed_warning("BEGIN", span, end),
-                        _ => return Err(TreeError),
+                        _ => return Err(TreeError::unclosed(span)),
                     }
 
                     Ok(Instruction::Iter {
                         depth,
                         subject,
+                        clauses: EachClauses::default(),
                         body,
                         alt,
                     })
@@ -396,29 +1033,60 @@ where
                         }) =>
                     {
                         let (line, column, padding) = span.get_line_column_padding();
-                        warn!("[benchpress] warning: output bloat due to ambiguous inner BEGIN");
+                        warn!("[benchpress] warning: ambiguous inner BEGIN");
                         warn!("     --> {}:{}:{}",
                             span.extra.filename, span.location_line(), column);
                         warn!("      |");
                         warn!("{:>5} | {}",
                             span.location_line(), line);
-                        warn!("      | {}{} `{subject}` could refer to the top-level value `{subject}` or the `.{subject}` property of the current element, so compiler must emit code for both cases",
+                        warn!("      | {}{} `{subject}` could refer to the top-level value `{subject}` or the `.{subject}` property of the current element, so which one is used is checked when rendering",
                             padding, "^".repeat(span.len()), subject = subject.span());
                         warn!("      | note: Migrate to modern syntax to avoid the ambiguity. This will become an error in the future.\n");
 
-                        // Path is absolute, so create a branch for both `./subject` and `subject`
+                        // Path is absolute, so parse the body once for both `./subject` and `subject`,
+                        // and iterate over `./subject` if it is truthy
                         let mut relative_path =
                             vec![PathPart::Part(Span::new_extra("./", span.extra))];
                         relative_path.extend_from_slice(path);
                         let relative_subject = Expression::Path { path: relative_path, span: *span };
 
-                        Instruction::If {
-                            subject: resolve_expression_paths(base, relative_subject.clone()),
-                            body: vec![normal(&mut input.clone(), relative_subject)?],
-                            alt: vec![normal(input, subject)?],
+                        let keyword = Span::new_extra("@relative", span.extra);
+                        // `./subject` is a property of the element of the enclosing loop,
+                        // so get it from that element instead of from every path it could be
+                        let relative = if base.is_element_of(depth - 1) {
+                            let mut element = vec![PathPart::PartDepth(
+                                Span::new_extra("@value", span.extra),
+                                depth - 1,
+                            )];
+                            element.extend_from_slice(path);
+                            Expression::Path { path: element, span: *span }
+                        } else {
+                            resolve_expression_paths(depth, base, names, relative_subject.clone())
+                        };
+                        let body_base = Base::ambiguous(
+                            keyword,
+                            depth,
+                            body_base(depth, base, names, &relative_subject, Some(depth)),
+                            body_base(depth, base, names, &subject, Some(depth)),
+                        );
+                        let subject = choice(
+                            keyword,
+                            depth,
+                            relative.clone(),
+                            resolve_expression_paths(depth, base, names, subject),
+                        );
+
+                        Instruction::Ambiguous {
+                            depth,
+                            relative,
+                            body: vec![normal(input, subject, body_base)?],
                         }
                     }
-                    _ => normal(input, subject)?,
+                    _ => {
+                        let body_base = body_base(depth, base, names, &subject, Some(depth));
+                        let subject = resolve_expression_paths(depth, base, names, subject);
+                        normal(input, subject, body_base)?
+                    }
                 }
             }
             tok => return Ok(Some(tok)),
@@ -439,32 +1107,6 @@ mod test {
         },
     };
 
-    impl<'a> Instruction<Span<'a>> {
-        pub fn span_to_str(self) -> Instruction<&'a str> {
-            match self {
-                Instruction::Text(span) => Instruction::Text(*span.fragment()),
-                Instruction::InterpEscaped(expr) => Instruction::InterpEscaped(expr.span_to_str()),
-                Instruction::InterpRaw(expr) => Instruction::InterpRaw(expr.span_to_str()),
-                Instruction::If { subject, body, alt } => Instruction::If {
-                    subject: subject.span_to_str(),
-                    body: body.into_iter().map(|i| i.span_to_str()).collect(),
-                    alt: alt.into_iter().map(|i| i.span_to_str()).collect(),
-                },
-                Instruction::Iter {
-                    depth,
-                    subject,
-                    body,
-                    alt,
-                } => Instruction::Iter {
-                    depth,
-                    subject: subject.span_to_str(),
-                    body: body.into_iter().map(|i| i.span_to_str()).collect(),
-                    alt: alt.into_iter().map(|i| i.span_to_str()).collect(),
-                },
-            }
-        }
-    }
-
     #[test]
     fn test_fix_extra_tokens() {
         fn span_to_str<'a>(tokens: Vec<Token<Span<'a>>>) -> Vec<Token<&'a str>> {
@@ -483,7 +1125,10 @@ mod test {
                     subject: Expression::Path {
                         span: "abc",
                         path: vec![PathPart::Part("abc")]
-                    }
+                    },
+                    key: None,
+                    value: None,
+                    clauses: Default::default(),
                 },
                 Token::Text(" for each thing "),
                 Token::Text("<!-- END foo -->"),
@@ -507,6 +1152,9 @@ mod test {
                     span: sp("abc"),
                     path: vec![PathPart::Part(sp("abc"))],
                 },
+                key: None,
+                value: None,
+                clauses: Default::default(),
             },
             Token::Text(sp(" for each thing ")),
             Token::End {
@@ -517,7 +1165,7 @@ mod test {
 
         let mut output = vec![];
 
-        assert!(tree(0, &[], &mut input, &mut output).is_ok());
+        assert!(tree(0, &[], &[], &mut input, &mut output).is_ok());
 
         assert_eq_unspan!(
             output,
@@ -527,7 +1175,8 @@ mod test {
                     span: "abc",
                     path: vec![PathPart::Part("abc")]
                 },
-                body: vec![Instruction::Text(" for each thing "),],
+                clauses: Default::default(),
+                body: vec![Instruction::Text(vec![" for each thing "]),],
                 alt: vec![],
             }]
         );
@@ -542,6 +1191,9 @@ mod test {
                     span: sp("abc"),
                     path: vec![PathPart::Part(sp("abc"))],
                 },
+                key: None,
+                value: None,
+                clauses: Default::default(),
             },
             Token::Text(sp(" before inner ")),
             Token::Each {
@@ -550,6 +1202,9 @@ mod test {
                     span: sp("./inner"),
                     path: vec![PathPart::Part(sp("./")), PathPart::Part(sp("inner"))],
                 },
+                key: None,
+                value: None,
+                clauses: Default::default(),
             },
             Token::InterpEscaped {
                 span: sp("{abc.inner.prop}"),
@@ -574,7 +1229,7 @@ mod test {
 
         let mut output = vec![];
 
-        assert!(tree(0, &[], &mut input, &mut output).is_ok());
+        assert!(tree(0, &[], &[], &mut input, &mut output).is_ok());
 
         assert_eq_unspan!(
             output,
@@ -584,14 +1239,16 @@ mod test {
                     span: "abc",
                     path: vec![PathPart::Part("abc")]
                 },
+                clauses: Default::default(),
                 body: vec![
-                    Instruction::Text(" before inner "),
+                    Instruction::Text(vec![" before inner "]),
                     Instruction::Iter {
                         depth: 1,
                         subject: Expression::Path {
                             span: "./inner",
                             path: vec![PathPart::PartDepth("abc", 0), PathPart::Part("inner")]
                         },
+                        clauses: Default::default(),
                         body: vec![Instruction::InterpEscaped(Expression::Path {
                             span: "abc.inner.prop",
                             path: vec![
@@ -602,7 +1259,7 @@ mod test {
                         })],
                         alt: vec![]
                     },
-                    Instruction::Text(" after inner "),
+                    Instruction::Text(vec![" after inner "]),
                 ],
                 alt: vec![],
             }]
@@ -618,6 +1275,9 @@ mod test {
                     span: sp("abc"),
                     path: vec![PathPart::Part(sp("abc"))],
                 },
+                key: None,
+                value: None,
+                clauses: Default::default(),
             },
             Token::Text(sp(" before inner ")),
             Token::LegacyBegin {
@@ -651,7 +1311,7 @@ mod test {
 
         let mut output = vec![];
 
-        assert!(tree(0, &[], &mut input, &mut output).is_ok());
+        assert!(tree(0, &[], &[], &mut input, &mut output).is_ok());
 
         assert_eq_unspan!(
             output,
@@ -661,50 +1321,373 @@ mod test {
                     span: "abc",
                     path: vec![PathPart::Part("abc")]
                 },
+                clauses: Default::default(),
                 body: vec![
-                    Instruction::Text(" before inner "),
-                    Instruction::If {
-                        subject: Expression::Path {
+                    Instruction::Text(vec![" before inner "]),
+                    Instruction::Ambiguous {
+                        depth: 1,
+                        relative: Expression::Path {
                             span: "inner",
-                            path: vec![PathPart::PartDepth("abc", 0), PathPart::Part("inner")]
+                            path: vec![PathPart::PartDepth("@value", 0), PathPart::Part("inner")]
                         },
                         body: vec![Instruction::Iter {
                             depth: 1,
-                            subject: Expression::Path {
+                            subject: Expression::Ternary {
                                 span: "inner",
-                                path: vec![PathPart::PartDepth("abc", 0), PathPart::Part("inner")]
+                                condition: Box::new(Expression::Path {
+                                    span: "@relative",
+                                    path: vec![PathPart::PartDepth("@relative", 1)]
+                                }),
+                                then: Box::new(Expression::Path {
+                                    span: "inner",
+                                    path: vec![
+                                        PathPart::PartDepth("@value", 0),
+                                        PathPart::Part("inner")
+                                    ]
+                                }),
+                                otherwise: Box::new(Expression::Path {
+                                    span: "inner",
+                                    path: vec![PathPart::Part("inner")]
+                                }),
                             },
-                            body: vec![Instruction::InterpEscaped(Expression::Path {
+                            clauses: Default::default(),
+                            body: vec![Instruction::InterpEscaped(Expression::Ternary {
                                 span: "abc.inner.prop",
-                                path: vec![
-                                    PathPart::PartDepth("abc", 0),
-                                    PathPart::PartDepth("inner", 1),
-                                    PathPart::Part("prop")
-                                ]
+                                condition: Box::new(Expression::Path {
+                                    span: "@relative",
+                                    path: vec![PathPart::PartDepth("@relative", 1)]
+                                }),
+                                then: Box::new(Expression::Path {
+                                    span: "abc.inner.prop",
+                                    path: vec![
+                                        PathPart::PartDepth("abc", 0),
+                                        PathPart::PartDepth("inner", 1),
+                                        PathPart::Part("prop")
+                                    ]
+                                }),
+                                otherwise: Box::new(Expression::Path {
+                                    span: "abc.inner.prop",
+                                    path: vec![
+                                        PathPart::Part("abc"),
+                                        PathPart::Part("inner"),
+                                        PathPart::Part("prop")
+                                    ]
+                                }),
                             })],
                             alt: vec![]
-                        }],
-                        alt: vec![Instruction::Iter {
-                            depth: 1,
-                            subject: Expression::Path {
-                                span: "inner",
-                                path: vec![PathPart::Part("inner")]
-                            },
-                            body: vec![Instruction::InterpEscaped(Expression::Path {
-                                span: "abc.inner.prop",
-                                path: vec![
-                                    PathPart::Part("abc"),
-                                    PathPart::Part("inner"),
-                                    PathPart::Part("prop")
-                                ]
-                            })],
-                            alt: vec![],
                         }]
                     },
-                    Instruction::Text(" after inner "),
+                    Instruction::Text(vec![" after inner "]),
+                ],
+                alt: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tree_named() {
+        let program = "{{{ each post in posts }}}{post.title}\
+            {{{ each tag in post.tags }}}{post.id}{tag}{tag.id}{{{ end }}}{{{ end }}}";
+        let (_, tokens) = crate::parse::tokens::tokens(sp(program)).unwrap();
+
+        let mut output = vec![];
+
+        assert!(tree(
+            0,
+            &[],
+            &[],
+            &mut fix_extra_tokens(tokens).into_iter(),
+            &mut output
+        )
+        .is_ok());
+
+        assert_eq_unspan!(
+            output,
+            vec![Instruction::Iter {
+                depth: 0,
+                subject: Expression::Path {
+                    span: "posts",
+                    path: vec![PathPart::Part("posts")]
+                },
+                clauses: Default::default(),
+                body: vec![
+                    Instruction::InterpEscaped(Expression::Path {
+                        span: "post.title",
+                        path: vec![PathPart::PartDepth("@value", 0), PathPart::Part("title")]
+                    }),
+                    Instruction::Iter {
+                        depth: 1,
+                        subject: Expression::Path {
+                            span: "post.tags",
+                            path: vec![PathPart::PartDepth("@value", 0), PathPart::Part("tags")]
+                        },
+                        clauses: Default::default(),
+                        body: vec![
+                            Instruction::InterpEscaped(Expression::Path {
+                                span: "post.id",
+                                path: vec![PathPart::PartDepth("@value", 0), PathPart::Part("id")]
+                            }),
+                            Instruction::InterpEscaped(Expression::Path {
+                                span: "tag",
+                                path: vec![PathPart::PartDepth("@value", 1)]
+                            }),
+                            Instruction::InterpEscaped(Expression::Path {
+                                span: "tag.id",
+                                path: vec![PathPart::PartDepth("@value", 1), PathPart::Part("id")]
+                            }),
+                        ],
+                        alt: vec![]
+                    },
                 ],
                 alt: vec![],
             }]
         );
     }
+
+    #[test]
+    fn test_tree_with() {
+        let program = "{{{ with a.b }}}{./c}{b.d}{{{ else }}}{./c}{{{ end }}}";
+        let (_, tokens) = crate::parse::tokens::tokens(sp(program)).unwrap();
+
+        let mut output = vec![];
+
+        assert!(tree(0, &[], &[], &mut tokens.into_iter(), &mut output).is_ok());
+
+        assert_eq_unspan!(
+            output,
+            vec![Instruction::If {
+                subject: Expression::Path {
+                    span: "a.b",
+                    path: vec![PathPart::Part("a"), PathPart::Part("b")]
+                },
+                body: vec![
+                    Instruction::InterpEscaped(Expression::Path {
+                        span: "./c",
+                        path: vec![
+                            PathPart::Part("a"),
+                            PathPart::Part("b"),
+                            PathPart::Part("c")
+                        ]
+                    }),
+                    Instruction::InterpEscaped(Expression::Path {
+                        span: "b.d",
+                        path: vec![
+                            PathPart::Part("a"),
+                            PathPart::Part("b"),
+                            PathPart::Part("d")
+                        ]
+                    }),
+                ],
+                alt: vec![Instruction::InterpEscaped(Expression::Path {
+                    span: "./c",
+                    path: vec![PathPart::Part("c")]
+                })],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tree_set() {
+        let program = "{{{ if a }}}{{{ set b = c }}}{b.d}{{{ end }}}{b}";
+        let (_, tokens) = crate::parse::tokens::tokens(sp(program)).unwrap();
+
+        let mut output = vec![];
+
+        assert!(tree(0, &[], &[], &mut tokens.into_iter(), &mut output).is_ok());
+
+        assert_eq_unspan!(
+            output,
+            vec![
+                Instruction::If {
+                    subject: Expression::Path {
+                        span: "a",
+                        path: vec![PathPart::Part("a")]
+                    },
+                    body: vec![Instruction::Let {
+                        name: "b",
+                        value: Expression::Path {
+                            span: "c",
+                            path: vec![PathPart::Part("c")]
+                        },
+                        body: vec![Instruction::InterpEscaped(Expression::Path {
+                            span: "b.d",
+                            path: vec![
+                                PathPart::Part("@local"),
+                                PathPart::Part("b"),
+                                PathPart::Part("d")
+                            ]
+                        })],
+                    }],
+                    alt: vec![],
+                },
+                Instruction::InterpEscaped(Expression::Path {
+                    span: "b",
+                    path: vec![PathPart::Part("b")]
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tree_switch() {
+        let program =
+            "{{{ switch a }}}\n  {{{ case \"b\" }}}B{{{ case c }}}C{{{ default }}}D{{{ end }}}";
+        let (_, tokens) = crate::parse::tokens::tokens(sp(program)).unwrap();
+
+        let mut output = vec![];
+
+        assert!(tree(0, &[], &[], &mut tokens.into_iter(), &mut output).is_ok());
+
+        assert_eq_unspan!(
+            output,
+            vec![Instruction::Switch {
+                subject: Expression::Path {
+                    span: "a",
+                    path: vec![PathPart::Part("a")]
+                },
+                cases: vec![
+                    (
+                        Expression::StringLiteral("\"b\""),
+                        vec![Instruction::Text(vec!["B"])]
+                    ),
+                    (
+                        Expression::Path {
+                            span: "c",
+                            path: vec![PathPart::Part("c")]
+                        },
+                        vec![Instruction::Text(vec!["C"])]
+                    ),
+                ],
+                default: vec![Instruction::Text(vec!["D"])],
+            }]
+        );
+
+        let program = "{{{ switch a }}}text{{{ case b }}}B{{{ end }}}";
+        let (_, tokens) = crate::parse::tokens::tokens(sp(program)).unwrap();
+        assert!(tree(0, &[], &[], &mut tokens.into_iter(), &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_tree_macro() {
+        let program = "{{{ each a }}}{{{ macro m(x) }}}{x}{a}{m(x)}{{{ end }}}{{{ end }}}";
+        let (_, tokens) = crate::parse::tokens::tokens(sp(program)).unwrap();
+
+        let mut output = vec![];
+
+        let macros = macro_names(&tokens);
+        assert!(tree(0, &[], &macros, &mut tokens.into_iter(), &mut output).is_ok());
+
+        let x = Expression::Path {
+            span: "x",
+            path: vec![PathPart::Part("@local"), PathPart::Part("x")],
+        };
+        assert_eq_unspan!(
+            output,
+            vec![Instruction::Iter {
+                depth: 0,
+                subject: Expression::Path {
+                    span: "a",
+                    path: vec![PathPart::Part("a")]
+                },
+                clauses: Default::default(),
+                body: vec![Instruction::Macro {
+                    name: "m",
+                    params: vec!["x"],
+                    body: vec![
+                        Instruction::InterpEscaped(x.clone()),
+                        Instruction::InterpEscaped(Expression::Path {
+                            span: "a",
+                            path: vec![PathPart::Part("a")]
+                        }),
+                        Instruction::InterpEscaped(Expression::MacroCall {
+                            span: "m(x)",
+                            name: "m",
+                            args: vec![x],
+                        }),
+                    ],
+                }],
+                alt: vec![],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_tree_block_helper() {
+        let program = "{{{ macro m() }}}m{{{ end }}}{{{ call m() }}}{{{ block wrap(a) }}}{a}{{{ else }}}b{{{ end }}}\
+            {{{ call mm(1) }}}";
+        let (_, tokens) = crate::parse::tokens::tokens(sp(program)).unwrap();
+
+        let mut output = vec![];
+
+        let macros = macro_names(&tokens);
+        assert!(tree(0, &[], &macros, &mut tokens.into_iter(), &mut output).is_ok());
+
+        let a = Expression::Path {
+            span: "a",
+            path: vec![PathPart::Part("a")],
+        };
+        assert_eq_unspan!(
+            output,
+            vec![
+                Instruction::Macro {
+                    name: "m",
+                    params: vec![],
+                    body: vec![Instruction::Text(vec!["m"])],
+                },
+                Instruction::InterpRaw(Expression::MacroCall {
+                    span: "m()",
+                    name: "m",
+                    args: vec![],
+                }),
+                Instruction::BlockHelper {
+                    name: "wrap",
+                    args: vec![a.clone()],
+                    kwargs: vec![],
+                    body: vec![Instruction::InterpEscaped(a)],
+                    alt: vec![Instruction::Text(vec!["b"])],
+                },
+                // only `block` opens a block, so calling an unknown name doesn't
+                Instruction::InterpRaw(Expression::Helper {
+                    span: "mm(1)",
+                    name: "mm",
+                    args: vec![Expression::NumberLiteral("1")],
+                    kwargs: vec![],
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tree_macro_keyword_arguments() {
+        let program = "{{{ macro m(a, b, c) }}}{{{ end }}}{m(1, c=3)}{m(b=2, d=4)}";
+        let (_, tokens) = crate::parse::tokens::tokens(sp(program)).unwrap();
+
+        let mut output = vec![];
+
+        let macros = macro_names(&tokens);
+        assert!(tree(0, &[], &macros, &mut tokens.into_iter(), &mut output).is_ok());
+
+        assert_eq_unspan!(
+            output[1..].to_vec(),
+            vec![
+                Instruction::InterpEscaped(Expression::MacroCall {
+                    span: "m(1, c=3)",
+                    name: "m",
+                    args: vec![
+                        Expression::NumberLiteral("1"),
+                        Expression::NullLiteral("null"),
+                        Expression::NumberLiteral("3"),
+                    ],
+                }),
+                Instruction::InterpEscaped(Expression::MacroCall {
+                    span: "m(b=2, d=4)",
+                    name: "m",
+                    args: vec![
+                        Expression::NullLiteral("null"),
+                        Expression::NumberLiteral("2"),
+                    ],
+                }),
+            ]
+        );
+    }
 }
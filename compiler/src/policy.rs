@@ -0,0 +1,561 @@
+use crate::{
+    audit::path_keys,
+    parse::{
+        expression::Expression,
+        path::{
+            Path,
+            PathPart,
+        },
+        tree::Instruction,
+        Span,
+        SpanExt,
+    },
+    prototype::visit,
+};
+use std::collections::HashSet;
+
+/// Which helpers a template can call and which data it can read,
+/// for compiling templates written by users
+///
+/// Path patterns are resolved paths like `user.*` or `config.secret*`,
+/// where `*` matches any part of one key
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Policy {
+    // if set, only these helpers can be called
+    pub helpers: Option<Vec<String>>,
+    // if set, only paths starting with one of these can be read
+    pub allow: Option<Vec<String>>,
+    // paths which can't be read, or read through an object containing them
+    pub forbid: Vec<String>,
+}
+
+/// Whether a key matches a pattern, where `*` matches any part of the key
+fn key_matches(pattern: &str, key: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == key,
+        Some(star) => match key.strip_prefix(&pattern[..star]) {
+            Some(key) => key
+                .char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(key.len()))
+                .any(|i| key_matches(&pattern[(star + 1)..], &key[i..])),
+            None => false,
+        },
+    }
+}
+
+/// How an expression reads the data at a path
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Read {
+    // the whole value, including everything within it
+    Value,
+    // only whether it is truthy, or equal to something
+    Test,
+    // the elements of a loop, which are checked where they are used
+    Elements,
+}
+
+impl Policy {
+    /// Read a policy from a JSON object like
+    /// `{ "helpers": ["formatDate"], "allow": ["user.*"], "forbid": ["config.secret*"] }`
+    /// Returns an error for an unknown key or a value which isn't an array of strings,
+    /// rather than ignoring part of the policy and allowing more than intended
+    pub fn from_json(value: &json::JsonValue) -> Result<Self, String> {
+        let strings = |key: &str, value: &json::JsonValue| {
+            value
+                .members()
+                .map(|entry| entry.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>()
+                .filter(|_| value.is_array())
+                .ok_or_else(|| format!("policy `{}` must be an array of strings", key))
+        };
+
+        let mut policy = Policy::default();
+        for (key, value) in value.entries() {
+            match key {
+                "helpers" => policy.helpers = Some(strings(key, value)?),
+                "allow" => policy.allow = Some(strings(key, value)?),
+                "forbid" => policy.forbid = strings(key, value)?,
+                key => return Err(format!("unknown policy key `{}`", key)),
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Whether an allowed pattern matches the start of a path
+    /// A dynamic key `*` is only matched by `*`
+    fn allows(&self, keys: &[String]) -> bool {
+        match &self.allow {
+            None => true,
+            Some(allow) => allow.iter().any(|pattern| {
+                let pattern: Vec<&str> = pattern.split('.').collect();
+                pattern.len() <= keys.len()
+                    && pattern.iter().zip(keys).all(|(expected, key)| {
+                        if key == "*" {
+                            expected.chars().all(|c| c == '*')
+                        } else {
+                            key_matches(expected, key)
+                        }
+                    })
+            }),
+        }
+    }
+
+    /// The forbidden pattern a path could be within, or contain unless `within` is set
+    /// A dynamic key `*` could be any key
+    fn forbidden(&self, keys: &[String], within: bool) -> Option<&str> {
+        self.forbid
+            .iter()
+            .find(|pattern| {
+                let pattern: Vec<&str> = pattern.split('.').collect();
+                (!within || pattern.len() <= keys.len())
+                    && pattern
+                        .iter()
+                        .zip(keys)
+                        .all(|(expected, key)| key == "*" || key_matches(expected, key))
+            })
+            .map(String::as_str)
+    }
+
+    /// Check the helpers called and the paths read in a template,
+    /// returning errors for those which aren't allowed
+    pub fn check(&self, tree: &[Instruction<Span>]) -> Vec<String> {
+        let mut checker = Checker {
+            policy: self,
+            loops: Vec::new(),
+            locals: Vec::new(),
+            errors: Vec::new(),
+        };
+        checker.instructions(tree);
+
+        // paths in the clauses of a loop can be checked more than once
+        let mut seen = HashSet::new();
+        checker.errors.retain(|error| seen.insert(error.clone()));
+        checker.errors
+    }
+}
+
+struct Checker<'p, 'a> {
+    policy: &'p Policy,
+    // the keys of the elements of each enclosing loop, for each path its subject can be
+    loops: Vec<Vec<Vec<String>>>,
+    // the keys bound to each `set` or `let` variable, for each path its value can be
+    locals: Vec<(&'a str, Vec<Vec<String>>)>,
+    errors: Vec<String>,
+}
+
+impl<'p, 'a> Checker<'p, 'a> {
+    fn instructions(&mut self, tree: &[Instruction<Span<'a>>]) {
+        for instruction in tree {
+            match instruction {
+                Instruction::Text(_) => {}
+                Instruction::InterpEscaped(expr) | Instruction::InterpRaw(expr) => {
+                    self.expression(expr)
+                }
+                Instruction::If { subject, body, alt } => {
+                    self.subject(subject, Read::Test);
+                    self.instructions(body);
+                    self.instructions(alt);
+                }
+                Instruction::Iter {
+                    subject,
+                    clauses,
+                    body,
+                    alt,
+                    ..
+                } => {
+                    self.subject(subject, Read::Elements);
+                    for clause in [&clauses.limit, &clauses.offset, &clauses.sep]
+                        .iter()
+                        .copied()
+                        .flatten()
+                    {
+                        self.expression(clause);
+                    }
+
+                    let mut elements = self.value_keys(subject);
+                    for keys in &mut elements {
+                        keys.push("*".to_string());
+                    }
+                    self.loops.push(elements);
+                    if let Some(filter) = &clauses.filter {
+                        self.expression(filter);
+                    }
+                    self.instructions(body);
+                    self.loops.pop();
+
+                    self.instructions(alt);
+                }
+                Instruction::Switch {
+                    subject,
+                    cases,
+                    default,
+                } => {
+                    self.subject(subject, Read::Test);
+                    for (value, body) in cases {
+                        self.expression(value);
+                        self.instructions(body);
+                    }
+                    self.instructions(default);
+                }
+                // macros can't refer to anything around them,
+                // and their arguments are checked where they are called
+                Instruction::Macro { params, body, .. } => {
+                    let loops = std::mem::take(&mut self.loops);
+                    let locals = std::mem::replace(
+                        &mut self.locals,
+                        params
+                            .iter()
+                            .map(|param| (*param.fragment(), Vec::new()))
+                            .collect(),
+                    );
+                    self.instructions(body);
+                    self.loops = loops;
+                    self.locals = locals;
+                }
+                Instruction::BlockHelper {
+                    name,
+                    args,
+                    kwargs,
+                    body,
+                    alt,
+                } => {
+                    self.helper(*name);
+                    for arg in args.iter().chain(kwargs.iter().map(|(_, arg)| arg)) {
+                        self.expression(arg);
+                    }
+                    self.instructions(body);
+                    self.instructions(alt);
+                }
+                // a variable bound to a path is checked where it is used
+                Instruction::Let { name, value, body } => {
+                    self.bound(value);
+                    let keys = self.value_keys(value);
+                    self.locals.push((*name.fragment(), keys));
+                    self.instructions(body);
+                    self.locals.pop();
+                }
+                Instruction::Ambiguous { relative, body, .. } => {
+                    self.subject(relative, Read::Test);
+                    self.instructions(body);
+                }
+            }
+        }
+    }
+
+    /// Check an expression and every expression within it
+    fn expression(&mut self, expr: &Expression<Span<'a>>) {
+        visit(expr, &mut |expr| match expr {
+            Expression::Path { span, path } => self.path(*span, path, Read::Value),
+            Expression::Helper { name, .. } | Expression::LegacyHelper { name, .. } => {
+                self.helper(*name)
+            }
+            _ => {}
+        });
+    }
+
+    /// Check the subject of a block, which may only be read in part
+    fn subject(&mut self, expr: &Expression<Span<'a>>, read: Read) {
+        match expr {
+            Expression::Path { span, path } => {
+                self.path(*span, path, read);
+                self.computed_keys(path);
+            }
+            Expression::Coalesce { left, right, .. } => {
+                self.subject(left, read);
+                self.subject(right, read);
+            }
+            Expression::Ternary {
+                condition,
+                then,
+                otherwise,
+                ..
+            } => {
+                self.subject(condition, Read::Test);
+                self.subject(then, read);
+                self.subject(otherwise, read);
+            }
+            expr => self.expression(expr),
+        }
+    }
+
+    /// Check the value of a variable, where the paths it can be are checked where it is used
+    fn bound(&mut self, expr: &Expression<Span<'a>>) {
+        match expr {
+            Expression::Path { path, .. } => self.computed_keys(path),
+            Expression::Coalesce { left, right, .. } => {
+                self.bound(left);
+                self.bound(right);
+            }
+            Expression::Ternary {
+                condition,
+                then,
+                otherwise,
+                ..
+            } => {
+                self.subject(condition, Read::Test);
+                self.bound(then);
+                self.bound(otherwise);
+            }
+            expr => self.expression(expr),
+        }
+    }
+
+    fn computed_keys(&mut self, path: Path<Span<'a>>) {
+        for part in path {
+            if let PathPart::Computed(_, key) | PathPart::ComputedDepth(_, key, _) = part {
+                self.expression(key);
+            }
+        }
+    }
+
+    /// The keys of the data an expression can evaluate to, for each path it can be,
+    /// which are none for anything other than paths to data like `@index`
+    fn value_keys(&self, expr: &Expression<Span<'a>>) -> Vec<Vec<String>> {
+        match expr {
+            Expression::Path { path, .. } => self.keys(path),
+            Expression::Coalesce { left, right, .. } => {
+                let mut keys = self.value_keys(left);
+                keys.extend(self.value_keys(right));
+                keys
+            }
+            Expression::Ternary {
+                then, otherwise, ..
+            } => {
+                let mut keys = self.value_keys(then);
+                keys.extend(self.value_keys(otherwise));
+                keys
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// The keys of the data read by a path, through loop elements and variables,
+    /// for each path those can be
+    fn keys(&self, path: Path<Span<'a>>) -> Vec<Vec<String>> {
+        let (first, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return Vec::new(),
+        };
+        let (mut sources, rest) = match (first, first.inner()) {
+            (PathPart::PartDepth(_, n), "@value") => (
+                self.loops.get(*n as usize).cloned().unwrap_or_default(),
+                rest,
+            ),
+            (_, "@value") => (self.loops.last().cloned().unwrap_or_default(), rest),
+            (_, "@root") => (vec![Vec::new()], rest),
+            (_, "@local") => match rest.split_first() {
+                Some((name, rest)) => (
+                    self.locals
+                        .iter()
+                        .rev()
+                        .find(|(local, _)| *local == name.inner())
+                        .map(|(_, sources)| sources.clone())
+                        .unwrap_or_default(),
+                    rest,
+                ),
+                None => (Vec::new(), rest),
+            },
+            (_, keyword) if keyword.starts_with('@') => return Vec::new(),
+            _ => (vec![Vec::new()], path),
+        };
+        for keys in &mut sources {
+            keys.extend(path_keys(rest));
+        }
+        sources
+    }
+
+    fn path(&mut self, span: Span<'a>, path: Path<Span<'a>>, read: Read) {
+        for keys in self.keys(path) {
+            self.keys_read(span, keys, read);
+        }
+    }
+
+    fn keys_read(&mut self, span: Span<'a>, mut keys: Vec<String>, read: Read) {
+        if read == Read::Elements {
+            keys.push("*".to_string());
+        }
+
+        let help = if !self.policy.allows(&keys) {
+            format!(
+                "only paths within `{}` can be read",
+                self.policy
+                    .allow
+                    .as_deref()
+                    .unwrap_or_default()
+                    .join("`, `")
+            )
+        } else if let Some(pattern) = self.policy.forbidden(&keys, read != Read::Value) {
+            format!("`{}` can't be read", pattern)
+        } else {
+            return;
+        };
+
+        let name = if keys.is_empty() {
+            "@root".to_string()
+        } else {
+            keys.join(".")
+        };
+        self.errors.push(span.error(
+            &format!("reading `{}` is not allowed by the policy", name),
+            &help,
+        ));
+    }
+
+    fn helper(&mut self, name: Span<'a>) {
+        let helpers = match &self.policy.helpers {
+            Some(helpers) if !helpers.iter().any(|helper| helper == name.fragment()) => helpers,
+            _ => return,
+        };
+
+        let help = if helpers.is_empty() {
+            "no helpers can be called".to_string()
+        } else {
+            format!("only `{}` can be called", helpers.join("`, `"))
+        };
+        self.errors.push(name.error(
+            &format!("helper `{}` is not allowed by the policy", name),
+            &help,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{
+        test::sp,
+        tokens::tokens,
+        tree::{
+            fix_extra_tokens,
+            tree,
+        },
+    };
+    use pretty_assertions::assert_eq;
+
+    fn check(policy: &Policy, source: &str) -> Vec<String> {
+        let (_, toks) = tokens(sp(source)).unwrap();
+        let mut instructions = vec![];
+        tree(
+            0,
+            &[],
+            &[],
+            &mut fix_extra_tokens(toks).into_iter(),
+            &mut instructions,
+        )
+        .unwrap();
+
+        policy
+            .check(&instructions)
+            .iter()
+            .map(|error| error.lines().next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn from_json() {
+        assert_eq!(
+            Policy::from_json(
+                &json::parse("{ \"helpers\": [\"formatDate\"], \"forbid\": [\"config.secret*\"] }")
+                    .unwrap()
+            ),
+            Ok(Policy {
+                helpers: Some(vec!["formatDate".to_string()]),
+                allow: None,
+                forbid: vec!["config.secret*".to_string()],
+            })
+        );
+
+        let error = |source: &str| Policy::from_json(&json::parse(source).unwrap()).unwrap_err();
+        assert_eq!(
+            error("{ \"paths\": [\"user.*\"] }"),
+            "unknown policy key `paths`"
+        );
+        assert_eq!(
+            error("{ \"helpers\": \"formatDate\" }"),
+            "policy `helpers` must be an array of strings"
+        );
+        assert_eq!(
+            error("{ \"forbid\": [\"config.secret*\", 1] }"),
+            "policy `forbid` must be an array of strings"
+        );
+    }
+
+    #[test]
+    fn key_patterns() {
+        assert!(key_matches("secret*", "secretKey"));
+        assert!(key_matches("*Key", "secretKey"));
+        assert!(key_matches("*", ""));
+        assert!(key_matches("s*c*t", "secret"));
+        assert!(!key_matches("secret*", "public"));
+        assert!(!key_matches("secret", "secretKey"));
+    }
+
+    #[test]
+    fn helpers() {
+        let policy = Policy {
+            helpers: Some(vec!["formatDate".to_string()]),
+            ..Policy::default()
+        };
+
+        assert_eq!(
+            check(
+                &policy,
+                "{formatDate(time)} {function.formatDate} {buildAvatar(user)}\
+                 {{{ wrap(x) }}}{{{ end }}}"
+            ),
+            vec![
+                "[benchpress] error: helper `buildAvatar` is not allowed by the policy",
+                "[benchpress] error: helper `wrap` is not allowed by the policy",
+            ]
+        );
+    }
+
+    #[test]
+    fn paths() {
+        let policy = Policy {
+            helpers: None,
+            allow: Some(vec![
+                "user".to_string(),
+                "users".to_string(),
+                "config".to_string(),
+            ]),
+            forbid: vec!["config.secret*".to_string(), "users.*.password".to_string()],
+        };
+
+        assert_eq!(
+            check(
+                &policy,
+                "{user.name} {config.title} {{{ if config }}}{{{ end }}}\
+                 {{{ each users }}}{users.name}{{{ end }}}"
+            ),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            check(
+                &policy,
+                "{posts} {config.secretKey} {@root} {config[user.key]} {helper(config)}"
+            ),
+            vec![
+                "[benchpress] error: reading `posts` is not allowed by the policy",
+                "[benchpress] error: reading `config.secretKey` is not allowed by the policy",
+                "[benchpress] error: reading `@root` is not allowed by the policy",
+                "[benchpress] error: reading `config.*` is not allowed by the policy",
+                "[benchpress] error: reading `config` is not allowed by the policy",
+            ]
+        );
+        assert_eq!(
+            check(
+                &policy,
+                "{{{ each friend in users }}}{friend.password}{{{ end }}}\
+                 {{{ let c = config }}}{c.secretKey}{{{ end }}}\
+                 {{{ each users }}}{{{ each config.list }}}{../@value}{{{ end }}}{{{ end }}}"
+            ),
+            vec![
+                "[benchpress] error: reading `users.*.password` is not allowed by the policy",
+                "[benchpress] error: reading `config.secretKey` is not allowed by the policy",
+                "[benchpress] error: reading `users.*` is not allowed by the policy",
+            ]
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    console::warn,
+    parse::{
+        expression::Expression,
+        path::PathPart,
+        tree::Instruction,
+        Span,
+        SpanExt,
+    },
+};
+use std::collections::HashSet;
+
+/// Keys which reach the prototype chain instead of template data
+const PROTOTYPE_KEYS: &[&str] = &["__proto__", "constructor", "prototype"];
+
+/// Check paths for keys which reach the prototype chain,
+/// returning errors if only own properties are followed, or warning about them otherwise
+#[rustfmt::skip::macros(warn)]
+pub fn check(tree: &[Instruction<Span>], own_properties: bool) -> Vec<String> {
+    let mut keys = Vec::new();
+    for_each_expression(tree, &mut |expr| {
+        if let Expression::Path { path, .. } = expr {
+            keys.extend(path.iter().filter_map(|part| match part.key() {
+                Some(key) if PROTOTYPE_KEYS.contains(&key.as_ref()) => Some(part.span()),
+                _ => None,
+            }));
+        }
+    });
+
+    // a key in the subject of a loop or `let` is also in the paths which use its name
+    let mut seen = HashSet::new();
+    keys.retain(|span| seen.insert(span.location_offset()));
+
+    keys.into_iter()
+        .filter_map(|span| {
+            let message = format!("`{}` reaches the prototype chain", span);
+            if own_properties {
+                return Some(span.error(&message, "use a different key"));
+            }
+
+            let (line, column, padding) = span.get_line_column_padding();
+            warn!("[benchpress] warning: {}", message);
+            warn!("     --> {}:{}:{}",
+                span.extra.filename, span.location_line(), column);
+            warn!("      |");
+            warn!("{:>5} | {}", span.location_line(), line);
+            warn!("      | {}{} help: use a different key, or compile with `ownProperties`",
+                padding, "^".repeat(span.len()));
+            warn!("      = note: This reads inherited properties like functions instead of data\n");
+            None
+        })
+        .collect()
+}
+
+/// Call `f` with every expression in a template, including those within other expressions
+pub fn for_each_expression<'a, F>(tree: &[Instruction<Span<'a>>], f: &mut F)
+where
+    F: FnMut(&Expression<Span<'a>>),
+{
+    for instruction in tree {
+        match instruction {
+            Instruction::Text(_) => {}
+            Instruction::InterpEscaped(expr) | Instruction::InterpRaw(expr) => visit(expr, f),
+            Instruction::If { subject, body, alt } => {
+                visit(subject, f);
+                for_each_expression(body, f);
+                for_each_expression(alt, f);
+            }
+            Instruction::Iter {
+                subject,
+                clauses,
+                body,
+                alt,
+                ..
+            } => {
+                visit(subject, f);
+                for clause in [
+                    &clauses.filter,
+                    &clauses.limit,
+                    &clauses.offset,
+                    &clauses.sep,
+                ]
+                .iter()
+                .copied()
+                .flatten()
+                {
+                    visit(clause, f);
+                }
+                for_each_expression(body, f);
+                for_each_expression(alt, f);
+            }
+            Instruction::Switch {
+                subject,
+                cases,
+                default,
+            } => {
+                visit(subject, f);
+                for (value, body) in cases {
+                    visit(value, f);
+                    for_each_expression(body, f);
+                }
+                for_each_expression(default, f);
+            }
+            Instruction::Macro { body, .. } => for_each_expression(body, f),
+            Instruction::BlockHelper {
+                args,
+                kwargs,
+                body,
+                alt,
+                ..
+            } => {
+                for arg in args.iter().chain(kwargs.iter().map(|(_, arg)| arg)) {
+                    visit(arg, f);
+                }
+                for_each_expression(body, f);
+                for_each_expression(alt, f);
+            }
+            Instruction::Let { value, body, .. }
+            | Instruction::Ambiguous {
+                relative: value,
+                body,
+                ..
+            } => {
+                visit(value, f);
+                for_each_expression(body, f);
+            }
+        }
+    }
+}
+
+/// Call `f` with an expression and every expression within it
+pub fn visit<'a, F>(expr: &Expression<Span<'a>>, f: &mut F)
+where
+    F: FnMut(&Expression<Span<'a>>),
+{
+    f(expr);
+
+    match expr {
+        Expression::Path { path, .. } => {
+            for part in path {
+                if let PathPart::Computed(_, key) | PathPart::ComputedDepth(_, key, _) = part {
+                    visit(key, f);
+                }
+            }
+        }
+        Expression::Negative { expr, .. } => visit(expr, f),
+        Expression::Helper { args, kwargs, .. } => {
+            for arg in args.iter().chain(kwargs.iter().map(|(_, arg)| arg)) {
+                visit(arg, f);
+            }
+        }
+        Expression::LegacyHelper { args, .. } | Expression::MacroCall { args, .. } => {
+            for arg in args {
+                visit(arg, f);
+            }
+        }
+        Expression::Coalesce { left, right, .. } => {
+            visit(left, f);
+            visit(right, f);
+        }
+        Expression::Ternary {
+            condition,
+            then,
+            otherwise,
+            ..
+        } => {
+            visit(condition, f);
+            visit(then, f);
+            visit(otherwise, f);
+        }
+        Expression::Range { start, end, .. } => {
+            visit(start, f);
+            visit(end, f);
+        }
+        Expression::StringLiteral(_)
+        | Expression::NumberLiteral(_)
+        | Expression::BooleanLiteral(_)
+        | Expression::NullLiteral(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::{
+        test::sp,
+        tokens::tokens,
+        tree::{
+            fix_extra_tokens,
+            tree,
+        },
+    };
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn prototype_keys() {
+        let source = sp("{{{ each x in items.constructor }}}{x.name}{{{ end }}}\
+             {a[\"__proto__\"].b} {helper(c.prototype)} {constructors}");
+        let (_, toks) = tokens(source).unwrap();
+        let mut instructions = vec![];
+        tree(
+            0,
+            &[],
+            &[],
+            &mut fix_extra_tokens(toks).into_iter(),
+            &mut instructions,
+        )
+        .unwrap();
+
+        let errors = check(&instructions, true);
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| error.lines().next().unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                "[benchpress] error: `constructor` reaches the prototype chain",
+                "[benchpress] error: `[\"__proto__\"]` reaches the prototype chain",
+                "[benchpress] error: `prototype` reaches the prototype chain",
+            ]
+        );
+        assert!(check(&instructions, false).is_empty());
+    }
+}
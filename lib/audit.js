@@ -0,0 +1,24 @@
+'use strict';
+
+// eslint-disable-next-line import/no-unresolved
+const { audit: auditTemplate } = require('../compiler');
+const { compilerOptions } = require('./precompile');
+
+/**
+ * List every `{{ }}` raw interpolation in a template, which is output without escaping
+ *
+ * @param {string} source - Template source
+ * @param {object} [options]
+ * @param {string} [options.filename] - Template file name for the report
+ * @param {string|string[]} [options.rawAllowlist] - Paths and helpers allowed to be output raw,
+ * as an array of entries or the contents of an allowlist file
+ * @returns {object[]} - `{ filename, line, column, source, paths, helpers }` for each,
+ * with `allowed` if there is an allowlist
+ */
+function audit(source, options) {
+  const filename = (options && options.filename) || '<unknown>';
+
+  return JSON.parse(auditTemplate(source, filename, compilerOptions(options)));
+}
+
+module.exports = audit;
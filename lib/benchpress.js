@@ -7,11 +7,13 @@ const Benchpress = (typeof module === 'object' && module.exports) ? module.expor
 
 const runtime = require('./runtime');
 const precompile = require('./precompile');
+const audit = require('./audit');
 const __express = require('./express');
 const evaluate = require('./evaluate');
 const { compileRender, compileParse } = require('./compile-render');
 
 Benchpress.precompile = precompile;
+Benchpress.audit = audit;
 Benchpress.__express = __express;
 Benchpress.evaluate = evaluate;
 Benchpress.compileRender = compileRender;
@@ -56,6 +58,94 @@ Benchpress.registerHelper('__escape', (str) => {
   return str.toString().replace(escapeChars, replaceChar);
 });
 
+// escape a value at the start of a URL attribute, like `href="{url}"`,
+// replacing URLs with schemes other than these
+const safeSchemes = /^(?:https?|mailto|tel|ftp)$/i;
+// browsers ignore whitespace and control characters within the scheme
+const urlScheme = /^([a-z][a-z0-9+.-]*):/i;
+const ignoredUrlChars = /[\u0000-\u0020]/g; // eslint-disable-line no-control-regex
+
+Benchpress.registerHelper('__escapeUrl', (str) => {
+  if (str == null) {
+    return '';
+  }
+
+  const scheme = urlScheme.exec(String(str).replace(ignoredUrlChars, ''));
+  if (scheme && !safeSchemes.test(scheme[1])) {
+    return '#unsafe-url';
+  }
+
+  return Benchpress.helpers.__escape(str);
+});
+
+// escape a value for the inside of a JS string literal within a script or event handler,
+// so it can't end the string, the script, or the attribute
+const jsChars = /[\\'"`$<>&=/\n\r\u2028\u2029]/g;
+const replaceJsChar = c => `\\u${`000${c.charCodeAt(0).toString(16)}`.slice(-4)}`;
+
+Benchpress.registerHelper('__escapeJs', (str) => {
+  if (str == null) {
+    return '';
+  }
+
+  return String(str).replace(jsChars, replaceJsChar);
+});
+
+// escape a value for a script or event handler outside of a string, as JSON
+// which can't end the script, so numbers, booleans, and objects keep their type
+const jsValueChars = /[<>&\u2028\u2029]/g;
+
+Benchpress.registerHelper('__escapeJsValue', (value) => {
+  const json = JSON.stringify(value);
+  if (json === undefined) {
+    return 'null';
+  }
+
+  return json.replace(jsValueChars, replaceJsChar);
+});
+
+// escapers for the `xml`, `json-string`, and `url` escape modes
+const xmlChars = /[&<>"']/g;
+const xmlEntities = {
+  '&': '&amp;',
+  '<': '&lt;',
+  '>': '&gt;',
+  '"': '&quot;',
+  "'": '&apos;',
+};
+
+Benchpress.registerHelper('__escapeXml', (str) => {
+  if (str == null) {
+    return '';
+  }
+
+  return String(str).replace(xmlChars, c => xmlEntities[c]);
+});
+
+Benchpress.registerHelper('__escapeJson', (str) => {
+  if (str == null) {
+    return '';
+  }
+
+  return JSON.stringify(String(str)).slice(1, -1);
+});
+
+Benchpress.registerHelper('__escapeUrlComponent', (str) => {
+  if (str == null) {
+    return '';
+  }
+
+  return encodeURIComponent(String(str));
+});
+
+// property access for templates compiled with `ownProperties`,
+// which doesn't follow the prototype chain
+const hasOwn = Object.prototype.hasOwnProperty;
+
+Benchpress.registerHelper('__own', (obj, key) => (
+  obj != null && hasOwn.call(obj, key) ? obj[key] : undefined
+));
+
 Benchpress.cache = {};
 
 Benchpress.globals = {};
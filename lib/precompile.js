@@ -1,7 +1,23 @@
 'use strict';
 
 // eslint-disable-next-line import/no-unresolved
-const { compile } = require('../compiler');
+const { compile_with_options: compileWithOptions } = require('../compiler');
+
+/**
+ * Pick the options used by the compiler, as JSON
+ * @private
+ */
+function compilerOptions(options) {
+  const picked = {};
+  [
+    'escape', 'rawAllowlist', 'ownProperties', 'limits', 'policy', 'optimize', 'impureHelpers',
+  ].forEach((key) => {
+    if (options && options[key] != null) {
+      picked[key] = options[key];
+    }
+  });
+  return JSON.stringify(picked);
+}
 
 /**
  * Precompile a benchpress template
@@ -11,6 +27,25 @@ const { compile } = require('../compiler');
  *
  * @param {string} source - Template source
  * @param {string} options.filename - Template file name for diagnostics
+ * @param {string} [options.escape] - Escape mode for `{ }` interpolation:
+ * `html` (default), `xml`, `plain` or `none`, `json-string`, or `url`
+ * @param {string|string[]} [options.rawAllowlist] - Paths and helpers allowed in `{{ }}`
+ * raw interpolations, as an array of entries or the contents of an allowlist file.
+ * If given, raw interpolations of anything else are compile errors.
+ * @param {boolean} [options.ownProperties] - Only follow own properties in paths, and make
+ * `__proto__`, `constructor`, and `prototype` keys compile errors instead of warnings
+ * @param {object} [options.limits] - Limits for compiling untrusted templates:
+ * `depth` (default 64), `sourceSize`, `tokens` (default 1000000), and `outputSize`.
+ * `null` removes a limit.
+ * @param {object} [options.policy] - Helpers and paths a template can use, for templates
+ * written by users: `helpers`, an array of helper names, `allow`, an array of paths which
+ * can be read along with everything within them, and `forbid`, an array of paths which can't.
+ * Anything else is a compile error.
+ * @param {boolean} [options.optimize] - Simplify the template before generating code,
+ * like merging text, removing conditions which are always true or false, and evaluating
+ * paths and helper calls shared within a loop body once (default true)
+ * @param {string[]} [options.impureHelpers] - Helpers which may return something else when
+ * called again with the same arguments, like random numbers, so each call to them is evaluated
  * @param {function} [callback] - (err, output)
  * @returns {Promise<string>} - output code
  */
@@ -30,7 +65,12 @@ function precompile(source, options, callback) {
       throw Error('source must be a string');
     }
 
-    return compile(source, filename);
+    try {
+      return compileWithOptions(source, filename, compilerOptions(options));
+    } catch (err) {
+      // compile errors are thrown as strings of messages
+      throw typeof err === 'string' ? Error(err) : err;
+    }
   });
 
   if (callback) {
@@ -44,3 +84,4 @@ function precompile(source, options, callback) {
 }
 
 module.exports = precompile;
+module.exports.compilerOptions = compilerOptions;
@@ -13,18 +13,58 @@ function guard(value) {
  * Iterate over an object or array
  * @param {string[]} obj - Iteratee object / array
  * @param {function} each - Callback to execute on each item
+ * @param {function} [alt] - Unused
+ * @param {object} [options] - Clauses from `{{{ each arr where cond limit 5 ... }}}`
+ * @param {function} [options.where] - Only include items for which this returns truthy
+ * @param {number} [options.limit] - Maximum number of items to include
+ * @param {number} [options.offset] - Number of items to skip
+ * @param {boolean} [options.reverse] - Iterate from last to first
+ * @param {string} [options.sep] - Output between items
  * @return {string}
  */
-function iter(obj, each) {
+function iter(obj, each, alt, options) {
   if (!obj || typeof obj !== 'object') { return ''; }
 
   let output = '';
   const keys = Object.keys(obj);
   const length = keys.length;
 
-  for (let i = 0; i < length; i += 1) {
-    const key = keys[i];
-    output += each(key, i, length, obj[key]);
+  if (!options) {
+    for (let i = 0; i < length; i += 1) {
+      const key = keys[i];
+      output += each(key, i, length, obj[key]);
+    }
+
+    return output;
+  }
+
+  const { where, reverse } = options;
+  const keyAt = i => keys[reverse ? length - 1 - i : i];
+
+  // evaluate `where` once per item and keep the keys it includes, so helpers within it
+  // can't disagree between counting the items and rendering them, and `@index`,
+  // `@length`, `@first`, and `@last` reflect the filtered sequence
+  let included = null;
+  if (where) {
+    included = [];
+    for (let i = 0; i < length; i += 1) {
+      const key = keyAt(i);
+      if (where(key, i, length, obj[key])) { included.push(key); }
+    }
+  }
+  const count = included ? included.length : length;
+  const at = included ? (i => included[i]) : keyAt;
+
+  const start = Math.min(Math.max(Number(options.offset) || 0, 0), count);
+  const end = options.limit == null ?
+    count :
+    Math.min(start + Math.max(Number(options.limit) || 0, 0), count);
+  const sep = options.sep == null ? '' : options.sep;
+
+  for (let position = start; position < end; position += 1) {
+    const key = at(position);
+    if (position > start) { output += sep; }
+    output += each(key, position - start, end - start, obj[key]);
   }
 
   return output;
@@ -39,7 +79,9 @@ function iter(obj, each) {
  * @returns {string}
  */
 function helper(context, helpers, helperName, args) {
-  if (typeof helpers[helperName] !== 'function') {
+  // only registered helpers, not inherited properties like `constructor`
+  if (!Object.prototype.hasOwnProperty.call(helpers, helperName) ||
+    typeof helpers[helperName] !== 'function') {
     return '';
   }
   try {
@@ -0,0 +1,70 @@
+'use strict';
+
+const assert = require('assert');
+const Benchpress = require('../build/lib/benchpress');
+
+const template = '<p>{{html}}</p>{{{ each posts }}}{{posts.content}}{posts.title}{{{ end }}}';
+
+describe('audit', () => {
+  it('should list raw interpolations', () => {
+    const sites = Benchpress.audit(template, { filename: 'posts.tpl' });
+
+    assert.deepStrictEqual(sites.map(site => [site.source, site.paths]), [
+      ['html', ['html']],
+      ['posts.content', ['posts.*.content']],
+    ]);
+    assert.strictEqual(sites[0].filename, 'posts.tpl');
+    assert.strictEqual(sites[0].line, 1);
+  });
+
+  it('should check raw interpolations against an allowlist', () => {
+    const sites = Benchpress.audit(template, { rawAllowlist: '# trusted\nposts.*.content\n' });
+
+    assert.deepStrictEqual(sites.map(site => site.allowed), [false, true]);
+  });
+
+  it('should fail to compile raw interpolations not in the allowlist', () =>
+    Benchpress.precompile(template, { rawAllowlist: ['posts.*.content'] })
+      .then(() => assert.fail('expected an error'), (err) => {
+        assert(err instanceof Error);
+        assert(err.message.includes('raw interpolation of `html` is not allowed'));
+      })
+  );
+
+  it('should check block helper calls against an allowlist', () => {
+    const source = '{{{ block wrap(title) }}}{title}{{{ end }}}';
+    const sites = Benchpress.audit(source, { rawAllowlist: [] });
+
+    assert.deepStrictEqual(sites.map(site => [site.source, site.helpers, site.allowed]), [
+      ['wrap', ['wrap'], false],
+    ]);
+
+    return Promise.all([
+      Benchpress.precompile(source, { rawAllowlist: [] })
+        .then(() => assert.fail('expected an error'), (err) => {
+          assert(err.message.includes('raw interpolation of `wrap()` is not allowed'));
+        }),
+      Benchpress.precompile(source, { rawAllowlist: ['wrap()'] })
+        .then(code => assert(code.length)),
+    ]);
+  });
+
+  it('should not let a pragma turn off escaping under an allowlist', () => {
+    const source = '{{{ pragma escape plain }}}\n<p>{name}</p>';
+    const sites = Benchpress.audit(source, {});
+
+    assert.deepStrictEqual(sites.map(site => [site.source, site.paths]), [
+      ['name', ['name']],
+    ]);
+
+    return Benchpress.precompile(source, { rawAllowlist: [] })
+      .then(() => assert.fail('expected an error'), (err) => {
+        assert(err.message.includes('pragma `escape plain` turns off escaping'));
+      });
+  });
+
+  it('should compile when every raw interpolation is allowed', () =>
+    Benchpress.precompile(template, { rawAllowlist: ['html', 'posts.*.content'] })
+      .then(code => assert(code.length))
+  );
+});
@@ -0,0 +1,111 @@
+'use strict';
+
+const assert = require('assert');
+const { prepare } = require('./lib/utils');
+const Benchpress = require('../build/lib/benchpress');
+const mainData = require('./data.json');
+
+function render(source, options) {
+  return Benchpress.precompile(source, options)
+    .then((code) => {
+      try {
+        return Benchpress.runtime(Benchpress.helpers, mainData, Benchpress.evaluate(code));
+      } catch (err) {
+        return `RENDER FAILED: ${err.message}`;
+      }
+    });
+}
+
+describe('optimize', () => {
+  const [source] = prepare();
+
+  Object.keys(source).forEach((name) => {
+    it(`should render ${name} the same as without optimizing`, () =>
+      Promise.all([
+        render(source[name], {}),
+        render(source[name], { optimize: false }),
+      ]).then(([optimized, unoptimized]) => {
+        assert.strictEqual(optimized, unoptimized);
+      })
+    );
+  });
+
+  it('should remove conditions which are always true or false', () =>
+    Promise.all([
+      Benchpress.precompile('{{{ if "yes" }}}a{{{ else }}}b{{{ end }}}', {}),
+      Benchpress.precompile('{{{ if "yes" }}}a{{{ else }}}b{{{ end }}}', { optimize: false }),
+    ]).then(([optimized, unoptimized]) => {
+      assert(!optimized.includes('"b"'));
+      assert(unoptimized.includes('"b"'));
+    })
+  );
+
+  it('should look up paths shared within a loop body once', () => {
+    const source = '{{{ each posts }}}{posts.user.username}{posts.user.picture}{{{ end }}}';
+    return Promise.all([
+      Benchpress.precompile(source, {}),
+      Benchpress.precompile(source, { optimize: false }),
+    ]).then(([optimized, unoptimized]) => {
+      assert.strictEqual(optimized.split("['user']").length - 1, 1);
+      assert.strictEqual(unoptimized.split("['user']").length - 1, 4);
+    });
+  });
+
+  it('should read paths after a helper where they are used', () => {
+    Benchpress.registerHelper('renameUser', function renameUser() {
+      this.user.name = 'renamed';
+      return '';
+    });
+
+    return Benchpress.precompile('{user.name} {renameUser()}{user.name}', {})
+      .then((code) => {
+        const data = { user: { name: 'original' } };
+        const output = Benchpress.runtime(Benchpress.helpers, data, Benchpress.evaluate(code));
+        assert.strictEqual(output, 'original renamed');
+      });
+  });
+
+  it('should call a helper once for the same arguments unless it is impure', () => {
+    let calls = 0;
+    Benchpress.registerHelper('countCalls', (value) => {
+      calls += 1;
+      return value;
+    });
+
+    const source = '{{{ if countCalls(text) }}}{{countCalls(text)}}{{{ end }}}';
+    return render(source, {})
+      .then((output) => {
+        assert.strictEqual(output, mainData.text);
+        assert.strictEqual(calls, 1);
+
+        calls = 0;
+        return render(source, { impureHelpers: ['countCalls'] });
+      })
+      .then((output) => {
+        assert.strictEqual(output, mainData.text);
+        assert.strictEqual(calls, 2);
+      });
+  });
+
+  it('should call a helper again after an impure helper', () => {
+    Benchpress.registerHelper('id', value => value);
+    Benchpress.registerHelper('rename', function rename() {
+      this.user.name = 'renamed';
+      return '';
+    });
+
+    const options = { impureHelpers: ['rename'] };
+    const sources = [
+      '{id(user.name)}{rename()}{id(user.name)}',
+      '{id(user.name)}{{{ if rename() }}}{{{ end }}}{id(user.name)}',
+      '{id(user.name)}{{{ each items }}}{rename()}{{{ end }}}{id(user.name)}',
+      '{{{ each items }}}{id(user.name)}{rename()}{id(user.name)}{{{ end }}}',
+    ];
+    return Promise.all(sources.map(source => Benchpress.precompile(source, options)
+      .then((code) => {
+        const data = { user: { name: 'orig' }, items: [1] };
+        const output = Benchpress.runtime(Benchpress.helpers, data, Benchpress.evaluate(code));
+        assert.strictEqual(output, 'origrenamed');
+      })));
+  });
+});
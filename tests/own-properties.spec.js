@@ -0,0 +1,27 @@
+'use strict';
+
+const assert = require('assert');
+const Benchpress = require('../build/lib/benchpress');
+
+function render(source, data) {
+  return Benchpress.precompile(source, { ownProperties: true })
+    .then(code => Benchpress.runtime(Benchpress.helpers, data, Benchpress.evaluate(code)));
+}
+
+describe('ownProperties', () => {
+  it('should not follow inherited properties', () => {
+    const data = Object.create({ inherited: 'secret' });
+    data.user = { name: 'Jane' };
+    data.keys = ['name', 'toString'];
+
+    return render('{user.name} {inherited} {{{ each keys }}}[{user[@value]}]{{{ end }}}', data)
+      .then(output => assert.strictEqual(output, 'Jane  [Jane][]'));
+  });
+
+  it('should reject prototype keys', () =>
+    render('{user.constructor.name}', {})
+      .then(() => assert.fail('expected an error'), (err) => {
+        assert(err.message.includes('`constructor` reaches the prototype chain'));
+      })
+  );
+});
@@ -0,0 +1,53 @@
+'use strict';
+
+const assert = require('assert');
+const Benchpress = require('../build/lib/benchpress');
+
+const policy = {
+  helpers: ['formatDate'],
+  allow: ['user', 'config.*'],
+  forbid: ['config.secret*'],
+};
+
+function compile(source) {
+  return Benchpress.precompile(source, { filename: 'user.tpl', policy });
+}
+
+describe('policy', () => {
+  it('should compile templates which follow the policy', () =>
+    compile('{user.name} {config.title} {formatDate(user.joined)}')
+  );
+
+  it('should reject helpers which are not allowed', () =>
+    compile('{buildAvatar(user)}')
+      .then(() => assert.fail('expected an error'), (err) => {
+        assert(err.message.includes('helper `buildAvatar` is not allowed by the policy'));
+      })
+  );
+
+  it('should reject paths which are not allowed or forbidden', () =>
+    compile('{posts} {config.secretKey}')
+      .then(() => assert.fail('expected an error'), (err) => {
+        assert(err.message.includes('reading `posts` is not allowed by the policy'));
+        assert(err.message.includes('reading `config.secretKey` is not allowed by the policy'));
+        assert(err.message.includes('user.tpl:1:'));
+      })
+  );
+
+  it('should fail to compile with an invalid policy', () =>
+    Promise.all([
+      { paths: ['user.*'] },
+      { helpers: 'formatDate' },
+    ].map(invalid => Benchpress.precompile('{user.name}', { filename: 'user.tpl', policy: invalid })
+      .then(() => assert.fail('expected an error'), (err) => {
+        assert(err.message.includes('policy'));
+        assert(err.message.includes('user.tpl'));
+      })))
+  );
+
+  it('should not call inherited properties of helpers', () =>
+    Benchpress.precompile('{constructor("alert(1)")}')
+      .then(code => Benchpress.runtime(Benchpress.helpers, {}, Benchpress.evaluate(code)))
+      .then(output => assert.strictEqual(output, ''))
+  );
+});
@@ -40,4 +40,60 @@ describe('precompile', () => {
       done();
     });
   });
+
+  it('should fail when a template is over a limit', () =>
+    Benchpress.precompile(template, { limits: { sourceSize: 10 } })
+      .then(() => assert.fail('expected an error'), (err) => {
+        assert(err.message.includes('over the limit of 10'));
+      })
+  );
+
+  it('should fail when blocks are nested too deeply', () =>
+    Benchpress.precompile('{{{ if a }}}'.repeat(100) + '{{{ end }}}'.repeat(100), {})
+      .then(() => assert.fail('expected an error'), (err) => {
+        assert(err.message.includes('blocks are nested more than 64 deep'));
+      })
+  );
+
+  it('should fail when expressions are nested too deeply', () =>
+    Benchpress.precompile(`{${'f('.repeat(1000)}x${')'.repeat(1000)}}`, { limits: { depth: 16 } })
+      .then(() => assert.fail('expected an error'), (err) => {
+        assert(err.message.includes('expressions are nested more than 16 deep'));
+      })
+  );
+
+  it('should fail when a block is never closed', () =>
+    Benchpress.precompile('A{{{ block mm(1) }}}B{{{ if yes }}}C{{{ end }}}D', {})
+      .then(() => assert.fail('expected an error'), (err) => {
+        assert(err.message.includes('block is never closed'));
+        assert(err.message.includes('{{{ block mm(1) }}}'));
+      })
+  );
+
+  it('should compile the body of nested ambiguous legacy loops once', () => {
+    const names = Array.from({ length: 10 }, (_, i) => i);
+    const source = names.map((i) => `<!-- BEGIN a${i} -->{x${i}}`).join('') +
+      names.reverse().map((i) => `<!-- END a${i} -->`).join('');
+
+    return Benchpress.precompile(source, {})
+      .then((code) => {
+        assert.strictEqual(code.split("context['x9']").length - 1, 1);
+      });
+  });
+
+  it('should compile nested ambiguous legacy loops to code linear in their depth', () => {
+    const nested = (depth) => {
+      const names = Array.from({ length: depth }, (_, i) => i);
+      return names.map((i) => `<!-- BEGIN a${i} -->{x${i}}`).join('') +
+        names.reverse().map((i) => `<!-- END a${i} -->`).join('');
+    };
+
+    return Promise.all([16, 32, 48].map((depth) => Benchpress.precompile(nested(depth), {})))
+      .then((codes) => {
+        // ignore indentation, which grows with the depth of each line
+        const [a, b, c] = codes.map((code) => code.replace(/\s+/g, '').length);
+        assert(c - b <= (b - a) * 1.1, `sizes ${a}, ${b}, ${c} are not linear`);
+        assert(c < 64 * 1024);
+      });
+  });
 });
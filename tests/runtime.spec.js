@@ -0,0 +1,39 @@
+'use strict';
+
+const assert = require('assert');
+const Benchpress = require('../build/lib/benchpress');
+
+describe('runtime', () => {
+  it('should evaluate a where clause once for each item', () => {
+    let calls = 0;
+    Benchpress.registerHelper('firstCalls', () => {
+      calls += 1;
+      return calls <= 3;
+    });
+
+    const source = '{{{ each arr where firstCalls(@value) }}}' +
+      '{@index}/{@length}={@value}{{{ if @last }}}!{{{ end }}} {{{ end }}}';
+    return Benchpress.precompile(source, {})
+      .then((code) => {
+        const data = { arr: ['a', 'b', 'c', 'd'] };
+        const output = Benchpress.runtime(Benchpress.helpers, data, Benchpress.evaluate(code));
+
+        assert.strictEqual(output, '0/3=a 1/3=b 2/3=c! ');
+        assert.strictEqual(calls, 4);
+      });
+  });
+
+  it('should render a block after a top-level set', () => {
+    const source = '{{{ set prefix = label }}}' +
+      '{{{ each posts }}}[{@local.prefix}{posts.title}]{{{ end }}}';
+    return Benchpress.precompile(source, {})
+      .then((code) => {
+        const data = { label: '#', posts: [{ title: 'a' }, { title: 'b' }] };
+        const template = Benchpress.evaluate(code);
+        const output = Benchpress.runtime(Benchpress.helpers, data, template.blocks.posts);
+
+        assert.strictEqual(output, '[#a][#b]');
+        assert.strictEqual(Benchpress.runtime(Benchpress.helpers, data, template), '[#a][#b]');
+      });
+  });
+});
@@ -107,6 +107,21 @@ Benchpress.registerHelper('wordpressHome', () => 'wordpress-home');
 
 Benchpress.registerHelper('stylesheetLocation', () => 'stylesheet-location');
 
+Benchpress.registerHelper('buildAvatar', (user, options = {}) =>
+  `<img src="${user.picture || '/default.png'}" width="${options.size || 32}"${options.rounded ? ' class="avatar-rounded"' : ''}>`);
+
+Benchpress.registerHelper('wrapIf', (condition, tag, block) => (condition ?
+  `<${tag}>${block.body()}</${tag}>` :
+  block.alt()));
+
+Benchpress.registerHelper('times', (count, block) => {
+  let out = '';
+  for (let i = 0; i < count; i += 1) {
+    out += block.body();
+  }
+  return out;
+});
+
 // the following helper definitions are from examples, copied as-is
 /* eslint-disable func-names, prefer-arrow-callback */
 
@@ -0,0 +1,8 @@
+{{{ block wrapIf(isTrue, "strong") }}}{forum} &amp; {language}{{{ end }}}
+{{{ block wrapIf(isFalse, "strong") }}}bold{{{ else }}}plain {forum}{{{ end }}}
+{{{ each person in people }}}
+{{{ block wrapIf(@first, "em") }}}{person.name} #{@index}{{{ else }}}{person.name}{{{ end }}}
+{{{ end }}}
+{{{ block times(3) }}}{{{ each arr limit 2 }}}{@value}{{{ end }}};{{{ end }}}
+{{{ block missingBlockHelper(test) }}}never{{{ end }}}
+{{{ macro bold(text) }}}<b>{text}</b>{{{ end }}}{{{ call bold(forum) }}}
@@ -0,0 +1,18 @@
+{oddKeys["some key"]}
+{oddKeys['dotted.key']}
+{oddKeys["ключ"]}
+{oddKeys[oddKeyName]}
+{people[1].name}
+{arr[4]}
+
+{{{ each people }}}
+{arr[@index]}: {people.name} is {people["age"]}
+{{{ end }}}
+
+{{{ if people[0].age }}}
+First person has an age
+{{{ end }}}
+
+{{{ if !oddKeys["missing key"] }}}
+Missing key is falsy
+{{{ end }}}
@@ -0,0 +1,7 @@
+{{{ if "yes" }}}literal{{{ else }}}never{{{ end }}}
+{{{ if !"yes" }}}never{{{ else }}}negated literal{{{ end }}}
+{{{ if '' }}}never{{{ end }}}empty literal
+{{{ if test }}}same {lorem}{{{ else }}}same {lorem}{{{ end }}}
+{{{ if not_test }}}never{{{ else }}}{{{ if !not_test }}}else if not{{{ else }}}never{{{ end }}}{{{ end }}}
+{{{ if test }}}{{{ if test }}}nested if{{{ else }}}never{{{ end }}}{{{ end }}}
+{{{ each arr }}}{{{ if "" }}}never{{{ end }}}{@value}\{x}{{{ end }}}
@@ -0,0 +1,13 @@
+<p title="{quoted}">{quoted}</p>
+<a href="{website}">{website}</a>
+<a href="{unsafeUrl}">unsafe</a>
+<a href='/go?to={unsafeUrl}'>relative</a>
+<img alt="" src="{{{ if isTrue }}}{unsafeUrl}{{{ else }}}{website}{{{ end }}}">
+<textarea><a href="{unsafeUrl}"></textarea>
+<!-- <a href="{unsafeUrl}"> -->
+<script>
+  // it's a comment
+  var quoted = "{quoted}";
+  var quotedAgain = '{quoted}';
+</script>
+<button onclick="alert('{quoted}')">{quoted}</button>
@@ -0,0 +1,8 @@
+{{{ each animal in animals where !isHuman }}}
+{@index}/{@length} {animal.name}{{{ if @first }}} (first){{{ end }}}{{{ if @last }}} (last){{{ end }}}
+{{{ end }}}
+{{{ each arr limit 2 offset 1 }}}{@index}={@value} {{{ end }}}
+{{{ each arr reverse }}}{@value}{{{ end }}}
+{{{ each person in people reverse limit 2 sep ", " }}}{person.name}{{{ end }}}
+{{{ each programmer in programmers where programmer.hobbies.length sep " & " }}}{programmer.name}{{{ end }}}
+{{{ each arr offset ten }}}never{{{ end }}}
@@ -0,0 +1,2 @@
+{{{ pragma escape json-string }}}
+{ "quoted": "{quoted}", "url": "{website}" }
@@ -0,0 +1,6 @@
+{{{ pragma escape xml }}}
+<feed title="{quoted}">
+  <link href="{website}"/>
+  <entry>{quoted}</entry>
+  <items>{{{ each animal in animals sep " & " }}}{animal.name}{{{ end }}}</items>
+</feed>
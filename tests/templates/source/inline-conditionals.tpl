@@ -0,0 +1,9 @@
+{ user.displayname ?? user.username }
+{ stringEmpty ?? "empty" } { emptyarray ?? "empty" } { thisIsNull ?? null ?? "empty" }
+{ isFalse ?? "empty" } { integerFalse ?? "empty" }
+{ isTrue ? "yes" : "no" } { isFalse ? "yes" : "no" } { emptyarray ? "yes" : "no" }
+{ !isTrue ? "yes" : isFalse ? "maybe" : "no" }
+{{{ if stringEmpty ?? isTrue }}}fallback is truthy{{{ end }}}
+{{{ each people }}}
+{ @first ? "first:" : "" }{ people.name | caps }{ @last ? "" : "," }
+{{{ end }}}
@@ -0,0 +1,11 @@
+{{ buildAvatar(user) }}
+{{ buildAvatar(user, size="24", rounded=true) }}
+{{ buildAvatar(user, size = ten) }}
+{{{ each comment in comments }}}{{ buildAvatar(comment.user, rounded=@first) }}
+{{{ end }}}
+{{ user | buildAvatar(size=ten) }}
+{{{ macro badge(text, kind) }}}<i class="{kind ?? "plain"}">{text}</i>{{{ end }}}
+{badge(forum, kind="info")}
+{badge(kind="warning")}
+{badge(forum)}
+{helper(a, b=1, c)}
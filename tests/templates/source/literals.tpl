@@ -0,0 +1,19 @@
+{join(", ", 1, -2.5, 1e3, true, false, 'single "quotes"', "double 'quotes'")}
+
+{{{ if isEven(4) }}}
+Four is even
+{{{ end }}}
+
+{{{ if isEven(ten) }}}
+Ten is even
+{{{ end }}}
+
+{{{ if !isEven(7) }}}
+Seven is odd
+{{{ end }}}
+
+{{{ if null }}}
+Null is truthy
+{{{ else }}}
+Null is falsy
+{{{ end }}}
@@ -0,0 +1,13 @@
+{{{ set both = join(" & ", forum, language) }}}
+{both} / {both}
+{{{ let x = "outer" }}}{{{ let x = "inner" }}}{x}{{{ end }}} {x}{{{ end }}}
+{{{ each people }}}
+{{{ set name = people.name | caps }}}
+{name} is {../age}
+{{{ end }}}
+{{{ set list = animals }}}
+{{{ each list }}}
+{list.name}: {{{ each ./hates }}}{./name} {{{ end }}}
+{{{ end }}}
+{{{ if isTrue }}}{{{ set word = "yes" }}}{word}{{{ else }}}no{{{ end }}}
+{{{ with package }}}{{{ set author = ./author }}}{author}{{{ end }}}
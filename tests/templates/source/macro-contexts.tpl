@@ -0,0 +1,5 @@
+{{{ macro show(value) }}}<b>{value}</b>{{{ end }}}
+{{{ macro plain(value) }}}{value}{{{ end }}}
+<p>{show(quoted)}</p>
+<a href="{plain(unsafeUrl)}" title="{show(quoted)}">link</a>
+<script>var shown = "{show(quoted)}";</script>
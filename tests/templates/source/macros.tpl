@@ -0,0 +1,12 @@
+{{{ macro avatar(user, size) }}}
+{{{ if user.picture }}}<img class="avatar-{size ?? "md"}" src="{user.picture}">{{{ else }}}<span class="avatar-{size ?? "md"}">{user.username}</span>{{{ end }}}
+{{{ end }}}
+{{{ macro thread(comments) }}}
+<ul>
+{{{ each comment in comments }}}
+<li>{ avatar(comment.user, "sm") } {comment.text}{{{ if comment.replies }}}{{{ call thread(comment.replies) }}}{{{ end }}}</li>
+{{{ end }}}
+</ul>
+{{{ end }}}
+{avatar(user)}
+{{{ call thread(comments) }}}
@@ -0,0 +1,9 @@
+{{{ each person in people }}}
+{person.name} is {person.age} years old.
+{{{ end }}}
+{{{ each username, name in usernames }}}
+{username}: {name}
+{{{ end }}}
+{{{ each programmer in programmers }}}{{{ each hobby in programmer.hobbies }}}
+{programmer.name} #{../@index}: {hobby}
+{{{ end }}}{{{ end }}}
@@ -0,0 +1,3 @@
+{{{ each animal in animals }}}
+{{{ each hated in animal.hates }}}[{animal.name}: {hated.name}]{{{ end }}}
+{{{ end }}}
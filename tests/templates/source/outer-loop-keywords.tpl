@@ -0,0 +1,5 @@
+{{{ each programmers }}}
+{{{ each programmers.hobbies }}}
+{programmers.name} #{../@index} of {../@length}: hobby {@index} of {@length} ({@value}), first: {../@first}, last: {../@last}, key: {../@key}, name: {../@value.name}
+{{{ end }}}
+{{{ end }}}
@@ -0,0 +1,9 @@
+{ lorem | caps }
+{ lorem|caps }
+{ ", " | join("a", "b") }
+{ ", " | join("a", "b") | caps }
+{{{ if ten | isEven }}}ten is even{{{ end }}}
+{{{ each people }}}
+{ people.name | caps }
+{{{ end }}}
+<script>if (a) { a || b }</script>
@@ -0,0 +1,9 @@
+{{{ each page in range(1, ten) }}}<a href="?page={page}">{page}</a>{{{ if !@last }}} | {{{ end }}}{{{ end }}}
+{{{ for i in 1..5 }}}{{{ if @first }}}[{{{ end }}}{i}:{@index}/{@length}{{{ if @last }}}]{{{ else }}}, {{{ end }}}{{{ end }}}
+{{{ for star in 1..people.length }}}*{{{ end }}}
+{{{ each person in people }}}{person.name}: {{{ for n in 1..2 }}}{../@index}.{n} {{{ end }}}
+{{{ end }}}
+{{{ for i in 3..1 }}}never{{{ else }}}empty{{{ end }}}
+{{{ for i in 1..thisIsNull }}}never{{{ else }}}null{{{ end }}}
+{{{ each -1..1 }}}({@value}){{{ end }}}
+{{{ each i in range(1, ten) limit 3 }}}{i}{{{ end }}} {{{ for i in 1..ten offset 7 reverse sep ", " }}}{i}{{{ end }}}
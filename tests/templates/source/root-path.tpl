@@ -0,0 +1,12 @@
+{@root.text}
+{@root.oddKeys["some key"]}
+
+{{{ each myObject1.myArray }}}
+{myArray.name} / {@root.myObject2.myArray[@index].name}
+{{{ end }}}
+
+{{{ each @root.arr }}}{@value}{{{ end }}}
+
+{{{ each people }}}
+{{{ if @root.test }}}{people.name}{{{ end }}}
+{{{ end }}}
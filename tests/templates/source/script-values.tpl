@@ -0,0 +1,9 @@
+<script>
+  var ten = {ten};
+  var isTrue = {isTrue};
+  var isFalse = {isFalse};
+  var integerFalse = {integerFalse};
+  var quoted = {quoted};
+  var missing = {doesNotExist};
+</script>
+<button onclick="go({ten}, {isFalse}, {quoted})">go</button>
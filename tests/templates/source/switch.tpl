@@ -0,0 +1,11 @@
+{{{ switch template.name }}}
+  {{{ case "footer" }}}
+    footer
+  {{{ case "header" }}}
+    header
+  {{{ default }}}
+    other
+{{{ end }}}
+{{{ switch ten }}}{{{ case "10" }}}string{{{ case 10 }}}number{{{ end }}}
+{{{ each animals }}}{{{ switch animals.name }}}{{{ case "Cat" }}}meow {{{ case "Dog" }}}woof {{{ default }}}{animals.name} {{{ end }}}{{{ end }}}
+{{{ switch thisIsNull }}}{{{ case "x" }}}x{{{ end }}}done
@@ -0,0 +1,16 @@
+{{{ with package }}}
+{./name} by {package.author} ({../url})
+{{{ end }}}
+{{{ with thisIsNull }}}
+should not show
+{{{ else }}}
+nothing here
+{{{ end }}}
+{{{ with emptyarray }}}should not show{{{ end }}}
+{{{ each animals }}}
+{{{ with animals.pet }}}
+{animals.name}: {./info}{{{ each ./groups }}} [{./name}]{{{ end }}}
+{{{ else }}}
+{animals.name}: not a pet
+{{{ end }}}
+{{{ end }}}